@@ -0,0 +1,316 @@
+//! The access log (--access-log), which gets one line for every request we read, separately from
+//! the diagnostic output of the `log` macros. Lines are in one of two formats
+//! (--access-log-format):
+//!
+//! * `combined`: the Combined Log Format used by Apache and nginx, followed by the request ID, the
+//!   upstream address, the number of request body bytes received, the total time taken and the
+//!   time spent waiting for the upstream (both in seconds). Missing fields are written as `-`.
+//! * `json`: a JSON object with the same fields.
+//!
+//! Once the file reaches --access-log-max-size bytes, it is renamed to `<path>.1` (shifting older
+//! files along to `<path>.2` and so on, up to --access-log-max-files of them) and a new file is
+//! started.
+//!
+//! Lines are written (and the file rotated) on a thread of its own, so that a slow disk doesn't
+//! hold up the tasks handling requests. If it falls too far behind, lines are dropped (and counted
+//! in the balancebeam_access_log_dropped_total metric). Whatever is still queued when we shut down
+//! is written before we exit.
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How access log lines are laid out (--access-log-format)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Combined,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "combined" => Ok(Format::Combined),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown access log format {:?} (expected combined or json)",
+                s
+            )),
+        }
+    }
+}
+
+/// What we know about a request, gathered as it is handled and written out once the response has
+/// been sent
+pub struct Entry {
+    request_id: String,
+    client_ip: String,
+    /// Address of the upstream the request was sent to, if it got that far
+    pub upstream: Option<String>,
+    method: http::Method,
+    /// The request target, as the client sent it
+    path: String,
+    protocol: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /// Number of request body bytes received from the client
+    pub bytes_in: u64,
+    /// When the request was read
+    time: SystemTime,
+    started: Instant,
+    /// How long the upstream took to respond (including any retries)
+    pub upstream_duration: Option<Duration>,
+}
+
+impl Entry {
+    /// Starts an entry for a request that has just been read (without its body, if it is being
+    /// streamed)
+    pub fn new(client_ip: &str, request: &http::Request<Vec<u8>>, request_id: String) -> Entry {
+        let header = |name: http::header::HeaderName| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+        };
+        Entry {
+            request_id,
+            client_ip: client_ip.to_string(),
+            upstream: None,
+            method: request.method().clone(),
+            path: request.uri().to_string(),
+            protocol: format!("{:?}", request.version()),
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+            bytes_in: request.body().len() as u64,
+            time: SystemTime::now(),
+            started: Instant::now(),
+            upstream_duration: None,
+        }
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn method(&self) -> &http::Method {
+        &self.method
+    }
+
+    pub fn client_ip(&self) -> &str {
+        &self.client_ip
+    }
+}
+
+/// A line of the JSON format
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    request_id: &'a str,
+    client_ip: &'a str,
+    upstream: Option<&'a str>,
+    method: &'a str,
+    path: &'a str,
+    protocol: &'a str,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Seconds from reading the request to sending the response
+    duration: f64,
+    /// Seconds spent waiting for the upstream
+    upstream_duration: Option<f64>,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+/// How many lines may be waiting for the writer thread before we start dropping them
+const QUEUE_SIZE: usize = 4096;
+
+pub struct AccessLog {
+    path: String,
+    format: Format,
+    /// Lines on their way to the writer thread, until the log is closed
+    lines: Mutex<Option<SyncSender<String>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AccessLog {
+    /// Opens (or creates) the log file, appending to whatever is already there, and starts the
+    /// thread that writes to it
+    pub fn open(
+        path: &str,
+        format: Format,
+        max_size: u64,
+        max_files: usize,
+    ) -> io::Result<AccessLog> {
+        let mut writer = Writer {
+            path: path.to_string(),
+            max_size,
+            max_files,
+            file: open_file(path)?,
+        };
+        let (lines, received) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        let writer = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in received {
+                    writer.write(&line);
+                }
+            })?;
+        Ok(AccessLog {
+            path: path.to_string(),
+            format,
+            lines: Mutex::new(Some(lines)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Queues the line for a request whose response has been sent. Returns false if the line had
+    /// to be dropped.
+    pub fn record(&self, entry: &Entry, status: http::StatusCode, bytes_out: u64) -> bool {
+        let mut line = match self.format {
+            Format::Combined => combined_line(entry, status, bytes_out),
+            Format::Json => json_line(entry, status, bytes_out),
+        };
+        line.push('\n');
+        let result = match &*self.lines.lock() {
+            Some(lines) => lines.try_send(line),
+            None => Err(TrySendError::Disconnected(line)),
+        };
+        match result {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Access log {} is falling behind; dropped a line", self.path);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                log::warn!("Access log {} is no longer being written", self.path);
+                false
+            }
+        }
+    }
+
+    /// Stops taking lines, and waits for the writer thread to write out the ones already queued
+    pub fn close(&self) {
+        self.lines.lock().take();
+        if let Some(writer) = self.writer.lock().take() {
+            if writer.join().is_err() {
+                log::error!("Access log {} writer thread panicked", self.path);
+            }
+        }
+    }
+}
+
+/// The file being logged to, and how big it has got
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+/// Owns the log file, on the writer thread
+struct Writer {
+    path: String,
+    /// Size at which the file is rotated (0 = never)
+    max_size: u64,
+    /// Number of rotated files kept
+    max_files: usize,
+    file: LogFile,
+}
+
+impl Writer {
+    fn write(&mut self, line: &str) {
+        let size = self.file.size;
+        if self.max_size > 0 && size > 0 && size + line.len() as u64 > self.max_size {
+            if let Err(error) = self.rotate() {
+                log::warn!("Failed to rotate access log {}: {}", self.path, error);
+            }
+        }
+        match self.file.file.write_all(line.as_bytes()) {
+            Ok(()) => self.file.size += line.len() as u64,
+            Err(error) => log::warn!("Failed to write to access log {}: {}", self.path, error),
+        }
+    }
+
+    /// Moves the current file out of the way, shifting older ones along, and starts a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let older = format!("{}.{}", self.path, index);
+                if fs::metadata(&older).is_ok() {
+                    fs::rename(&older, format!("{}.{}", self.path, index + 1))?;
+                }
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = open_file(&self.path)?;
+        Ok(())
+    }
+}
+
+fn open_file(path: &str) -> io::Result<LogFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(LogFile { file, size })
+}
+
+/// Formats a line of the Combined Log Format, plus our extra fields
+fn combined_line(entry: &Entry, status: http::StatusCode, bytes_out: u64) -> String {
+    let time: DateTime<Utc> = entry.time.into();
+    format!(
+        "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} {} {} {:.3} {}",
+        entry.client_ip,
+        time.format("%d/%b/%Y:%H:%M:%S %z"),
+        entry.method,
+        entry.path,
+        entry.protocol,
+        status.as_u16(),
+        bytes_out,
+        escape(entry.referer.as_deref().unwrap_or("-")),
+        escape(entry.user_agent.as_deref().unwrap_or("-")),
+        entry.request_id,
+        entry.upstream.as_deref().unwrap_or("-"),
+        entry.bytes_in,
+        entry.started.elapsed().as_secs_f64(),
+        entry.upstream_duration.map_or_else(
+            || "-".to_string(),
+            |duration| format!("{:.3}", duration.as_secs_f64())
+        ),
+    )
+}
+
+fn json_line(entry: &Entry, status: http::StatusCode, bytes_out: u64) -> String {
+    let time: DateTime<Utc> = entry.time.into();
+    serde_json::to_string(&JsonLine {
+        time: time.to_rfc3339(),
+        request_id: &entry.request_id,
+        client_ip: &entry.client_ip,
+        upstream: entry.upstream.as_deref(),
+        method: entry.method.as_str(),
+        path: &entry.path,
+        protocol: &entry.protocol,
+        status: status.as_u16(),
+        bytes_in: entry.bytes_in,
+        bytes_out,
+        duration: entry.started.elapsed().as_secs_f64(),
+        upstream_duration: entry
+            .upstream_duration
+            .map(|duration| duration.as_secs_f64()),
+        referer: entry.referer.as_deref(),
+        user_agent: entry.user_agent.as_deref(),
+    })
+    .unwrap()
+}
+
+/// Escapes a header value for a quoted field of the Combined Log Format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,289 @@
+//! The admin API, served on a separate listener (--admin-bind) so that it can be kept away from
+//! clients. It lets operators manage the upstreams without restarting balancebeam:
+//!
+//! * `GET /upstreams` lists the upstreams and their status
+//! * `POST /upstreams` adds an upstream, given a body like `{"address": "10.0.0.3:8080",
+//!   "weight": 2, "pool": "api"}` (the weight is optional, and the pool defaults to the fallback
+//!   pool)
+//! * `DELETE /upstreams/<address>` removes an upstream, unless it is the last one in its pool
+//! * `POST /upstreams/<address>/drain` stops sending new connections to an upstream, while letting
+//!   the existing ones finish, and `DELETE /upstreams/<address>/drain` undoes that
+//! * `GET /metrics` reports request counts, latencies, health check results and so on in the
+//!   Prometheus text format
+//!
+//! Addresses in paths may be percent-encoded (e.g. `https%3A%2F%2Fapi.internal%3A8443`). Every
+//! `/upstreams` endpoint responds with the list of upstreams. Changes made here last until the
+//! config file (if there is one) is reloaded.
+
+use crate::tls::UpstreamTarget;
+use crate::{request, response, ProxyState};
+use http::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::{atomic::Ordering, Arc};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+
+/// An upstream as listed by the admin API
+#[derive(Serialize)]
+struct UpstreamStatus {
+    address: String,
+    pool: String,
+    weight: usize,
+    dead: bool,
+    /// State of the upstream's circuit breaker (closed, open or half-open)
+    circuit: String,
+    draining: bool,
+    /// Number of connections to the upstream that are currently handed out to clients
+    outstanding: usize,
+}
+
+/// The body of a request to add an upstream
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewUpstream {
+    address: String,
+    #[serde(default = "default_weight")]
+    weight: usize,
+    pool: Option<String>,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+/// Accepts connections to the admin API until the listener fails
+pub async fn serve(mut listener: TcpListener, state: Arc<ProxyState>) {
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        if let Ok(stream) = stream {
+            tokio::spawn(handle_connection(stream, Arc::clone(&state)));
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<ProxyState>) {
+    loop {
+        // Held to the same timeouts as proxied requests, so that slow clients can't hold admin
+        // connections open either
+        let request = match request::read_from_stream(&mut stream, &state.client_timeouts).await {
+            Ok(request) => request,
+            Err(request::Error::IncompleteRequest(0))
+            | Err(request::Error::IdleTimeout)
+            | Err(request::Error::ConnectionError(_)) => {
+                return;
+            }
+            Err(request::Error::Timeout) => {
+                let response = crate::request_timeout_response();
+                let _ = response::write_to_stream(&response, &http::Method::GET, &mut stream).await;
+                return;
+            }
+            Err(error) => {
+                log::debug!("Error parsing admin API request: {:?}", error);
+                let response = response::make_http_error(StatusCode::BAD_REQUEST);
+                let _ = response::write_to_stream(&response, &http::Method::GET, &mut stream).await;
+                return;
+            }
+        };
+        let response = handle_request(&state, &request).await;
+        log::info!(
+            "Admin API: {} <- {}",
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(error) =
+            response::write_to_stream(&response, request.method(), &mut stream).await
+        {
+            log::warn!("Failed to send admin API response: {}", error);
+            return;
+        }
+    }
+}
+
+async fn handle_request(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let path = request.uri().path().trim_end_matches('/');
+    // Addresses can have slashes in them (https://...), so an upstream's routes are everything
+    // after /upstreams/, with or without /drain on the end
+    if let Some(rest) = path.strip_prefix("/upstreams/") {
+        let (address, drain) = match rest.strip_suffix("/drain") {
+            Some(address) => (address, true),
+            None => (rest, false),
+        };
+        let address = match percent_decode(address) {
+            Some(address) => address,
+            None => return error_response(StatusCode::BAD_REQUEST, "invalid upstream address"),
+        };
+        return match (request.method(), drain) {
+            (&Method::DELETE, false) => remove_upstream(state, &address).await,
+            (&Method::POST, true) => drain_upstream(state, &address, true).await,
+            (&Method::DELETE, true) => drain_upstream(state, &address, false).await,
+            _ => response::make_http_error(StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    match (request.method(), path.trim_start_matches('/')) {
+        (&Method::GET, "metrics") => metrics(state).await,
+        (&Method::GET, "upstreams") => list_upstreams(state, StatusCode::OK).await,
+        (&Method::POST, "upstreams") => add_upstream(state, request.body()).await,
+        (_, "metrics") | (_, "upstreams") => {
+            response::make_http_error(StatusCode::METHOD_NOT_ALLOWED)
+        }
+        _ => response::make_http_error(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Decodes %XX escapes in a path segment. Returns None if an escape is malformed or the result
+/// isn't UTF-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Builds a response with a JSON body
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> http::Response<Vec<u8>> {
+    let body = serde_json::to_vec(value).unwrap();
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+/// Builds an error response explaining what went wrong
+fn error_response(status: StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
+
+async fn metrics(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let body = state
+        .metrics
+        .render(&*state.upstreams.read().await)
+        .into_bytes();
+    http::Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+async fn list_upstreams(state: &ProxyState, status: StatusCode) -> http::Response<Vec<u8>> {
+    let upstreams = state.upstreams.read().await;
+    let list: Vec<UpstreamStatus> = (0..upstreams.addresses.len())
+        .map(|index| UpstreamStatus {
+            address: upstreams.addresses[index].clone(),
+            pool: upstreams.pools[index].clone(),
+            weight: upstreams.weights[index],
+            dead: upstreams.dead[index],
+            circuit: upstreams.breakers[index].state().to_string(),
+            draining: upstreams.draining[index],
+            outstanding: upstreams.outstanding[index].load(Ordering::SeqCst),
+        })
+        .collect();
+    json_response(status, &list)
+}
+
+async fn add_upstream(state: &ProxyState, body: &[u8]) -> http::Response<Vec<u8>> {
+    let upstream: NewUpstream = match serde_json::from_slice(body) {
+        Ok(upstream) => upstream,
+        Err(error) => return error_response(StatusCode::BAD_REQUEST, &error.to_string()),
+    };
+    if upstream.address.is_empty() || upstream.weight == 0 {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "address must not be empty, and weight must be a positive integer",
+        );
+    }
+    if let Err(message) = UpstreamTarget::parse(&upstream.address) {
+        return error_response(StatusCode::BAD_REQUEST, &message);
+    }
+    let pool = {
+        let router = state.router.read().await;
+        match upstream.pool {
+            Some(pool) if router.pool(&pool).is_some() => pool,
+            Some(_) => return error_response(StatusCode::BAD_REQUEST, "no such pool"),
+            None => router.fallback_pool().to_string(),
+        }
+    };
+    if !state
+        .upstreams
+        .write()
+        .await
+        .add(upstream.address.clone(), upstream.weight, pool.clone())
+    {
+        return error_response(StatusCode::CONFLICT, "upstream already exists");
+    }
+    log::info!(
+        "Added upstream {} with weight {} to pool {}",
+        upstream.address,
+        upstream.weight,
+        pool
+    );
+    list_upstreams(state, StatusCode::CREATED).await
+}
+
+async fn remove_upstream(state: &ProxyState, address: &str) -> http::Response<Vec<u8>> {
+    {
+        let mut upstreams = state.upstreams.write().await;
+        let index = match upstreams.position(address) {
+            Some(index) => index,
+            None => return error_response(StatusCode::NOT_FOUND, "no such upstream"),
+        };
+        let pool = &upstreams.pools[index];
+        if upstreams
+            .pools
+            .iter()
+            .filter(|other| *other == pool)
+            .count()
+            == 1
+        {
+            return error_response(
+                StatusCode::CONFLICT,
+                "can't remove the last upstream in a pool",
+            );
+        }
+        upstreams.remove(address);
+    }
+    state.connection_pool.evict(address).await;
+    log::info!("Removed upstream {}", address);
+    list_upstreams(state, StatusCode::OK).await
+}
+
+async fn drain_upstream(
+    state: &ProxyState,
+    address: &str,
+    draining: bool,
+) -> http::Response<Vec<u8>> {
+    if !state
+        .upstreams
+        .write()
+        .await
+        .set_draining(address, draining)
+    {
+        return error_response(StatusCode::NOT_FOUND, "no such upstream");
+    }
+    if draining {
+        // Idle connections won't be handed out again, so there is no point keeping them open
+        state.connection_pool.evict(address).await;
+        log::info!("Draining upstream {}", address);
+    } else {
+        log::info!("Stopped draining upstream {}", address);
+    }
+    list_upstreams(state, StatusCode::OK).await
+}
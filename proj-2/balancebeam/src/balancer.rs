@@ -0,0 +1,183 @@
+use crate::tls::UpstreamTarget;
+use rand::Rng;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// An upstream server that a Balancer may pick, along with the information the strategies need to
+/// make their choice
+pub struct Candidate {
+    /// Index of the upstream in the current `Upstreams` set (see upstreams.rs)
+    pub index: usize,
+    /// Relative weight of the upstream (as given with --upstream host:port@weight)
+    pub weight: usize,
+    /// Number of connections to the upstream that are currently handed out to clients
+    pub outstanding: usize,
+}
+
+/// A load balancing strategy. Given the upstreams that are currently alive, a Balancer decides
+/// which one should receive the next connection.
+pub trait Balancer: Send + Sync {
+    /// Picks one of the candidates, returning its upstream index. `candidates` is never empty.
+    fn choose(&self, candidates: &[Candidate]) -> usize;
+}
+
+/// The load balancing strategies that can be selected with --strategy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Random,
+    RoundRobin,
+    LeastConnections,
+    Weighted,
+    PowerOfTwoChoices,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "weighted" => Ok(Strategy::Weighted),
+            "p2c" => Ok(Strategy::PowerOfTwoChoices),
+            _ => Err(format!(
+                "unknown strategy {:?} (expected random, round-robin, least-connections, \
+                weighted or p2c)",
+                s
+            )),
+        }
+    }
+}
+
+impl Strategy {
+    pub fn build(self) -> Box<dyn Balancer> {
+        match self {
+            Strategy::Random => Box::new(RandomBalancer),
+            Strategy::RoundRobin => Box::new(RoundRobinBalancer {
+                next: AtomicUsize::new(0),
+            }),
+            Strategy::LeastConnections => Box::new(LeastConnectionsBalancer),
+            Strategy::Weighted => Box::new(WeightedBalancer),
+            Strategy::PowerOfTwoChoices => Box::new(PowerOfTwoChoicesBalancer),
+        }
+    }
+}
+
+/// Picks an upstream uniformly at random
+struct RandomBalancer;
+
+impl Balancer for RandomBalancer {
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        candidates[rand::thread_rng().gen_range(0, candidates.len())].index
+    }
+}
+
+/// Cycles through the upstreams in order
+struct RoundRobinBalancer {
+    next: AtomicUsize,
+}
+
+impl Balancer for RoundRobinBalancer {
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        candidates[next % candidates.len()].index
+    }
+}
+
+/// Picks the upstream with the fewest outstanding connections, breaking ties at random
+struct LeastConnectionsBalancer;
+
+impl Balancer for LeastConnectionsBalancer {
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        let fewest = candidates
+            .iter()
+            .map(|candidate| candidate.outstanding)
+            .min()
+            .unwrap();
+        let least_loaded: Vec<&Candidate> = candidates
+            .iter()
+            .filter(|candidate| candidate.outstanding == fewest)
+            .collect();
+        least_loaded[rand::thread_rng().gen_range(0, least_loaded.len())].index
+    }
+}
+
+/// Picks an upstream at random, with each upstream's chance proportional to its weight
+struct WeightedBalancer;
+
+impl Balancer for WeightedBalancer {
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        let total_weight: usize = candidates.iter().map(|candidate| candidate.weight).sum();
+        let mut target = rand::thread_rng().gen_range(0, total_weight);
+        for candidate in candidates {
+            if target < candidate.weight {
+                return candidate.index;
+            }
+            target -= candidate.weight;
+        }
+        unreachable!("target is always less than the total weight")
+    }
+}
+
+/// Picks two upstreams at random and uses whichever has fewer outstanding connections
+struct PowerOfTwoChoicesBalancer;
+
+impl Balancer for PowerOfTwoChoicesBalancer {
+    fn choose(&self, candidates: &[Candidate]) -> usize {
+        if candidates.len() == 1 {
+            return candidates[0].index;
+        }
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0, candidates.len());
+        // Pick a second candidate that is different from the first
+        let second = (first + rng.gen_range(1, candidates.len())) % candidates.len();
+        if candidates[second].outstanding < candidates[first].outstanding {
+            candidates[second].index
+        } else {
+            candidates[first].index
+        }
+    }
+}
+
+/// Counts a connection as outstanding for as long as the guard is alive. An UpstreamConnection
+/// holds one of these, so the count drops as soon as the connection is returned to the pool or
+/// closed.
+pub struct LoadGuard {
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl LoadGuard {
+    pub fn acquire(outstanding: &Arc<AtomicUsize>) -> LoadGuard {
+        outstanding.fetch_add(1, Ordering::SeqCst);
+        LoadGuard {
+            outstanding: Arc::clone(outstanding),
+        }
+    }
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Parses an --upstream argument of the form host:port or host:port@weight (where the address may
+/// also be https://host[:port]), returning the address and weight (which defaults to 1)
+pub fn parse_upstream(upstream: &str) -> Result<(String, usize), String> {
+    let (address, weight) = match upstream.rsplit_once('@') {
+        Some((address, weight)) => match weight.parse::<usize>() {
+            Ok(weight) if weight > 0 => (address, weight),
+            _ => {
+                return Err(format!(
+                    "invalid weight {:?} for upstream {} (must be a positive integer)",
+                    weight, address
+                ))
+            }
+        },
+        None => (upstream, 1),
+    };
+    UpstreamTarget::parse(address)?;
+    Ok((address.to_string(), weight))
+}
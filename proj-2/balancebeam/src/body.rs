@@ -0,0 +1,200 @@
+use crate::chunked;
+use std::cmp::min;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Instant};
+
+/// How much of a streamed body we read before passing it along. We don't read any more from the
+/// sender until the receiver has accepted these bytes, so a slow receiver slows down the sender
+/// instead of making us buffer the body.
+const COPY_BUFFER_SIZE: usize = 16384;
+
+/// How the end of a message body is marked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// The message has no body
+    Empty,
+    /// The body is exactly this many bytes long
+    ContentLength(usize),
+    /// The body is sent with the chunked transfer coding, and ends with a zero-length chunk
+    Chunked,
+    /// The body ends when the sender closes the connection (only allowed for responses)
+    UntilClose,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The sender hung up before sending Content-Length bytes, or sent bytes after a message that
+    /// has no body
+    ContentLengthMismatch,
+    /// A chunk-size line is not a valid hexadecimal number
+    InvalidChunkSize,
+    /// A chunk or trailer is malformed, or the sender hung up before sending the last chunk
+    MalformedChunk,
+    /// Encountered an I/O error when reading the body from the sender
+    Read(std::io::Error),
+    /// Encountered an I/O error when writing the body to the receiver
+    Write(std::io::Error),
+}
+
+impl From<chunked::Error> for Error {
+    fn from(error: chunked::Error) -> Error {
+        match error {
+            chunked::Error::InvalidChunkSize => Error::InvalidChunkSize,
+            chunked::Error::MalformedChunk | chunked::Error::BodyTooLarge => Error::MalformedChunk,
+            chunked::Error::Io(err) => Error::Read(err),
+        }
+    }
+}
+
+/// Runs a future, returning None if it hasn't finished by the deadline (if there is one)
+pub async fn with_deadline<T>(
+    deadline: Option<Instant>,
+    future: impl Future<Output = T>,
+) -> Option<T> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Passes reads or writes through to another stream, counting the bytes (e.g. for the access log)
+pub struct Counted<'a, W> {
+    inner: &'a mut W,
+    pub count: u64,
+}
+
+impl<'a, W> Counted<'a, W> {
+    pub fn new(inner: &'a mut W) -> Counted<'a, W> {
+        Counted { inner, count: 0 }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(bytes_read)) = result {
+            self.count += bytes_read as u64;
+        }
+        result
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Counted<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let result = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(bytes_written)) = result {
+            self.count += bytes_written as u64;
+        }
+        result
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Writes all of `bytes` and flushes them out. TLS streams hold on to written data until they've
+/// made up a record, so without the flush the end of a message could sit there indefinitely.
+pub async fn write_all(
+    dest: &mut (impl AsyncWrite + Unpin),
+    bytes: &[u8],
+) -> Result<(), std::io::Error> {
+    dest.write_all(bytes).await?;
+    dest.flush().await
+}
+
+/// Copies a message body from `source` to `dest` as it arrives, without holding more than a small
+/// piece of it in memory. `already_read` holds any bytes of the body that were read along with the
+/// headers. Trailers of a chunked body are only passed along if `forward_trailers` is set.
+pub async fn copy(
+    source: &mut (impl AsyncRead + Unpin),
+    already_read: Vec<u8>,
+    dest: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+    forward_trailers: bool,
+) -> Result<(), Error> {
+    match framing {
+        Framing::Empty if already_read.is_empty() => Ok(()),
+        Framing::Empty => Err(Error::ContentLengthMismatch),
+        Framing::ContentLength(len) => copy_exact(source, already_read, dest, len).await,
+        Framing::UntilClose => copy_until_close(source, already_read, dest).await,
+        Framing::Chunked => chunked::copy_body(source, already_read, dest, forward_trailers).await,
+    }
+}
+
+/// Copies a body of exactly `len` bytes
+async fn copy_exact(
+    source: &mut (impl AsyncRead + Unpin),
+    already_read: Vec<u8>,
+    dest: &mut (impl AsyncWrite + Unpin),
+    len: usize,
+) -> Result<(), Error> {
+    if already_read.len() > len {
+        log::debug!("Peer sent more bytes than we expected based on the given content length!");
+        return Err(Error::ContentLengthMismatch);
+    }
+    write_all(dest, &already_read).await.map_err(Error::Write)?;
+    let mut remaining = len - already_read.len();
+    let mut buffer = vec![0_u8; min(COPY_BUFFER_SIZE, remaining)];
+    while remaining > 0 {
+        let max_len = min(buffer.len(), remaining);
+        let bytes_read = source
+            .read(&mut buffer[..max_len])
+            .await
+            .map_err(Error::Read)?;
+        if bytes_read == 0 {
+            log::debug!(
+                "Peer hung up with {} bytes of the body left to send, even though it said the \
+                content length is {}",
+                remaining,
+                len
+            );
+            return Err(Error::ContentLengthMismatch);
+        }
+        write_all(dest, &buffer[..bytes_read])
+            .await
+            .map_err(Error::Write)?;
+        remaining -= bytes_read;
+    }
+    Ok(())
+}
+
+/// Copies a body that ends when the sender closes the connection
+async fn copy_until_close(
+    source: &mut (impl AsyncRead + Unpin),
+    already_read: Vec<u8>,
+    dest: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), Error> {
+    write_all(dest, &already_read).await.map_err(Error::Write)?;
+    let mut buffer = vec![0_u8; COPY_BUFFER_SIZE];
+    loop {
+        let bytes_read = source.read(&mut buffer).await.map_err(Error::Read)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        write_all(dest, &buffer[..bytes_read])
+            .await
+            .map_err(Error::Write)?;
+    }
+}
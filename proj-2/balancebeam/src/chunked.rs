@@ -0,0 +1,275 @@
+use crate::body;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+/// Longest chunk-size or trailer line we are willing to buffer
+const MAX_LINE_SIZE: usize = 4096;
+/// Maximum number of trailer fields we accept after the last chunk
+const MAX_NUM_TRAILERS: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A chunk-size line does not start with a valid hexadecimal number
+    InvalidChunkSize,
+    /// A chunk is not followed by CRLF, a trailer field can't be parsed, or the peer hung up
+    /// before sending the last chunk
+    MalformedChunk,
+    /// The decoded body is bigger than the caller's size limit
+    BodyTooLarge,
+    /// Encountered an I/O error when reading from the stream
+    Io(std::io::Error),
+}
+
+/// Trailer fields sent after the last chunk of a chunked body. These are stored in the extensions
+/// of the http::Request or http::Response so that they can be sent along when the body is
+/// re-encoded.
+#[derive(Debug, Clone, Default)]
+pub struct Trailers(pub http::HeaderMap);
+
+/// Returns true if the message body is sent with the chunked transfer coding. (If there are
+/// several codings, chunked must be the last one.)
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Returns true if the client said it is willing to accept trailers in a chunked response ("TE:
+/// trailers"). Trailers are dropped for other clients, since they may not be able to parse them
+/// (RFC 7230 section 4.1.2).
+pub fn accepts_trailers(request_headers: &http::HeaderMap) -> bool {
+    request_headers
+        .get_all("te")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// Reads chunked data from a stream, starting with any bytes that were already read past the end
+/// of the headers.
+struct ChunkReader<'a, S> {
+    stream: &'a mut S,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<S: AsyncRead + Unpin> ChunkReader<'_, S> {
+    /// Reads more bytes from the stream into the buffer, discarding the bytes that have already
+    /// been consumed
+    async fn fill(&mut self) -> Result<(), Error> {
+        self.buffer.drain(..self.position);
+        self.position = 0;
+        let mut read_buffer = [0_u8; 512];
+        let bytes_read = self
+            .stream
+            .read(&mut read_buffer)
+            .await
+            .map_err(Error::Io)?;
+        if bytes_read == 0 {
+            return Err(Error::MalformedChunk);
+        }
+        self.buffer.extend_from_slice(&read_buffer[..bytes_read]);
+        Ok(())
+    }
+
+    /// Returns the next line (without its CRLF)
+    async fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            let unread = &self.buffer[self.position..];
+            if let Some(end) = unread.windows(2).position(|window| window == b"\r\n") {
+                let line = unread[..end].to_vec();
+                self.position += end + 2;
+                return Ok(line);
+            }
+            if unread.len() > MAX_LINE_SIZE {
+                return Err(Error::MalformedChunk);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Returns the next `len` bytes
+    async fn read_exact(&mut self, len: usize) -> Result<&[u8], Error> {
+        while self.buffer.len() - self.position < len {
+            self.fill().await?;
+        }
+        let bytes = &self.buffer[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Returns between 1 and `max_len` bytes, reading from the stream only if nothing is buffered
+    async fn read_some(&mut self, max_len: usize) -> Result<&[u8], Error> {
+        if self.position == self.buffer.len() {
+            self.fill().await?;
+        }
+        let len = std::cmp::min(max_len, self.buffer.len() - self.position);
+        let bytes = &self.buffer[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Reads the trailer fields after the last chunk, up to and including the final empty line
+    async fn read_trailers(&mut self) -> Result<Trailers, Error> {
+        let mut trailers = http::HeaderMap::new();
+        loop {
+            let line = self.read_line().await?;
+            if line.is_empty() {
+                return Ok(Trailers(trailers));
+            }
+            if trailers.len() >= MAX_NUM_TRAILERS {
+                return Err(Error::MalformedChunk);
+            }
+            let (name, value) = parse_trailer(&line)?;
+            trailers.append(name, value);
+        }
+    }
+
+    /// Makes sure nothing was sent after the end of the body. Since we only handle one message at
+    /// a time, any extra bytes after the last chunk are treated as an error, just like extra bytes
+    /// after a Content-Length body.
+    fn finish(&self) -> Result<(), Error> {
+        if self.position != self.buffer.len() {
+            log::debug!("Peer sent more bytes after the last chunk of a chunked body");
+            return Err(Error::MalformedChunk);
+        }
+        Ok(())
+    }
+}
+
+/// Parses a chunk-size line, ignoring any chunk extensions (";name=value")
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let size = std::str::from_utf8(line).or(Err(Error::InvalidChunkSize))?;
+    let size = size.split(';').next().unwrap().trim();
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(Error::InvalidChunkSize);
+    }
+    usize::from_str_radix(size, 16).or(Err(Error::InvalidChunkSize))
+}
+
+/// Parses a trailer line of the form "name: value"
+fn parse_trailer(line: &[u8]) -> Result<(http::HeaderName, http::HeaderValue), Error> {
+    let colon = line
+        .iter()
+        .position(|&byte| byte == b':')
+        .ok_or(Error::MalformedChunk)?;
+    let name = http::HeaderName::from_bytes(&line[..colon]).or(Err(Error::MalformedChunk))?;
+    let value = std::str::from_utf8(&line[colon + 1..]).or(Err(Error::MalformedChunk))?;
+    let value = http::HeaderValue::from_str(value.trim()).or(Err(Error::MalformedChunk))?;
+    Ok((name, value))
+}
+
+/// Reads and decodes a chunked body from the stream. `already_read` holds any bytes of the encoded
+/// body that were read along with the headers. Returns the decoded body and its trailers.
+pub async fn read_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    already_read: Vec<u8>,
+    max_body_size: usize,
+) -> Result<(Vec<u8>, Trailers), Error> {
+    let mut reader = ChunkReader {
+        stream,
+        buffer: already_read,
+        position: 0,
+    };
+    let mut body = Vec::new();
+    loop {
+        let chunk_size = parse_chunk_size(&reader.read_line().await?)?;
+        if chunk_size == 0 {
+            break;
+        }
+        // (Written this way round so that a huge chunk size can't overflow the sum)
+        if chunk_size > max_body_size - body.len() {
+            return Err(Error::BodyTooLarge);
+        }
+        body.extend_from_slice(reader.read_exact(chunk_size).await?);
+        if reader.read_exact(2).await? != b"\r\n" {
+            return Err(Error::MalformedChunk);
+        }
+    }
+
+    // The last chunk is followed by zero or more trailer fields, and then an empty line
+    let trailers = reader.read_trailers().await?;
+    reader.finish()?;
+    Ok((body, trailers))
+}
+
+/// Copies a chunked body from `source` to `dest` as it arrives, so that only a small piece of a
+/// chunk is held in memory at a time. Chunks are passed along without their extensions, and the
+/// trailers are only passed along if `forward_trailers` is set.
+pub async fn copy_body(
+    source: &mut (impl AsyncRead + Unpin),
+    already_read: Vec<u8>,
+    dest: &mut (impl AsyncWrite + Unpin),
+    forward_trailers: bool,
+) -> Result<(), body::Error> {
+    let mut reader = ChunkReader {
+        stream: source,
+        buffer: already_read,
+        position: 0,
+    };
+    loop {
+        let chunk_size = parse_chunk_size(&reader.read_line().await?)?;
+        if chunk_size == 0 {
+            break;
+        }
+        body::write_all(dest, format!("{:x}\r\n", chunk_size).as_bytes())
+            .await
+            .map_err(body::Error::Write)?;
+        let mut remaining = chunk_size;
+        while remaining > 0 {
+            let bytes = reader.read_some(remaining).await?;
+            remaining -= bytes.len();
+            body::write_all(dest, bytes)
+                .await
+                .map_err(body::Error::Write)?;
+        }
+        if reader.read_exact(2).await? != b"\r\n" {
+            return Err(body::Error::MalformedChunk);
+        }
+        body::write_all(dest, b"\r\n")
+            .await
+            .map_err(body::Error::Write)?;
+    }
+
+    let trailers = reader.read_trailers().await?;
+    reader.finish()?;
+    let mut last_chunk = Vec::new();
+    encode_last_chunk(
+        &mut last_chunk,
+        Some(&trailers).filter(|_| forward_trailers),
+    );
+    body::write_all(dest, &last_chunk)
+        .await
+        .map_err(body::Error::Write)
+}
+
+/// Encodes a body with the chunked transfer coding. The whole body is sent as a single chunk,
+/// followed by the last chunk and any trailers.
+pub fn encode(body: &[u8], trailers: Option<&Trailers>) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(body.len() + 32);
+    if !body.is_empty() {
+        encoded.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+        encoded.extend_from_slice(body);
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encode_last_chunk(&mut encoded, trailers);
+    encoded
+}
+
+/// Appends the zero-length last chunk, followed by any trailers and the final empty line
+fn encode_last_chunk(encoded: &mut Vec<u8>, trailers: Option<&Trailers>) {
+    encoded.extend_from_slice(b"0\r\n");
+    if let Some(Trailers(trailers)) = trailers {
+        for (name, value) in trailers {
+            encoded.extend_from_slice(name.as_str().as_bytes());
+            encoded.extend_from_slice(b": ");
+            encoded.extend_from_slice(value.as_bytes());
+            encoded.extend_from_slice(b"\r\n");
+        }
+    }
+    encoded.extend_from_slice(b"\r\n");
+}
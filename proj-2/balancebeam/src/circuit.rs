@@ -0,0 +1,182 @@
+//! Circuit breakers, which stop sending requests to an upstream that keeps failing. Every upstream
+//! has one, in one of three states:
+//!
+//! * Closed: requests flow as usual. Consecutive failures (5xx responses, or exchanges that never
+//!   got a response) are counted, and --circuit-breaker-failures of them in a row open the
+//!   breaker. An upstream that fails outright, e.g. because it can't be connected to, opens it
+//!   straight away.
+//! * Open: the upstream gets no requests. After --circuit-breaker-open-duration (or as soon as an
+//!   active health check passes), the breaker goes half-open.
+//! * Half-open: up to --circuit-breaker-trial-requests trial requests are let through. If they all
+//!   succeed, the breaker closes again; if any of them fails, it opens again.
+
+use parking_lot::Mutex;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half-open",
+        })
+    }
+}
+
+/// When breakers open and how they recover (the --circuit-breaker-* options)
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    /// Consecutive failures that open a closed breaker (0 = only outright failures do)
+    pub failure_threshold: u32,
+    /// How long an open breaker keeps requests away before letting trial requests through
+    pub open_duration: Duration,
+    /// Number of trial requests that have to succeed before a half-open breaker closes
+    pub trial_requests: u32,
+}
+
+struct Inner {
+    state: State,
+    /// When the breaker entered its current state
+    since: Instant,
+    /// Consecutive failures while closed
+    failures: u32,
+    /// Trial requests let through while half-open
+    trials: u32,
+    /// Trial requests that succeeded while half-open
+    successes: u32,
+}
+
+/// The circuit breaker of a single upstream
+pub struct Breaker {
+    /// Address of the upstream, for logging
+    address: String,
+    settings: Settings,
+    inner: Mutex<Inner>,
+}
+
+impl Breaker {
+    /// Creates a closed breaker
+    pub fn new(address: String, settings: Settings) -> Breaker {
+        Breaker {
+            address,
+            settings,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                since: Instant::now(),
+                failures: 0,
+                trials: 0,
+                successes: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.inner.lock().state
+    }
+
+    /// Returns true if the upstream may be sent a request right now
+    pub fn allows_request(&self) -> bool {
+        let inner = self.inner.lock();
+        match inner.state {
+            State::Closed => true,
+            State::Open => inner.since.elapsed() >= self.settings.open_duration,
+            // Trials that never report back (e.g. because the client went away) are given up on
+            // after another open_duration
+            State::HalfOpen => {
+                inner.trials < self.settings.trial_requests
+                    || inner.since.elapsed() >= self.settings.open_duration
+            }
+        }
+    }
+
+    /// Records that the upstream was chosen for a request, letting it through as a trial request
+    /// if the breaker isn't closed. Callers should check allows_request first.
+    pub fn admit(&self) {
+        let mut inner = self.inner.lock();
+        match inner.state {
+            State::Closed => return,
+            State::Open if inner.since.elapsed() < self.settings.open_duration => return,
+            State::Open => self.transition(&mut inner, State::HalfOpen),
+            State::HalfOpen if inner.trials >= self.settings.trial_requests => {
+                // The earlier trials went missing, so start over
+                inner.since = Instant::now();
+                inner.trials = 0;
+                inner.successes = 0;
+            }
+            State::HalfOpen => {}
+        }
+        inner.trials += 1;
+    }
+
+    /// Records a request that the upstream handled successfully
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock();
+        match inner.state {
+            State::Closed => inner.failures = 0,
+            State::HalfOpen => {
+                inner.successes += 1;
+                if inner.successes >= self.settings.trial_requests {
+                    self.transition(&mut inner, State::Closed);
+                }
+            }
+            // A request that was sent before the breaker opened
+            State::Open => {}
+        }
+    }
+
+    /// Records a request that failed with a 5xx response or without a response at all
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock();
+        match inner.state {
+            State::Closed => {
+                inner.failures += 1;
+                if self.settings.failure_threshold > 0
+                    && inner.failures >= self.settings.failure_threshold
+                {
+                    self.transition(&mut inner, State::Open);
+                }
+            }
+            State::HalfOpen => self.transition(&mut inner, State::Open),
+            State::Open => {}
+        }
+    }
+
+    /// Opens the breaker right away, because the upstream failed in a way that makes further
+    /// requests pointless (e.g. it refused a connection)
+    pub fn trip(&self) {
+        let mut inner = self.inner.lock();
+        if inner.state != State::Open {
+            self.transition(&mut inner, State::Open);
+        }
+    }
+
+    /// Lets trial requests through without waiting out the rest of open_duration, because an
+    /// active health check says the upstream is back
+    pub fn probe(&self) {
+        let mut inner = self.inner.lock();
+        if inner.state == State::Open {
+            self.transition(&mut inner, State::HalfOpen);
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, state: State) {
+        if state == State::Open {
+            log::warn!("Circuit breaker for {} is now {}", self.address, state);
+        } else {
+            log::info!("Circuit breaker for {} is now {}", self.address, state);
+        }
+        inner.state = state;
+        inner.since = Instant::now();
+        inner.failures = 0;
+        inner.trials = 0;
+        inner.successes = 0;
+    }
+}
@@ -0,0 +1,87 @@
+use serde::Deserialize;
+use std::path::Path;
+
+/// Settings read from a --config file. The keys are named after the command-line options they
+/// replace, e.g.:
+///
+/// ```toml
+/// bind = "0.0.0.0:1100"
+/// upstream = ["10.0.0.1:8080@2", "10.0.0.2:8080"]
+/// pool = ["api=10.0.1.1:8080,10.0.1.2:8080", "static=10.0.2.1:8080"]
+/// route = ["prefix:/api/=api", "prefix:/static/=static", "host:assets.example.com=static"]
+/// fallback_pool = "default"
+/// pool_health_check_path = ["api=/healthz"]
+/// pool_strategy = ["api=least-connections"]
+/// active_health_check_interval = 10
+/// active_health_check_path = "/health"
+/// request_header = ["set:X-Environment: production", "remove:X-Debug"]
+/// response_header = ["add:Strict-Transport-Security: max-age=31536000"]
+/// trusted_proxy = ["10.0.0.0/8"]
+/// max_requests_per_minute = 100
+/// rate_limit_burst = 20
+/// tls_cert = "/etc/balancebeam/cert.pem"
+/// tls_key = "/etc/balancebeam/key.pem"
+/// tls_sni_cert = ["api.example.com=/etc/balancebeam/api.pem,/etc/balancebeam/api-key.pem"]
+/// ```
+///
+/// Any setting left out of the file falls back to the command-line value (or its default).
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub bind: Option<String>,
+    /// Upstream servers, as host:port or host:port@weight
+    pub upstream: Option<Vec<String>>,
+    /// Named pools of upstreams, as name=host:port[@weight],...
+    pub pool: Option<Vec<String>>,
+    /// Rules sending requests to pools, as kind:value=pool
+    pub route: Option<Vec<String>>,
+    pub fallback_pool: Option<String>,
+    /// Health check paths for particular pools, as pool=path
+    pub pool_health_check_path: Option<Vec<String>>,
+    /// Load balancing strategies for particular pools, as pool=strategy
+    pub pool_strategy: Option<Vec<String>>,
+    pub active_health_check_interval: Option<usize>,
+    pub active_health_check_path: Option<String>,
+    /// Header rules for requests, as add:Name: value, set:Name: value or remove:Name
+    pub request_header: Option<Vec<String>>,
+    /// Header rules for responses, in the same form as request_header
+    pub response_header: Option<Vec<String>>,
+    /// IPs or CIDR blocks whose forwarding headers are kept
+    pub trusted_proxy: Option<Vec<String>>,
+    pub max_requests_per_minute: Option<usize>,
+    pub rate_limit_burst: Option<usize>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Certificates for particular hostnames, as hostname=cert.pem,key.pem
+    pub tls_sni_cert: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file couldn't be read
+    Io(std::io::Error),
+    /// The config file isn't valid TOML, or has unknown keys or values of the wrong type
+    Toml(toml::de::Error),
+    /// The config file isn't valid YAML, or has unknown keys or values of the wrong type
+    Yaml(serde_yaml::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Toml(err) => write!(f, "{}", err),
+            Error::Yaml(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Reads a config file. Files ending in .yaml or .yml are parsed as YAML, and anything else as
+/// TOML.
+pub async fn load(path: &str) -> Result<Config, Error> {
+    let contents = tokio::fs::read_to_string(path).await.map_err(Error::Io)?;
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(Error::Yaml),
+        _ => toml::from_str(&contents).map_err(Error::Toml),
+    }
+}
@@ -0,0 +1,144 @@
+//! A rate-limit store that shares counts between balancebeam instances, so that a client gets the
+//! configured limit across all of them rather than at each one.
+//!
+//! Every instance keeps its own token buckets, and every GOSSIP_INTERVAL tells its peers (over
+//! UDP) how many requests it has let through from each client since the last round. Peers take
+//! those requests out of their own buckets for the client. The instances agree only eventually, so
+//! a client spreading a burst across instances can get slightly more than its share before the
+//! counts catch up.
+//!
+//! Each datagram holds lines of the form `<client IP> <number of requests>\n`. Datagrams from
+//! addresses other than the configured peers are ignored.
+
+use crate::rate_limit::{Limit, LocalStore, Rejection, Store};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::udp::{RecvHalf, SendHalf};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration};
+
+/// How often each instance tells its peers about the requests it has let through
+const GOSSIP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Largest datagram we send, so that it fits in a typical MTU without fragmenting
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+pub struct GossipStore {
+    /// Buckets reflecting the requests made both here and at the peers
+    local: Arc<LocalStore>,
+    /// Requests let through here since the last gossip round, by client
+    unsent: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl GossipStore {
+    /// Starts exchanging counts with the peers over the gossip socket. The peers are the gossip
+    /// addresses of the other instances.
+    pub async fn start(socket: UdpSocket, peers: &[String]) -> io::Result<GossipStore> {
+        let mut peer_addresses = Vec::new();
+        for peer in peers {
+            peer_addresses.extend(tokio::net::lookup_host(peer).await?);
+        }
+        let (receiver, sender) = socket.split();
+        let store = GossipStore {
+            local: Arc::new(LocalStore::new()),
+            unsent: Arc::new(Mutex::new(HashMap::new())),
+        };
+        tokio::spawn(receive_counts(
+            receiver,
+            peer_addresses.clone(),
+            Arc::clone(&store.local),
+        ));
+        tokio::spawn(send_counts(
+            sender,
+            peer_addresses,
+            Arc::clone(&store.unsent),
+        ));
+        Ok(store)
+    }
+}
+
+impl Store for GossipStore {
+    fn check(&self, client: &str, limit: Limit) -> Result<(), Rejection> {
+        self.local.check(client, limit)?;
+        *self.unsent.lock().entry(client.to_string()).or_insert(0) += 1;
+        Ok(())
+    }
+
+    fn evict_idle(&self, limit: Option<Limit>) {
+        self.local.evict_idle(limit);
+    }
+}
+
+/// Sends the requests let through here to every peer, once per GOSSIP_INTERVAL
+async fn send_counts(
+    mut sender: SendHalf,
+    peers: Vec<SocketAddr>,
+    unsent: Arc<Mutex<HashMap<String, u64>>>,
+) {
+    let mut interval = time::interval(GOSSIP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let counts = std::mem::take(&mut *unsent.lock());
+        if counts.is_empty() {
+            continue;
+        }
+        for datagram in encode(&counts) {
+            for peer in &peers {
+                if let Err(error) = sender.send_to(&datagram, peer).await {
+                    log::debug!("Failed to send rate limit counts to {}: {}", peer, error);
+                }
+            }
+        }
+    }
+}
+
+/// Takes the requests that peers report out of our buckets
+async fn receive_counts(mut receiver: RecvHalf, peers: Vec<SocketAddr>, local: Arc<LocalStore>) {
+    let mut buf = [0u8; 65536];
+    loop {
+        let (len, from) = match receiver.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(error) => {
+                log::debug!("Error receiving rate limit counts: {}", error);
+                continue;
+            }
+        };
+        if !peers.contains(&from) {
+            log::warn!("Ignoring rate limit counts from unknown peer {}", from);
+            continue;
+        }
+        for (client, requests) in decode(&buf[..len]) {
+            local.debit(client, requests);
+        }
+    }
+}
+
+/// Packs the counts into as many datagrams as it takes
+fn encode(counts: &HashMap<String, u64>) -> Vec<Vec<u8>> {
+    let mut datagrams = vec![Vec::new()];
+    for (client, requests) in counts {
+        let line = format!("{} {}\n", client, requests);
+        if datagrams.last().unwrap().len() + line.len() > MAX_DATAGRAM_SIZE {
+            datagrams.push(Vec::new());
+        }
+        datagrams
+            .last_mut()
+            .unwrap()
+            .extend_from_slice(line.as_bytes());
+    }
+    datagrams
+}
+
+/// Unpacks the counts in a datagram, skipping any lines that don't parse
+fn decode(datagram: &[u8]) -> impl Iterator<Item = (&str, u64)> {
+    std::str::from_utf8(datagram)
+        .unwrap_or("")
+        .lines()
+        .filter_map(|line| {
+            let (client, requests) = line.split_once(' ')?;
+            Some((client, requests.parse().ok()?))
+        })
+}
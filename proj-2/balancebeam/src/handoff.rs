@@ -0,0 +1,181 @@
+//! Passing listening sockets from one process to another, so that balancebeam can be restarted
+//! (e.g. to upgrade it) without turning connections away. Sockets are inherited in one of two ways:
+//!
+//! * systemd socket activation: systemd binds the sockets and passes them in from fd 3 onwards,
+//!   with their count in LISTEN_FDS and their names (set with FileDescriptorName=) in
+//!   LISTEN_FDNAMES. An unnamed first socket is taken to be the proxy listener.
+//! * Binary upgrade: on SIGUSR2, balancebeam starts its executable again with the same arguments,
+//!   passing its sockets along in BALANCEBEAM_SOCKETS. Once the new process is ready, it sends the
+//!   old one SIGTERM, and the old one drains its connections as in a graceful shutdown. If the new
+//!   process fails to start, the old one carries on as before.
+//!
+//! Sockets are known by name: "bind" for the proxy listener, "admin" for the admin API and "gossip"
+//! for rate limit gossip. An inherited socket is used instead of binding the configured address.
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{self, Pid};
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::os::unix::io::{FromRawFd, RawFd};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::process::Command;
+
+pub const PROXY: &str = "bind";
+pub const ADMIN: &str = "admin";
+pub const GOSSIP: &str = "gossip";
+
+/// The first fd passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets handed to us by systemd or by the process we are taking over from
+pub struct Inherited {
+    fds: HashMap<String, RawFd>,
+    /// The process we are taking over from, which is told to shut down once we are ready
+    upgrade_from: Option<Pid>,
+}
+
+impl Inherited {
+    /// Picks up the sockets described by the environment, removing the variables so that they
+    /// aren't passed on to processes we start
+    pub fn from_env() -> Result<Inherited, String> {
+        let mut fds = HashMap::new();
+        if let Some(count) = take_var("LISTEN_FDS") {
+            let listen_pid = take_var("LISTEN_PID");
+            let names = take_var("LISTEN_FDNAMES").unwrap_or_default();
+            // The variables are meant for the process systemd started, which may not be us
+            if listen_pid == Some(unistd::getpid().to_string()) {
+                let count: RawFd = count
+                    .parse()
+                    .map_err(|_| format!("Invalid LISTEN_FDS {:?}", count))?;
+                let names: Vec<&str> = names.split(':').collect();
+                for index in 0..count {
+                    let fd = SD_LISTEN_FDS_START + index;
+                    match names.get(index as usize) {
+                        Some(name) if !name.is_empty() && *name != "unknown" => {
+                            fds.insert(name.to_string(), fd);
+                        }
+                        _ if index == 0 => {
+                            fds.insert(PROXY.to_string(), fd);
+                        }
+                        _ => {
+                            log::warn!("Closing unnamed socket (fd {}) passed by systemd", fd);
+                            let _ = unistd::close(fd);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(sockets) = take_var("BALANCEBEAM_SOCKETS") {
+            for socket in sockets.split(',').filter(|socket| !socket.is_empty()) {
+                let (name, fd) = socket
+                    .split_once('=')
+                    .and_then(|(name, fd)| Some((name, fd.parse().ok()?)))
+                    .ok_or_else(|| format!("Invalid BALANCEBEAM_SOCKETS entry {:?}", socket))?;
+                fds.insert(name.to_string(), fd);
+            }
+        }
+        let upgrade_from = match take_var("BALANCEBEAM_UPGRADE_FROM") {
+            Some(pid) => {
+                Some(Pid::from_raw(pid.parse().map_err(|_| {
+                    format!("Invalid BALANCEBEAM_UPGRADE_FROM {:?}", pid)
+                })?))
+            }
+            None => None,
+        };
+        // Keep the sockets out of processes we start, until we pass them on deliberately
+        for (name, &fd) in &fds {
+            set_inheritable(fd, false)
+                .map_err(|error| format!("Inherited {} socket (fd {}): {}", name, fd, error))?;
+        }
+        Ok(Inherited { fds, upgrade_from })
+    }
+
+    /// Returns the inherited listener called `name`, or else binds a new one to `address`
+    pub async fn tcp_listener(&mut self, name: &str, address: &str) -> io::Result<TcpListener> {
+        match self.fds.remove(name) {
+            Some(fd) => {
+                log::info!("Using inherited {} socket (fd {})", name, fd);
+                // Safety: the fd was handed over for us to own, and it is only taken once
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            }
+            None => TcpListener::bind(address).await,
+        }
+    }
+
+    /// Returns the inherited UDP socket called `name`, or else binds a new one to `address`
+    pub async fn udp_socket(&mut self, name: &str, address: &str) -> io::Result<UdpSocket> {
+        match self.fds.remove(name) {
+            Some(fd) => {
+                log::info!("Using inherited {} socket (fd {})", name, fd);
+                // Safety: as in tcp_listener
+                let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)
+            }
+            None => UdpSocket::bind(address).await,
+        }
+    }
+
+    /// Called once we are ready to serve. Tells the process we are taking over from (if any) to
+    /// shut down, and closes inherited sockets that weren't needed.
+    pub fn finish(mut self) {
+        if let Some(pid) = self.upgrade_from {
+            log::info!("Taking over from process {}", pid);
+            if let Err(error) = kill(pid, Signal::SIGTERM) {
+                log::warn!("Could not tell process {} to shut down: {}", pid, error);
+            }
+        }
+        for (name, fd) in self.fds.drain() {
+            log::warn!("Closing unused inherited {} socket (fd {})", name, fd);
+            let _ = unistd::close(fd);
+        }
+    }
+}
+
+/// Starts a new balancebeam process with our arguments, handing it the given sockets. Returns its
+/// pid.
+pub fn spawn_successor(sockets: &[(&str, RawFd)]) -> io::Result<u32> {
+    let mut args = env::args_os();
+    // Run whatever is at the path we were started from, rather than current_exe(), which still
+    // points at our own (possibly since replaced) binary
+    let program = args.next().unwrap_or_else(|| "balancebeam".into());
+    let passed: Vec<String> = sockets
+        .iter()
+        .map(|(name, fd)| format!("{}={}", name, fd))
+        .collect();
+    for &(_, fd) in sockets {
+        set_inheritable(fd, true)?;
+    }
+    let child = Command::new(program)
+        .args(args)
+        .env("BALANCEBEAM_SOCKETS", passed.join(","))
+        .env("BALANCEBEAM_UPGRADE_FROM", unistd::getpid().to_string())
+        .spawn();
+    for &(_, fd) in sockets {
+        set_inheritable(fd, false)?;
+    }
+    Ok(child?.id())
+}
+
+/// Removes an environment variable, returning its value if it was set
+fn take_var(name: &str) -> Option<String> {
+    let value = env::var(name).ok();
+    env::remove_var(name);
+    value
+}
+
+/// Sets whether an fd is kept open across exec
+fn set_inheritable(fd: RawFd, inheritable: bool) -> io::Result<()> {
+    let flags = if inheritable {
+        FdFlag::empty()
+    } else {
+        FdFlag::FD_CLOEXEC
+    };
+    fcntl(fd, FcntlArg::F_SETFD(flags))
+        .map(|_| ())
+        .map_err(io::Error::other)
+}
@@ -0,0 +1,111 @@
+use std::str::FromStr;
+
+/// The part of a request that decides which upstream it sticks to when hashing is enabled
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashKey {
+    /// The IP address of the client
+    ClientIp,
+    /// The value of the named request header
+    Header(http::header::HeaderName),
+    /// The value of the named cookie
+    Cookie(String),
+}
+
+impl FromStr for HashKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "client-ip" {
+            Ok(HashKey::ClientIp)
+        } else if let Some(name) = s.strip_prefix("header:") {
+            http::header::HeaderName::from_bytes(name.as_bytes())
+                .map(HashKey::Header)
+                .map_err(|_| format!("invalid header name {:?}", name))
+        } else if let Some(name) = s.strip_prefix("cookie:") {
+            Ok(HashKey::Cookie(name.to_string()))
+        } else {
+            Err(format!(
+                "unknown hash key {:?} (expected client-ip, header:<name> or cookie:<name>)",
+                s
+            ))
+        }
+    }
+}
+
+impl HashKey {
+    /// Extracts the key from a request. Returns None if the request doesn't carry the header or
+    /// cookie, in which case the request should be load balanced normally.
+    pub fn extract(&self, request: &http::Request<Vec<u8>>, client_ip: &str) -> Option<String> {
+        match self {
+            HashKey::ClientIp => Some(client_ip.to_string()),
+            HashKey::Header(name) => request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+            HashKey::Cookie(name) => request
+                .headers()
+                .get_all("cookie")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie_name, _)| cookie_name == name)
+                .map(|(_, cookie_value)| cookie_value.to_string()),
+        }
+    }
+}
+
+/// A consistent-hash ring over the upstream servers. Each upstream is placed on the ring at several
+/// points (virtual nodes) so that keys are spread evenly, and a key belongs to the first upstream
+/// found clockwise from the key's hash. When an upstream is dead, its keys move on to the next
+/// alive upstream on the ring, while keys belonging to other upstreams stay where they are.
+pub struct HashRing {
+    /// (hash, upstream index) pairs, sorted by hash
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    pub fn new(upstream_addresses: &[String], virtual_nodes: usize) -> HashRing {
+        let mut points = Vec::with_capacity(upstream_addresses.len() * virtual_nodes);
+        for (index, address) in upstream_addresses.iter().enumerate() {
+            for vnode in 0..virtual_nodes {
+                points.push((hash(format!("{}#{}", address, vnode).as_bytes()), index));
+            }
+        }
+        points.sort_unstable();
+        HashRing { points }
+    }
+
+    /// Returns the index of the upstream that the key belongs to, skipping upstreams for which
+    /// `is_alive` returns false. Returns None if no upstream is alive.
+    pub fn lookup<F: Fn(usize) -> bool>(&self, key: &str, is_alive: F) -> Option<usize> {
+        let start = match self.points.binary_search(&(hash(key.as_bytes()), 0)) {
+            Ok(position) | Err(position) => position,
+        };
+        self.points
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(self.points.len())
+            .map(|&(_, index)| index)
+            .find(|&index| is_alive(index))
+    }
+}
+
+/// Hashes bytes with 64-bit FNV-1a, followed by a finalizer that mixes the bits so that similar
+/// inputs (e.g. "host:port#1" and "host:port#2") land far apart on the ring. Unlike
+/// std::collections::hash_map::DefaultHasher, this is stable across builds, so every balancebeam
+/// instance maps a given key to the same upstream.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
@@ -0,0 +1,363 @@
+//! Rewriting headers as requests and responses pass through balancebeam. In order:
+//!
+//! * Hop-by-hop headers (Connection, the headers it lists, Keep-Alive, TE, Upgrade, ...) describe
+//!   a single connection, so they are dropped rather than passed along (RFC 7230 section 6.1).
+//!   Upgrade requests and 101 responses keep what they need to switch protocols.
+//! * Requests get X-Forwarded-For, X-Forwarded-Proto, X-Forwarded-Host and Forwarded (RFC 7239)
+//!   headers describing the client, and both directions get a Via header. Clients could put
+//!   anything in the forwarding headers, so the ones they send are only kept if the client is a
+//!   trusted proxy (--trusted-proxy); otherwise they are replaced.
+//! * Requests get an X-Request-Id header, and requests that are part of a trace get a traceparent
+//!   naming us as the parent span (see the trace module).
+//! * Finally, the configured rules (--request-header and --response-header) add, set or remove
+//!   headers.
+
+use crate::{chunked, request, trace};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// How balancebeam names itself in Via headers
+const VIA_PSEUDONYM: &str = "balancebeam";
+
+/// Headers that only apply to a single connection, besides the ones listed in Connection
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// The headers that carry information about the client, which a client could use to pretend to be
+/// someone else
+const FORWARDING: [&str; 4] = [
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "forwarded",
+];
+
+/// The header carrying the request ID, both to the upstream and back to the client
+const REQUEST_ID: &str = "x-request-id";
+
+/// The W3C Trace Context headers
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// A configured change to the headers of every request or response
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// Adds a value, keeping any values the header already has
+    Add(HeaderName, HeaderValue),
+    /// Replaces all values of the header
+    Set(HeaderName, HeaderValue),
+    Remove(HeaderName),
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// Parses a rule of the form add:Name: value, set:Name: value or remove:Name
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid header rule {:?} (expected add:<name>: <value>, set:<name>: <value> or \
+                remove:<name>)",
+                s
+            )
+        };
+        let (action, header) = s.split_once(':').ok_or_else(invalid)?;
+        let parse_name = |name: &str| match HeaderName::from_str(name.trim()) {
+            // We set these ourselves, depending on how we forward the body
+            Ok(name) if name == header::CONTENT_LENGTH || name == header::TRANSFER_ENCODING => {
+                Err(format!("header rule {:?} can't change {}", s, name))
+            }
+            Ok(name) => Ok(name),
+            Err(_) => Err(invalid()),
+        };
+        let parse_header = || {
+            let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+            let value = HeaderValue::from_str(value.trim()).map_err(|_| invalid())?;
+            Ok((parse_name(name)?, value))
+        };
+        match action {
+            "add" => parse_header().map(|(name, value)| Rule::Add(name, value)),
+            "set" => parse_header().map(|(name, value)| Rule::Set(name, value)),
+            "remove" => parse_name(header).map(Rule::Remove),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Rule {
+    fn apply(&self, headers: &mut HeaderMap) {
+        match self {
+            Rule::Add(name, value) => {
+                headers.append(name, value.clone());
+            }
+            Rule::Set(name, value) => {
+                headers.insert(name, value.clone());
+            }
+            Rule::Remove(name) => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+/// A proxy whose forwarding headers we believe (--trusted-proxy), given as an IP address or a CIDR
+/// block
+#[derive(Debug, Clone)]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid trusted proxy {:?} (expected an IP or CIDR block)",
+                s
+            )
+        };
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(TrustedProxy {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        // Compare the leading prefix_len bits of the addresses
+        let (network, ip, bits) = match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix_len;
+        shift == bits || network >> shift == ip >> shift
+    }
+}
+
+/// Rewrites the headers of the requests and responses we pass along
+pub struct Rewriter {
+    pub request_rules: Vec<Rule>,
+    pub response_rules: Vec<Rule>,
+    pub trusted_proxies: Vec<TrustedProxy>,
+}
+
+impl Rewriter {
+    /// Returns true if the headers a client sends about earlier hops can be believed
+    fn trusts(&self, client_ip: &str) -> bool {
+        client_ip
+            .parse()
+            .map(|ip| self.trusted_proxies.iter().any(|proxy| proxy.contains(ip)))
+            .unwrap_or(false)
+    }
+
+    /// Returns the ID to identify a request by: the X-Request-Id it came with if the client is a
+    /// trusted proxy, or else a new one
+    pub fn request_id(&self, request: &http::Request<Vec<u8>>, client_ip: &str) -> String {
+        request
+            .headers()
+            .get(REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| trace::is_valid_request_id(id) && self.trusts(client_ip))
+            .map_or_else(trace::new_request_id, str::to_string)
+    }
+
+    /// Prepares a request from a client for its upstream. `client_ip` is the address the request
+    /// came from, `proto` is "http" or "https", `received_version` is the HTTP version the client
+    /// spoke (which may not be the version we forward it with), and `request_id` is the ID from
+    /// request_id.
+    pub fn rewrite_request(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+        client_ip: &str,
+        proto: &str,
+        received_version: http::Version,
+        request_id: &str,
+    ) {
+        let upgrade = wants_upgrade(request.headers());
+        // We read trailers ourselves, so the upstream may send them if the client can take them
+        let accepts_trailers = chunked::accepts_trailers(request.headers());
+        let headers = request.headers_mut();
+        remove_hop_by_hop(headers, upgrade);
+        if accepts_trailers {
+            headers.insert(header::TE, HeaderValue::from_static("trailers"));
+        }
+
+        if !self.trusts(client_ip) {
+            for name in &FORWARDING {
+                headers.remove(*name);
+            }
+        }
+        let host = headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(str::to_string);
+        if !headers.contains_key("x-forwarded-proto") {
+            headers.insert("x-forwarded-proto", HeaderValue::from_str(proto).unwrap());
+        }
+        if let Some(host) = &host {
+            if !headers.contains_key("x-forwarded-host") {
+                headers.insert("x-forwarded-host", HeaderValue::from_str(host).unwrap());
+            }
+        }
+        let mut forwarded = format!("for={};proto={}", forwarded_node(client_ip), proto);
+        if let Some(host) = &host {
+            forwarded += &format!(";host={}", quote_if_needed(host));
+        }
+        request::extend_header_value(request, "forwarded", &forwarded);
+        // Tell the upstream the client's IP address. (We're the ones connecting directly to the
+        // upstream server, so without this header, the upstream server will only know our IP,
+        // not the client's.)
+        request::extend_header_value(request, "x-forwarded-for", client_ip);
+        set_request_id(request.headers_mut(), request_id);
+        continue_trace(request.headers_mut());
+        append_via(request.headers_mut(), received_version);
+
+        for rule in &self.request_rules {
+            rule.apply(request.headers_mut());
+        }
+    }
+
+    /// Prepares a response from an upstream for the client
+    pub fn rewrite_response(&self, response: &mut http::Response<Vec<u8>>) {
+        let upgrade = response.status() == http::StatusCode::SWITCHING_PROTOCOLS;
+        let version = response.version();
+        let headers = response.headers_mut();
+        remove_hop_by_hop(headers, upgrade);
+        append_via(headers, version);
+        for rule in &self.response_rules {
+            rule.apply(headers);
+        }
+    }
+}
+
+/// Sets the X-Request-Id header, replacing any the message already has
+pub fn set_request_id(headers: &mut HeaderMap, request_id: &str) {
+    // Request IDs are either checked by trace::is_valid_request_id or generated by us
+    headers.insert(REQUEST_ID, HeaderValue::from_str(request_id).unwrap());
+}
+
+/// Makes us the parent span of a request that is part of a trace, dropping the trace headers if
+/// they are invalid
+fn continue_trace(headers: &mut HeaderMap) {
+    let mut traceparents = headers.get_all(TRACEPARENT).iter();
+    let child = match (traceparents.next(), traceparents.next()) {
+        (None, _) => return,
+        (Some(traceparent), None) => traceparent.to_str().ok().and_then(trace::child_traceparent),
+        // More than one traceparent is invalid too
+        (Some(_), Some(_)) => None,
+    };
+    match child {
+        Some(traceparent) => {
+            headers.insert(TRACEPARENT, HeaderValue::from_str(&traceparent).unwrap());
+        }
+        None => {
+            headers.remove(TRACEPARENT);
+            headers.remove(TRACESTATE);
+        }
+    }
+}
+
+/// Returns the tokens listed in the Connection header
+fn connection_tokens(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+/// Returns true if a request asks to switch to another protocol (e.g. WebSockets)
+fn wants_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && connection_tokens(headers)
+            .iter()
+            .any(|token| token == "upgrade")
+}
+
+/// Removes the headers that only apply to the connection a message arrived on. With
+/// `keep_upgrade`, Upgrade is kept and Connection is reduced to "upgrade", so that the next hop
+/// switches protocols too.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap, keep_upgrade: bool) {
+    for token in connection_tokens(headers) {
+        if let Ok(name) = HeaderName::from_bytes(token.as_bytes()) {
+            if !(keep_upgrade && name == header::UPGRADE) {
+                headers.remove(name);
+            }
+        }
+    }
+    for name in &HOP_BY_HOP {
+        if !(keep_upgrade && *name == "upgrade") {
+            headers.remove(*name);
+        }
+    }
+    if keep_upgrade {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+/// Adds balancebeam to the Via header of a message received with the given HTTP version
+fn append_via(headers: &mut HeaderMap, version: http::Version) {
+    let version = match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_2 => "2",
+        _ => "1.1",
+    };
+    headers.append(
+        header::VIA,
+        HeaderValue::from_str(&format!("{} {}", version, VIA_PSEUDONYM)).unwrap(),
+    );
+}
+
+/// Formats an IP address as a node in a Forwarded header. IPv6 addresses have to be bracketed,
+/// which means they have to be quoted.
+fn forwarded_node(ip: &str) -> String {
+    if ip.contains(':') {
+        format!("\"[{}]\"", ip)
+    } else {
+        ip.to_string()
+    }
+}
+
+/// Quotes a Forwarded header value unless it is a plain token
+fn quote_if_needed(value: &str) -> String {
+    let is_token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
@@ -0,0 +1,400 @@
+//! HTTP/2 for client connections. Clients get HTTP/2 by negotiating "h2" through ALPN during the
+//! TLS handshake, or, on cleartext connections, by opening with the HTTP/2 connection preface
+//! ("prior knowledge" h2c).
+//!
+//! Each stream is turned into an HTTP/1.1 request and forwarded to an upstream like any other
+//! request, so upstreams never see HTTP/2. Since streams are multiplexed, every one of them is
+//! balanced separately (as with --per-request-balancing), and their bodies are buffered in full
+//! even with --stream-bodies.
+
+use crate::{access_log, body, chunked, headers, pool, request, response, ProxyState};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::StatusCode;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{delay_for, Duration};
+
+/// Every HTTP/2 connection starts with these bytes (RFC 7540 section 3.5)
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The ALPN protocol name for HTTP/2 over TLS
+pub const ALPN_H2: &[u8] = b"h2";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request can't be expressed in HTTP/1.1 (e.g. a CONNECT tunnel)
+    Unsupported,
+    /// The request body is bigger than request::MAX_BODY_SIZE
+    RequestBodyTooLarge,
+    /// The client took longer than --client-body-timeout to send the body
+    Timeout,
+    /// The client reset the stream or broke the HTTP/2 protocol
+    H2(h2::Error),
+}
+
+/// Returns true if a cleartext client opened the connection with the HTTP/2 preface. The bytes
+/// are only peeked at, so an HTTP/1 request is left in the stream to be read as usual.
+pub async fn starts_with_preface(stream: &mut TcpStream) -> bool {
+    let mut buffer = [0_u8; PREFACE.len()];
+    loop {
+        let bytes_read = match stream.peek(&mut buffer).await {
+            Ok(bytes_read) => bytes_read,
+            Err(_) => return false,
+        };
+        if bytes_read == 0 || buffer[..bytes_read] != PREFACE[..bytes_read] {
+            return false;
+        }
+        if bytes_read == PREFACE.len() {
+            return true;
+        }
+        // Only part of the preface has arrived. peek returns straight away while any data is
+        // waiting, so give the rest a moment rather than spinning.
+        delay_for(Duration::from_millis(10)).await;
+    }
+}
+
+/// Serves an HTTP/2 connection until the client hangs up, handling each stream on its own task
+pub async fn serve(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    state: Arc<ProxyState>,
+    client_ip: String,
+) {
+    let handshake = h2::server::handshake(stream);
+    let mut connection =
+        match body::with_deadline(state.client_timeouts.header_deadline(), handshake).await {
+            Some(Ok(connection)) => connection,
+            Some(Err(error)) => {
+                log::info!("HTTP/2 handshake with {} failed: {}", client_ip, error);
+                return;
+            }
+            None => {
+                log::info!("HTTP/2 handshake with {} timed out", client_ip);
+                return;
+            }
+        };
+    // Once we start shutting down, a GOAWAY tells the client to stop opening streams, and accept()
+    // runs out when the streams already open have finished
+    let mut going_away = false;
+    loop {
+        let result = tokio::select! {
+            result = connection.accept() => match result {
+                Some(result) => result,
+                None => break,
+            },
+            _ = state.shutdown.started(), if !going_away => {
+                log::debug!("Shutting down HTTP/2 connection from {}", client_ip);
+                connection.graceful_shutdown();
+                going_away = true;
+                continue;
+            }
+        };
+        match result {
+            Ok((request, respond)) => {
+                tokio::spawn(handle_stream(
+                    Arc::clone(&state),
+                    client_ip.clone(),
+                    request,
+                    respond,
+                ));
+            }
+            Err(error) => {
+                log::info!("HTTP/2 connection from {} failed: {}", client_ip, error);
+                return;
+            }
+        }
+    }
+    log::debug!("Client finished sending requests. Shutting down connection");
+}
+
+/// Reads the rest of an HTTP/2 request and turns it into the HTTP/1.1 request we forward upstream.
+/// The body (and any trailers) must arrive by `body_deadline`.
+async fn read_request(
+    request: http::Request<RecvStream>,
+    body_deadline: Option<tokio::time::Instant>,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let (parts, mut recv_stream) = request.into_parts();
+    let path = match parts.uri.path_and_query() {
+        Some(path) if parts.method != http::Method::CONNECT => path.as_str(),
+        _ => return Err(Error::Unsupported),
+    };
+
+    let read_body = async {
+        let mut body = Vec::new();
+        while let Some(data) = recv_stream.data().await {
+            let data = data.map_err(Error::H2)?;
+            if data.len() > request::MAX_BODY_SIZE - body.len() {
+                return Err(Error::RequestBodyTooLarge);
+            }
+            body.extend_from_slice(&data);
+            let _ = recv_stream.flow_control().release_capacity(data.len());
+        }
+        let trailers = recv_stream.trailers().await.map_err(Error::H2)?;
+        Ok((body, trailers))
+    };
+    let (body, trailers) = body::with_deadline(body_deadline, read_body)
+        .await
+        .ok_or(Error::Timeout)??;
+    let body_len = body.len();
+
+    let mut request = http::Request::builder()
+        .method(parts.method.clone())
+        .uri(path)
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap();
+    let headers = request.headers_mut();
+    for (name, value) in &parts.headers {
+        if name != http::header::COOKIE && name != http::header::CONTENT_LENGTH {
+            headers.append(name, value.clone());
+        }
+    }
+    // HTTP/2 lets clients split cookies into several fields, but HTTP/1.1 wants just one
+    let cookies: Vec<&[u8]> = parts
+        .headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .map(|value| value.as_bytes())
+        .collect();
+    if !cookies.is_empty() {
+        let cookies = http::HeaderValue::from_bytes(&cookies.join(&b"; "[..])).unwrap();
+        headers.insert(http::header::COOKIE, cookies);
+    }
+    // The :authority pseudo-header takes the place of Host
+    if !headers.contains_key(http::header::HOST) {
+        if let Some(authority) = parts.uri.authority() {
+            let host = http::HeaderValue::from_str(authority.as_str()).unwrap();
+            headers.insert(http::header::HOST, host);
+        }
+    }
+    // HTTP/2 frames mark the end of the body, so it needs a length (or chunked encoding, if there
+    // are trailers to send after it) once it's on an HTTP/1.1 connection
+    match trailers {
+        Some(trailers) => {
+            headers.insert(
+                http::header::TRANSFER_ENCODING,
+                http::HeaderValue::from_static("chunked"),
+            );
+            request.extensions_mut().insert(chunked::Trailers(trailers));
+        }
+        None => {
+            if body_len > 0 || parts.headers.contains_key(http::header::CONTENT_LENGTH) {
+                headers.insert(
+                    http::header::CONTENT_LENGTH,
+                    http::HeaderValue::from(body_len),
+                );
+            }
+        }
+    }
+    Ok(request)
+}
+
+/// Forwards one stream to an upstream and sends back its response
+async fn handle_stream(
+    state: Arc<ProxyState>,
+    client_ip: String,
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+) {
+    let body_deadline = state.client_timeouts.body_deadline();
+    let mut request = match read_request(request, body_deadline).await {
+        Ok(request) => request,
+        Err(Error::H2(error)) => {
+            log::info!("Error reading request from client stream: {}", error);
+            return;
+        }
+        Err(error) => {
+            log::debug!("Error parsing request: {:?}", error);
+            let status = match error {
+                Error::Unsupported => StatusCode::NOT_IMPLEMENTED,
+                Error::Timeout => StatusCode::REQUEST_TIMEOUT,
+                _ => StatusCode::PAYLOAD_TOO_LARGE,
+            };
+            let response = response::make_http_error(status);
+            send_response(&state, &client_ip, &mut respond, response, false, None);
+            return;
+        }
+    };
+    let request_id = state
+        .header_rewriter
+        .read()
+        .await
+        .request_id(&request, &client_ip);
+    let mut entry = access_log::Entry::new(&client_ip, &request, request_id);
+
+    if let Some(limit) = state.rate_limit() {
+        if let Err(rejection) = state.rate_limiter.check(&client_ip, limit) {
+            state.metrics.record_rate_limited();
+            send_response(
+                &state,
+                &client_ip,
+                &mut respond,
+                rejection.response(),
+                false,
+                Some(&entry),
+            );
+            return;
+        }
+    }
+
+    let pool = state.router.read().await.route(&request).to_string();
+    let affinity_key = state
+        .hash_key
+        .as_ref()
+        .and_then(|hash_key| hash_key.extract(&request, &client_ip));
+    let mut upstream_conn = match crate::connect_to_upstream(
+        Arc::clone(&state),
+        &pool,
+        affinity_key.as_deref(),
+    )
+    .await
+    {
+        Ok(upstream_conn) => upstream_conn,
+        Err(error) => {
+            let response = crate::upstream_error_response(error.kind() == ErrorKind::TimedOut);
+            send_response(
+                &state,
+                &client_ip,
+                &mut respond,
+                response,
+                false,
+                Some(&entry),
+            );
+            return;
+        }
+    };
+    log::info!(
+        "{} -> {}: {} (HTTP/2) [{}]",
+        client_ip,
+        upstream_conn.address,
+        request::format_request_line(&request),
+        entry.request_id()
+    );
+    state.header_rewriter.read().await.rewrite_request(
+        &mut request,
+        &client_ip,
+        state.client_proto(),
+        http::Version::HTTP_2,
+        entry.request_id(),
+    );
+
+    let forwarded = Instant::now();
+    let response = crate::forward_request(
+        &state,
+        &mut upstream_conn,
+        &request,
+        affinity_key.as_deref(),
+        false,
+        entry.request_id(),
+    )
+    .await;
+    entry.upstream = Some(upstream_conn.address.clone());
+    entry.upstream_duration = Some(forwarded.elapsed());
+    let mut response = match response {
+        Ok(response) => response,
+        Err(error) => {
+            log::error!(
+                "Error exchanging request with upstream {}: {:?} [{}]",
+                upstream_conn.address,
+                error,
+                entry.request_id()
+            );
+            let response = crate::upstream_error_response(error.is_timeout());
+            send_response(
+                &state,
+                &client_ip,
+                &mut respond,
+                response,
+                false,
+                Some(&entry),
+            );
+            return;
+        }
+    };
+    if pool::can_reuse(&request, &response) {
+        state
+            .connection_pool
+            .checkin(&upstream_conn.address, upstream_conn.stream)
+            .await;
+    }
+    state
+        .header_rewriter
+        .read()
+        .await
+        .rewrite_response(&mut response);
+    let forward_trailers = chunked::accepts_trailers(request.headers());
+    send_response(
+        &state,
+        &client_ip,
+        &mut respond,
+        response,
+        forward_trailers,
+        Some(&entry),
+    );
+    log::debug!("Forwarded response to client [{}]", entry.request_id());
+}
+
+/// Sends an HTTP/1.1 response on an HTTP/2 stream, dropping the headers that only make sense for
+/// the connection they arrived on. `entry` describes the request it answers, if it could be read.
+fn send_response(
+    state: &ProxyState,
+    client_ip: &str,
+    respond: &mut SendResponse<Bytes>,
+    mut response: http::Response<Vec<u8>>,
+    forward_trailers: bool,
+    entry: Option<&access_log::Entry>,
+) {
+    state.metrics.record_response(response.status());
+    if let Some(entry) = entry {
+        headers::set_request_id(response.headers_mut(), entry.request_id());
+        crate::log_access(
+            state,
+            entry,
+            response.status(),
+            response.body().len() as u64,
+        );
+    }
+    log::info!(
+        "{} <- {} (HTTP/2) [{}]",
+        client_ip,
+        response::format_response_line(&response),
+        entry.map_or("-", access_log::Entry::request_id)
+    );
+    let (mut parts, body) = response.into_parts();
+    // HTTP/2 frames the body itself
+    headers::remove_hop_by_hop(&mut parts.headers, false);
+    parts.headers.remove(http::header::TRANSFER_ENCODING);
+    parts.version = http::Version::HTTP_2;
+    let trailers = parts
+        .extensions
+        .remove::<chunked::Trailers>()
+        .filter(|_| forward_trailers)
+        .map(|chunked::Trailers(trailers)| trailers)
+        .filter(|trailers| !trailers.is_empty());
+
+    let result = respond
+        .send_response(
+            http::Response::from_parts(parts, ()),
+            body.is_empty() && trailers.is_none(),
+        )
+        .and_then(|mut send_stream| {
+            if !body.is_empty() {
+                send_stream.send_data(Bytes::from(body), trailers.is_none())?;
+            }
+            match trailers {
+                Some(trailers) => send_stream.send_trailers(trailers),
+                None => Ok(()),
+            }
+        });
+    if let Err(error) = result {
+        log::warn!(
+            "Failed to send response to client: {} [{}]",
+            error,
+            entry.map_or("-", access_log::Entry::request_id)
+        );
+    }
+}
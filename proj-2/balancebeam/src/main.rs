@@ -1,22 +1,64 @@
+mod access_log;
+mod admin;
+mod balancer;
+mod body;
+mod chunked;
+mod circuit;
+mod config;
+mod gossip;
+mod handoff;
+mod hash_ring;
+mod headers;
+mod http2;
+mod metrics;
+mod pool;
+mod rate_limit;
 mod request;
 mod response;
+mod retry;
+mod routing;
+mod shutdown;
+mod tcp;
+mod tls;
+mod trace;
+mod tunnel;
+mod upstreams;
 
-use std::{collections::HashMap, sync::Arc};
+use std::sync::{atomic::AtomicUsize, atomic::Ordering, Arc};
 
+use access_log::AccessLog;
+use balancer::{Candidate, LoadGuard, Strategy};
+use body::Framing;
+use circuit::Breaker;
 use clap::Parser;
+use gossip::GossipStore;
+use hash_ring::HashKey;
 use http::StatusCode;
-use rand::{Rng, SeedableRng};
+use metrics::Metrics;
+use pool::{ConnectionPool, UpstreamConnection};
+use rate_limit::{Limit, LocalStore};
+use retry::RetryBudget;
+use routing::{Pool, Route, Router};
+use shutdown::Shutdown;
+use std::collections::HashMap;
 use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::time::{Instant, SystemTime};
+use tcp::{Mode, ProxyProtocol};
+use tls::{MaybeTlsStream, Terminator, UpstreamTarget, Verification};
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::AsyncWriteExt,
+    net::TcpStream,
+    signal::unix::{signal, SignalKind},
     stream::StreamExt,
     sync::RwLock,
-    time::{self, Duration},
+    time::{self, delay_for, Duration},
 };
+use upstreams::Upstreams;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(about = "Fun with load balancing")]
 struct CmdOptions {
     #[clap(
@@ -26,8 +68,63 @@ struct CmdOptions {
         default_value = "0.0.0.0:1100"
     )]
     bind: String,
-    #[clap(short, long, help = "Upstream host to forward requests to")]
+    #[clap(
+        long,
+        help = "Protocol to proxy: http, or tcp to relay raw TCP connections without parsing them",
+        default_value = "http"
+    )]
+    mode: Mode,
+    #[clap(
+        long,
+        help = "In TCP mode, start each upstream connection with a PROXY protocol header carrying \
+                the client's address (none, v1 or v2)",
+        default_value = "none"
+    )]
+    proxy_protocol: ProxyProtocol,
+    #[clap(
+        long,
+        help = "In TCP mode, close connections once nothing has passed through them for this long \
+                (in seconds, 0 = never)",
+        default_value = "0"
+    )]
+    tcp_idle_timeout: u64,
+    #[clap(
+        short,
+        long,
+        help = "Upstream host to forward requests to, optionally with a weight (host:port@weight); \
+                use https://host[:port] for upstreams that speak TLS"
+    )]
     upstream: Vec<String>,
+    #[clap(
+        long,
+        help = "Define a named pool of upstreams for --route rules to send requests to \
+                (name=host:port[@weight],...); the --upstream servers form the pool named default"
+    )]
+    pool: Vec<String>,
+    #[clap(
+        long,
+        help = "Send the requests matching a rule to a pool, trying rules in the order given \
+                (prefix:<path>=<pool>, exact:<path>=<pool>, regex:<pattern>=<pool> or \
+                host:<hostname>=<pool>)"
+    )]
+    route: Vec<String>,
+    #[clap(
+        long,
+        help = "Pool for requests that match no --route rule",
+        default_value = "default"
+    )]
+    fallback_pool: String,
+    #[clap(
+        long,
+        help = "Load balancing strategy (random, round-robin, least-connections, weighted or p2c)",
+        default_value = "random"
+    )]
+    strategy: Strategy,
+    #[clap(
+        long,
+        help = "Load balancing strategy for a particular pool (pool=strategy; default: --strategy)"
+    )]
+    pool_strategy: Vec<String>,
     #[clap(
         long,
         help = "Perform active health checks on this interval (in seconds)",
@@ -40,31 +137,543 @@ struct CmdOptions {
         default_value = "/"
     )]
     active_health_check_path: String,
+    #[clap(
+        long,
+        help = "Path to send active health checks to for a particular pool (pool=path; default: \
+                --active-health-check-path)"
+    )]
+    pool_health_check_path: Vec<String>,
+    #[clap(
+        long,
+        help = "Stop sending requests to an upstream after this many failures (5xx responses or \
+                no response) in a row (0 = only when it can't be connected to)",
+        default_value = "5"
+    )]
+    circuit_breaker_failures: u32,
+    #[clap(
+        long,
+        help = "Keep requests away from an upstream for this long (in seconds) after its circuit \
+                breaker opens, before sending it trial requests",
+        default_value = "10"
+    )]
+    circuit_breaker_open_duration: u64,
+    #[clap(
+        long,
+        help = "Number of trial requests that have to succeed before an upstream's circuit breaker \
+                closes again",
+        default_value = "3"
+    )]
+    circuit_breaker_trial_requests: u32,
+    #[clap(
+        long,
+        help = "Add, set or remove a header on requests sent to upstreams (add:<name>: <value>, \
+                set:<name>: <value> or remove:<name>), applying rules in the order given"
+    )]
+    request_header: Vec<String>,
+    #[clap(
+        long,
+        help = "Add, set or remove a header on responses sent to clients (add:<name>: <value>, \
+                set:<name>: <value> or remove:<name>), applying rules in the order given"
+    )]
+    response_header: Vec<String>,
+    #[clap(
+        long,
+        help = "Keep the X-Forwarded-* and Forwarded headers sent by clients at this IP or in this \
+                CIDR block, instead of replacing them"
+    )]
+    trusted_proxy: Vec<String>,
     #[clap(
         long,
         help = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        help = "Number of requests an IP may send back to back before being held to \
+                --max-requests-per-minute (0 = same as --max-requests-per-minute)",
+        default_value = "0"
+    )]
+    rate_limit_burst: usize,
+    #[clap(
+        long,
+        help = "Share rate limits with other instances by gossiping over UDP on this IP/port"
+    )]
+    rate_limit_gossip_bind: Option<String>,
+    #[clap(
+        long,
+        help = "Gossip address of another instance to share rate limits with (requires \
+                --rate-limit-gossip-bind)"
+    )]
+    rate_limit_peer: Vec<String>,
+    #[clap(
+        long,
+        help = "Pick an upstream for every request, rather than once per client connection"
+    )]
+    per_request_balancing: bool,
+    #[clap(
+        long,
+        help = "Send requests with the same key to the same upstream (client-ip, header:<name> or \
+                cookie:<name>)"
+    )]
+    hash_key: Option<HashKey>,
+    #[clap(
+        long,
+        help = "Number of points each upstream gets on the consistent-hash ring",
+        default_value = "160"
+    )]
+    hash_virtual_nodes: usize,
+    #[clap(
+        long,
+        help = "Retry a request that fails against an upstream on another upstream up to this \
+                many times (if its method is idempotent, or none of it reached the upstream)",
+        default_value = "2"
+    )]
+    max_retries: u32,
+    #[clap(
+        long,
+        help = "Wait about this long before retrying a request (in milliseconds), doubling with \
+                every attempt",
+        default_value = "25"
+    )]
+    retry_backoff: u64,
+    #[clap(
+        long,
+        help = "Only allow retries to add up to this percentage of the requests being forwarded, \
+                so that failing upstreams aren't swamped with retries",
+        default_value = "20"
+    )]
+    retry_budget: usize,
+    #[clap(
+        long,
+        help = "Maximum number of idle connections to keep open to each upstream (0 = no pooling)",
+        default_value = "8"
+    )]
+    upstream_pool_size: usize,
+    #[clap(
+        long,
+        help = "Close pooled upstream connections after they have been idle this long (in seconds)",
+        default_value = "30"
+    )]
+    upstream_idle_timeout: u64,
+    #[clap(
+        long,
+        help = "Stream request and response bodies as they arrive instead of buffering them (lifts \
+                the body size limits)"
+    )]
+    stream_bodies: bool,
+    #[clap(
+        long,
+        help = "Close upgraded connections (e.g. WebSockets) once nothing has passed through them \
+                for this long (in seconds, 0 = never)",
+        default_value = "300"
+    )]
+    upgrade_idle_timeout: u64,
+    #[clap(
+        long,
+        help = "Give up connecting to an upstream (including the TLS handshake) after this long \
+                (in seconds, 0 = never)",
+        default_value = "5"
+    )]
+    connect_timeout: u64,
+    #[clap(
+        long,
+        help = "Answer 408 Request Timeout if a client takes longer than this to send the headers \
+                of a request, once it has started (in seconds, 0 = never)",
+        default_value = "10"
+    )]
+    client_header_timeout: u64,
+    #[clap(
+        long,
+        help = "Answer 408 Request Timeout if a client takes longer than this to send the body of \
+                a request (in seconds, 0 = never)",
+        default_value = "30"
+    )]
+    client_body_timeout: u64,
+    #[clap(
+        long,
+        help = "Close client connections that have been idle between requests for this long (in \
+                seconds, 0 = never)",
+        default_value = "60"
+    )]
+    keep_alive_timeout: u64,
+    #[clap(
+        long,
+        help = "Answer 504 Gateway Timeout if an upstream hasn't started responding this long \
+                after a request was sent to it (in seconds, 0 = never)",
+        default_value = "30"
+    )]
+    upstream_first_byte_timeout: u64,
+    #[clap(
+        long,
+        help = "Answer 504 Gateway Timeout if an upstream hasn't finished responding this long \
+                after a request was sent to it (in seconds, 0 = never)",
+        default_value = "120"
+    )]
+    upstream_response_timeout: u64,
+    #[clap(
+        long,
+        help = "On SIGTERM, wait this long for open connections to finish before exiting (in \
+                seconds)",
+        default_value = "30"
+    )]
+    shutdown_grace_period: u64,
+    #[clap(
+        long,
+        help = "IP/port to serve the admin API on (disabled if not given; it has no \
+                authentication, so keep it away from untrusted clients)"
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        help = "File to write an access log to, with a line for every request (disabled if not \
+                given). If writing falls behind, lines are dropped rather than holding up \
+                requests, and counted in the balancebeam_access_log_dropped_total metric"
+    )]
+    access_log: Option<String>,
+    #[clap(
+        long,
+        help = "Layout of access log lines: combined (the Combined Log Format, plus a few extra \
+                fields) or json",
+        default_value = "combined"
+    )]
+    access_log_format: access_log::Format,
+    #[clap(
+        long,
+        help = "Rotate the access log once it reaches this size (in bytes, 0 = never)",
+        default_value = "104857600"
+    )]
+    access_log_max_size: u64,
+    #[clap(
+        long,
+        help = "Number of rotated access log files to keep",
+        default_value = "5"
+    )]
+    access_log_max_files: usize,
+    #[clap(
+        long,
+        help = "Read settings from this TOML or YAML file, and reload it on SIGHUP or when it \
+                changes"
+    )]
+    config: Option<String>,
+    #[clap(
+        long,
+        help = "Check the config file and TLS certificates for changes on this interval (in \
+                seconds, 0 = only reload on SIGHUP)",
+        default_value = "2"
+    )]
+    config_watch_interval: u64,
+    #[clap(
+        long,
+        help = "Terminate TLS using this PEM certificate chain (requires --tls-key)"
+    )]
+    tls_cert: Option<String>,
+    #[clap(long, help = "PEM private key for --tls-cert")]
+    tls_key: Option<String>,
+    #[clap(
+        long,
+        help = "Certificate to serve to clients asking for a particular hostname through SNI \
+                (hostname=cert.pem,key.pem; the hostname may start with *.)"
+    )]
+    tls_sni_cert: Vec<String>,
+    #[clap(
+        long,
+        help = "Verify https:// upstreams against the CA certificates in this PEM file (default: \
+                Mozilla's root CAs)"
+    )]
+    upstream_ca_bundle: Option<String>,
+    #[clap(
+        long,
+        help = "Present this PEM certificate chain to https:// upstreams that ask for a client \
+                certificate (requires --upstream-tls-key)"
+    )]
+    upstream_tls_cert: Option<String>,
+    #[clap(long, help = "PEM private key for --upstream-tls-cert")]
+    upstream_tls_key: Option<String>,
+    #[clap(
+        long,
+        help = "How to check the certificates of https:// upstreams (full, ca-only to skip the \
+                hostname check, or none to accept any certificate)",
+        default_value = "full"
+    )]
+    upstream_tls_verify: Verification,
+}
+
+impl CmdOptions {
+    /// Returns these options with the settings from a config file applied on top
+    fn with_config(&self, config: config::Config) -> CmdOptions {
+        let mut options = self.clone();
+        if let Some(bind) = config.bind {
+            options.bind = bind;
+        }
+        if let Some(upstream) = config.upstream {
+            options.upstream = upstream;
+        }
+        if let Some(pool) = config.pool {
+            options.pool = pool;
+        }
+        if let Some(route) = config.route {
+            options.route = route;
+        }
+        if let Some(fallback_pool) = config.fallback_pool {
+            options.fallback_pool = fallback_pool;
+        }
+        if let Some(strategies) = config.pool_strategy {
+            options.pool_strategy = strategies;
+        }
+        if let Some(interval) = config.active_health_check_interval {
+            options.active_health_check_interval = interval;
+        }
+        if let Some(path) = config.active_health_check_path {
+            options.active_health_check_path = path;
+        }
+        if let Some(paths) = config.pool_health_check_path {
+            options.pool_health_check_path = paths;
+        }
+        if let Some(rules) = config.request_header {
+            options.request_header = rules;
+        }
+        if let Some(rules) = config.response_header {
+            options.response_header = rules;
+        }
+        if let Some(trusted_proxies) = config.trusted_proxy {
+            options.trusted_proxy = trusted_proxies;
+        }
+        if let Some(max_requests_per_minute) = config.max_requests_per_minute {
+            options.max_requests_per_minute = max_requests_per_minute;
+        }
+        if let Some(burst) = config.rate_limit_burst {
+            options.rate_limit_burst = burst;
+        }
+        if let Some(cert) = config.tls_cert {
+            options.tls_cert = Some(cert);
+        }
+        if let Some(key) = config.tls_key {
+            options.tls_key = Some(key);
+        }
+        if let Some(sni_certs) = config.tls_sni_cert {
+            options.tls_sni_cert = sni_certs;
+        }
+        options
+    }
+}
+
+/// The upstreams and pools described by the options
+struct UpstreamSettings {
+    addresses: Vec<String>,
+    weights: Vec<usize>,
+    /// Name of the pool each upstream belongs to
+    pools: Vec<String>,
+    router: Router,
+}
+
+/// Checks the settings that can be changed by reloading the config file, returning the upstreams
+/// and the pools they are grouped into
+fn check_options(options: &CmdOptions) -> Result<UpstreamSettings, String> {
+    if options.upstream.is_empty() && options.pool.is_empty() {
+        return Err(
+            "At least one upstream server must be specified using the --upstream or --pool option."
+                .to_string(),
+        );
+    }
+    if options.active_health_check_interval == 0 {
+        return Err("The active health check interval must be at least 1 second.".to_string());
+    }
+    if options.circuit_breaker_open_duration == 0 {
+        return Err("The circuit breaker open duration must be at least 1 second.".to_string());
+    }
+    if options.hash_virtual_nodes == 0 {
+        return Err("The number of hash virtual nodes must be at least 1.".to_string());
+    }
+    if options.mode == Mode::Http && options.proxy_protocol != ProxyProtocol::None {
+        return Err("--proxy-protocol only works with --mode tcp.".to_string());
+    }
+    if options.mode == Mode::Tcp && !matches!(options.hash_key, None | Some(HashKey::ClientIp)) {
+        return Err("Only --hash-key client-ip works with --mode tcp.".to_string());
+    }
+    if options.mode == Mode::Tcp && !options.route.is_empty() {
+        return Err("--route only works with --mode http.".to_string());
+    }
+
+    let mut pool_upstreams = Vec::new();
+    if !options.upstream.is_empty() {
+        pool_upstreams.push((routing::DEFAULT_POOL.to_string(), options.upstream.clone()));
+    }
+    for pool in &options.pool {
+        pool_upstreams.push(routing::parse_pool(pool)?);
+    }
+    let mut strategies = HashMap::new();
+    for setting in &options.pool_strategy {
+        let (pool, strategy) = routing::parse_pool_setting(setting, "--pool-strategy")?;
+        strategies.insert(pool, strategy.parse::<Strategy>()?);
+    }
+    let mut health_check_paths = HashMap::new();
+    for setting in &options.pool_health_check_path {
+        let (pool, path) = routing::parse_pool_setting(setting, "--pool-health-check-path")?;
+        health_check_paths.insert(pool, path);
+    }
+    if let Some(pool) = strategies
+        .keys()
+        .chain(health_check_paths.keys())
+        .find(|&&pool| pool_upstreams.iter().all(|(name, _)| name != pool))
+    {
+        return Err(format!(
+            "Settings were given for pool {:?}, which does not exist.",
+            pool
+        ));
+    }
+
+    let mut addresses = Vec::new();
+    let mut weights = Vec::new();
+    let mut upstream_pools = Vec::new();
+    let mut pools: Vec<Pool> = Vec::new();
+    for (name, upstreams) in pool_upstreams {
+        if pools.iter().any(|pool| pool.name == name) {
+            return Err(format!("Pool {:?} is defined more than once.", name));
+        }
+        for upstream in &upstreams {
+            let (address, weight) = balancer::parse_upstream(upstream)?;
+            // Health is tracked per upstream, but pools may check it differently
+            if let Some(index) = addresses.iter().position(|other| *other == address) {
+                if upstream_pools[index] != name {
+                    return Err(format!(
+                        "Upstream {} can't be in both pool {:?} and pool {:?}.",
+                        address, upstream_pools[index], name
+                    ));
+                }
+            }
+            addresses.push(address);
+            weights.push(weight);
+            upstream_pools.push(name.clone());
+        }
+        pools.push(Pool {
+            balancer: strategies
+                .get(name.as_str())
+                .copied()
+                .unwrap_or(options.strategy)
+                .build(),
+            health_check_path: health_check_paths
+                .get(name.as_str())
+                .map(|path| path.to_string()),
+            name,
+        });
+    }
+    let routes = options
+        .route
+        .iter()
+        .map(|route| route.parse::<Route>())
+        .collect::<Result<Vec<Route>, String>>()?;
+    Ok(UpstreamSettings {
+        addresses,
+        weights,
+        pools: upstream_pools,
+        router: Router::new(routes, pools, options.fallback_pool.clone())?,
+    })
 }
 
-/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
-/// to, what servers have failed, rate limiting counts, etc.)
+/// Contains information about the state of balancebeam (e.g. settings, rate limiting counts, etc.).
+/// Everything known about the upstreams themselves lives in `upstreams`.
 ///
 /// You should add fields to this struct in later milestones.
 struct ProxyState {
+    /// Whether we proxy HTTP requests or raw TCP connections
+    mode: Mode,
+    /// The PROXY protocol header sent to upstreams in TCP mode
+    proxy_protocol: ProxyProtocol,
+    /// How long a TCP mode connection may go without traffic before we close it (None = forever)
+    tcp_idle_timeout: Option<Duration>,
     /// How frequently we check whether upstream servers are alive (Milestone 4)
-    active_health_check_interval: usize,
+    active_health_check_interval: AtomicUsize,
     /// Where we should send requests when doing active health checks (Milestone 4)
-    active_health_check_path: String,
+    active_health_check_path: RwLock<String>,
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
-    /// Whether the upstream address is dead
-    upstream_dead: RwLock<Vec<bool>>,
-    /// The rate limiter tracks counters for each IP
-    requests_counters: RwLock<HashMap<String, usize>>,
+    max_requests_per_minute: AtomicUsize,
+    /// Number of requests an IP can make back to back before being held to the per-minute rate
+    rate_limit_burst: AtomicUsize,
+    /// Whether each request is balanced separately, rather than pinning a client connection to one
+    /// upstream
+    per_request_balancing: bool,
+    /// Servers that we are proxying to, along with each one's pool, weight, health, circuit
+    /// breaker and draining flag, all indexed by upstream index. Changed through the admin API, and
+    /// replaced when the config file is reloaded.
+    upstreams: RwLock<Upstreams>,
+    /// Decides which pool each request goes to, and holds each pool's balancer. Replaced when the
+    /// config file is reloaded. (When both are needed, lock this before `upstreams`.)
+    router: RwLock<Router>,
+    /// If set, requests are routed within their pool by hashing this key instead of using the
+    /// balancer
+    hash_key: Option<HashKey>,
+    /// The rate limiter tracks a token bucket for each IP, possibly shared with other instances
+    rate_limiter: Box<dyn rate_limit::Store>,
+    /// Maximum number of times a failed request is retried on another upstream
+    max_retries: u32,
+    /// Backoff before the first retry of a request, doubled for each retry after that
+    retry_backoff: Duration,
+    /// Keeps retries to a fraction of the requests we forward
+    retry_budget: RetryBudget,
+    /// Idle keep-alive connections to upstream servers that can be handed to new clients
+    connection_pool: ConnectionPool,
+    /// Whether bodies are copied between client and upstream as they arrive, rather than read in
+    /// full before being forwarded
+    stream_bodies: bool,
+    /// How long an upgraded connection may go without traffic before we close it (None = forever)
+    upgrade_idle_timeout: Option<Duration>,
+    /// How long connecting to an upstream may take (None = forever)
+    connect_timeout: Option<Duration>,
+    /// How long clients may take over each part of a request
+    client_timeouts: request::Timeouts,
+    /// How long an upstream may take to start responding to a request (None = forever)
+    upstream_first_byte_timeout: Option<Duration>,
+    /// How long an upstream may take to finish responding to a request (None = forever)
+    upstream_response_timeout: Option<Duration>,
+    /// Lets connections know when we are shutting down, and tracks how many are still open
+    shutdown: Shutdown,
+    /// Rewrites the headers of requests and responses as they pass through
+    header_rewriter: RwLock<headers::Rewriter>,
+    /// Counters served in Prometheus format on the admin API's /metrics endpoint
+    metrics: Metrics,
+    /// Gets a line for every request, if --access-log is given
+    access_log: Option<AccessLog>,
+    /// Terminates TLS for client connections, if it is turned on
+    tls: Option<Terminator>,
+    /// Opens TLS connections to https:// upstreams
+    upstream_tls: tls::Connector,
+}
+
+impl ProxyState {
+    /// Whether an upstream is chosen for every request, rather than once per client connection.
+    /// Requests are hashed individually since each one may carry a different key.
+    fn balances_each_request(&self) -> bool {
+        self.per_request_balancing || self.hash_key.is_some()
+    }
+
+    /// The protocol clients use to talk to us, for X-Forwarded-Proto and Forwarded
+    fn client_proto(&self) -> &'static str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// The deadlines for the response to a request that has just been sent to an upstream
+    fn response_deadlines(&self) -> response::Deadlines {
+        let now = time::Instant::now();
+        response::Deadlines {
+            first_byte: self
+                .upstream_first_byte_timeout
+                .map(|timeout| now + timeout),
+            complete: self.upstream_response_timeout.map(|timeout| now + timeout),
+        }
+    }
+
+    /// The current rate limit, or None if rate limiting is off
+    fn rate_limit(&self) -> Option<Limit> {
+        Limit::new(
+            self.max_requests_per_minute.load(Ordering::SeqCst),
+            self.rate_limit_burst.load(Ordering::SeqCst),
+        )
+    }
 }
 
 #[tokio::main]
@@ -77,66 +686,317 @@ async fn main() {
     }
     pretty_env_logger::init();
 
-    // Parse the command line arguments passed to this program
-    let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
-    }
+    // Parse the command line arguments passed to this program, along with the config file (if
+    // there is one)
+    let cli_options = CmdOptions::parse();
+    let options = match &cli_options.config {
+        Some(path) => match config::load(path).await {
+            Ok(config) => cli_options.with_config(config),
+            Err(error) => {
+                log::error!("Could not load config file {}: {}", path, error);
+                std::process::exit(1);
+            }
+        },
+        None => cli_options.clone(),
+    };
+    let upstream_settings = match check_options(&options) {
+        Ok(upstream_settings) => upstream_settings,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let tls = match tls_settings(&options) {
+        Ok(Some(settings)) => match Terminator::new(settings, options.mode) {
+            Ok(terminator) => Some(terminator),
+            Err(err) => {
+                log::error!("Could not load TLS certificates: {}", err);
+                std::process::exit(1);
+            }
+        },
+        Ok(None) => None,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
-    // Start listening for connections
-    let mut listener = match TcpListener::bind(&options.bind).await {
+    let header_rewriter = match header_rewriter(&options) {
+        Ok(header_rewriter) => header_rewriter,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let access_log = match &options.access_log {
+        Some(path) => match AccessLog::open(
+            path,
+            options.access_log_format,
+            options.access_log_max_size,
+            options.access_log_max_files,
+        ) {
+            Ok(access_log) => Some(access_log),
+            Err(err) => {
+                log::error!("Could not open access log {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let upstream_tls = match upstream_tls_connector(&options) {
+        Ok(connector) => connector,
+        Err(err) => {
+            log::error!("Could not set up TLS for upstreams: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // Start listening for connections, on sockets handed over by systemd or by an older
+    // balancebeam process if there are any
+    let mut inherited = match handoff::Inherited::from_env() {
+        Ok(inherited) => inherited,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut listener = match inherited.tcp_listener(handoff::PROXY, &options.bind).await {
         Ok(listener) => listener,
         Err(err) => {
             log::error!("Could not bind to {}: {}", options.bind, err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+    log::info!(
+        "Listening for {} on {}",
+        match (options.mode, tls.is_some()) {
+            (Mode::Http, false) => "HTTP requests",
+            (Mode::Http, true) => "HTTPS requests",
+            (Mode::Tcp, false) => "TCP connections",
+            (Mode::Tcp, true) => "TLS connections",
+        },
+        listener
+            .local_addr()
+            .map_or_else(|_| options.bind.clone(), |address| address.to_string())
+    );
+    // The sockets we pass on if we are asked to hand over to a new process
+    let mut handoff_sockets = vec![(handoff::PROXY, listener.as_raw_fd())];
+    let admin_listener = match &options.admin_bind {
+        Some(admin_bind) => match inherited.tcp_listener(handoff::ADMIN, admin_bind).await {
+            Ok(listener) => {
+                log::info!("Serving the admin API on {}", admin_bind);
+                handoff_sockets.push((handoff::ADMIN, listener.as_raw_fd()));
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind admin API to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let rate_limiter: Box<dyn rate_limit::Store> = match &options.rate_limit_gossip_bind {
+        Some(gossip_bind) => {
+            let started = match inherited.udp_socket(handoff::GOSSIP, gossip_bind).await {
+                Ok(socket) => {
+                    handoff_sockets.push((handoff::GOSSIP, socket.as_raw_fd()));
+                    GossipStore::start(socket, &options.rate_limit_peer).await
+                }
+                Err(err) => Err(err),
+            };
+            match started {
+                Ok(store) => {
+                    log::info!(
+                        "Sharing rate limits on {} with {:?}",
+                        gossip_bind,
+                        options.rate_limit_peer
+                    );
+                    Box::new(store)
+                }
+                Err(err) => {
+                    log::error!(
+                        "Could not start rate limit gossip on {}: {}",
+                        gossip_bind,
+                        err
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        None if !options.rate_limit_peer.is_empty() => {
+            log::error!("--rate-limit-peer requires --rate-limit-gossip-bind");
+            std::process::exit(1);
+        }
+        None => Box::new(LocalStore::new()),
+    };
 
-    let upstream_dead = vec![false; options.upstream.len()];
     let state = Arc::new(ProxyState {
-        upstream_addresses: options.upstream,
-        upstream_dead: RwLock::new(upstream_dead),
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        requests_counters: RwLock::new(HashMap::new()),
+        mode: options.mode,
+        proxy_protocol: options.proxy_protocol,
+        tcp_idle_timeout: Some(options.tcp_idle_timeout)
+            .filter(|&seconds| seconds > 0)
+            .map(Duration::from_secs),
+        upstreams: RwLock::new(Upstreams::new(
+            upstream_settings.addresses,
+            upstream_settings.weights,
+            upstream_settings.pools,
+            options.hash_virtual_nodes,
+            circuit::Settings {
+                failure_threshold: options.circuit_breaker_failures,
+                open_duration: Duration::from_secs(options.circuit_breaker_open_duration),
+                trial_requests: options.circuit_breaker_trial_requests.max(1),
+            },
+        )),
+        router: RwLock::new(upstream_settings.router),
+        hash_key: options.hash_key.clone(),
+        active_health_check_interval: AtomicUsize::new(options.active_health_check_interval),
+        active_health_check_path: RwLock::new(options.active_health_check_path.clone()),
+        max_requests_per_minute: AtomicUsize::new(options.max_requests_per_minute),
+        rate_limit_burst: AtomicUsize::new(options.rate_limit_burst),
+        per_request_balancing: options.per_request_balancing,
+        rate_limiter,
+        max_retries: options.max_retries,
+        retry_backoff: Duration::from_millis(options.retry_backoff),
+        retry_budget: RetryBudget::new(options.retry_budget),
+        connection_pool: ConnectionPool::new(
+            options.upstream_pool_size,
+            Duration::from_secs(options.upstream_idle_timeout),
+        ),
+        stream_bodies: options.stream_bodies,
+        upgrade_idle_timeout: Some(options.upgrade_idle_timeout)
+            .filter(|&seconds| seconds > 0)
+            .map(Duration::from_secs),
+        connect_timeout: optional_seconds(options.connect_timeout),
+        client_timeouts: request::Timeouts {
+            idle: optional_seconds(options.keep_alive_timeout),
+            header: optional_seconds(options.client_header_timeout),
+            body: optional_seconds(options.client_body_timeout),
+        },
+        upstream_first_byte_timeout: optional_seconds(options.upstream_first_byte_timeout),
+        upstream_response_timeout: optional_seconds(options.upstream_response_timeout),
+        shutdown: Shutdown::new(),
+        header_rewriter: RwLock::new(header_rewriter),
+        metrics: Metrics::new(),
+        access_log,
+        tls,
+        upstream_tls,
     });
 
     tokio::spawn(active_health_check(Arc::clone(&state)));
+    tokio::spawn(reap_idle_connections(Arc::clone(&state)));
+    // Rate limiting may be turned on later by reloading the config file, so idle clients are
+    // evicted even if it is off for now
+    tokio::spawn(evict_idle_clients(Arc::clone(&state)));
+    if let Some(admin_listener) = admin_listener {
+        tokio::spawn(admin::serve(admin_listener, Arc::clone(&state)));
+    }
+    if cli_options.config.is_some() || state.tls.is_some() {
+        tokio::spawn(watch_files(Arc::clone(&state), cli_options, options.bind));
+    }
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            log::error!("Could not listen for SIGTERM: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut upgrade = match signal(SignalKind::user_defined2()) {
+        Ok(upgrade) => upgrade,
+        Err(err) => {
+            log::error!("Could not listen for SIGUSR2: {}", err);
+            std::process::exit(1);
+        }
+    };
+    // We're ready, so an older process we are taking over from can start shutting down
+    inherited.finish();
 
-    if state.max_requests_per_minute != 0 {
-        tokio::spawn(reset_counters(Arc::clone(&state)));
+    // Handle incoming connections until we are told to shut down
+    let mut incoming = listener.incoming();
+    loop {
+        let stream = tokio::select! {
+            stream = incoming.next() => stream,
+            _ = terminate.recv() => break,
+            _ = upgrade.recv() => {
+                // The new process tells us to shut down (with SIGTERM) once it is ready
+                match handoff::spawn_successor(&handoff_sockets) {
+                    Ok(pid) => log::info!("Started process {} to take over from us", pid),
+                    Err(err) => log::error!("Could not start a process to take over: {}", err),
+                }
+                continue;
+            }
+        };
+        match stream {
+            Some(Ok(stream)) => {
+                // The connection counts as open from here, so that shutting down waits for it even
+                // if its task hasn't started yet
+                let open_connection = state.shutdown.track();
+                let state = Arc::clone(&state);
+                // Handle the connection!
+                tokio::spawn(async move {
+                    handle_connection(stream, state).await;
+                    drop(open_connection);
+                });
+            }
+            Some(Err(_)) => {}
+            None => break,
+        }
+    }
+
+    // Stop accepting connections, and give the ones we have a chance to finish
+    drop(incoming);
+    drop(listener);
+    state.shutdown.begin();
+    let grace_period = Duration::from_secs(options.shutdown_grace_period);
+    log::info!(
+        "Shutting down; waiting up to {:?} for {} open connections",
+        grace_period,
+        state.shutdown.open_connections()
+    );
+    match state.shutdown.drain(grace_period).await {
+        0 => log::info!("All connections finished. Exiting"),
+        remaining => log::warn!(
+            "Grace period is over; exiting with {} connections still open",
+            remaining
+        ),
     }
-
-    // Handle incoming connections
-    let mut incoming = listener.incoming();
-    while let Some(stream) = incoming.next().await {
-        if let Ok(stream) = stream {
-            let state = Arc::clone(&state);
-            // Handle the connection!
-            tokio::spawn(handle_connection(stream, state));
-        }
+    // Write out whatever is left of the access log before we exit
+    if let Some(access_log) = &state.access_log {
+        access_log.close();
     }
 }
 
 async fn active_health_check_upstream(
     state: Arc<ProxyState>,
-    upstream_address: &String,
+    upstream_address: &str,
+    path: &str,
 ) -> Option<()> {
-    let mut stream = TcpStream::connect(upstream_address).await.ok()?;
+    let mut stream = open_upstream_connection(&state, upstream_address)
+        .await
+        .ok()?;
+    // A TCP service may not speak anything we understand, so being able to connect is enough
+    if state.mode == Mode::Tcp {
+        return Some(());
+    }
     let request = http::Request::builder()
         .method(http::Method::GET)
-        .uri(&state.active_health_check_path)
-        .header("Host", upstream_address)
+        .uri(path)
+        .header(
+            "Host",
+            UpstreamTarget::parse(upstream_address).ok()?.authority,
+        )
         .body(Vec::new())
         .unwrap();
     request::write_to_stream(&request, &mut stream).await.ok()?;
 
-    let response = response::read_from_stream(&mut stream, request.method())
-        .await
-        .ok()?;
+    let response =
+        response::read_from_stream(&mut stream, request.method(), state.response_deadlines())
+            .await
+            .ok()?;
     if response.status() == StatusCode::OK {
         Some(())
     } else {
@@ -144,109 +1004,1068 @@ async fn active_health_check_upstream(
     }
 }
 
-async fn reset_counters(state: Arc<ProxyState>) {
+async fn evict_idle_clients(state: Arc<ProxyState>) {
     let mut interval = time::interval(Duration::from_secs(60));
     interval.tick().await;
     loop {
         interval.tick().await;
-        state.requests_counters.write().await.clear();
+        state.rate_limiter.evict_idle(state.rate_limit());
     }
 }
 
-async fn active_health_check(state: Arc<ProxyState>) {
-    let mut interval = time::interval(Duration::from_secs(
-        state.active_health_check_interval as u64,
-    ));
+async fn reap_idle_connections(state: Arc<ProxyState>) {
+    let mut interval = time::interval(state.connection_pool.idle_timeout());
     interval.tick().await;
     loop {
         interval.tick().await;
-        // health check here
-        for (index, upstream_address) in state.upstream_addresses.iter().enumerate() {
-            state.upstream_dead.write().await[index] =
-                active_health_check_upstream(Arc::clone(&state), upstream_address)
-                    .await
-                    .is_none();
+        state.connection_pool.reap_expired().await;
+    }
+}
+
+async fn active_health_check(state: Arc<ProxyState>) {
+    loop {
+        // Look up the interval every time, since reloading the config file may change it
+        let interval = state.active_health_check_interval.load(Ordering::SeqCst);
+        delay_for(Duration::from_secs(interval as u64)).await;
+        // Check the upstreams that were configured when this round started, each on its pool's
+        // health check path. (We don't hold the locks while checking, so the config may be
+        // reloaded in the meantime.)
+        let checks: Vec<(String, String)> = {
+            let default_path = state.active_health_check_path.read().await.clone();
+            let router = state.router.read().await;
+            let upstreams = state.upstreams.read().await;
+            upstreams
+                .addresses
+                .iter()
+                .zip(&upstreams.pools)
+                .map(|(address, pool)| {
+                    let path = router
+                        .pool(pool)
+                        .and_then(|pool| pool.health_check_path.clone())
+                        .unwrap_or_else(|| default_path.clone());
+                    (address.clone(), path)
+                })
+                .collect()
+        };
+        for (upstream_address, path) in &checks {
+            let dead = active_health_check_upstream(Arc::clone(&state), upstream_address, path)
+                .await
+                .is_none();
+            state.metrics.record_health_check(upstream_address, !dead);
+            if dead {
+                state.connection_pool.evict(upstream_address).await;
+            }
+            let mut upstreams = state.upstreams.write().await;
+            upstreams.set_dead(upstream_address, dead);
+            if !dead {
+                // No need to wait out the open breaker of an upstream that passes its health check
+                if let Some(breaker) = upstreams.breaker(upstream_address) {
+                    breaker.probe();
+                }
+            }
+        }
+    }
+}
+
+/// Turns a timeout given in seconds into a Duration, or None if it is 0 (i.e. off)
+fn optional_seconds(seconds: u64) -> Option<Duration> {
+    Some(seconds)
+        .filter(|&seconds| seconds > 0)
+        .map(Duration::from_secs)
+}
+
+/// Returns the TLS settings from the options, or None if TLS is off
+fn tls_settings(options: &CmdOptions) -> Result<Option<tls::Settings>, String> {
+    tls::Settings::from_options(
+        options.tls_cert.as_ref(),
+        options.tls_key.as_ref(),
+        &options.tls_sni_cert,
+    )
+}
+
+/// Builds the header rewriting rules from the options
+fn header_rewriter(options: &CmdOptions) -> Result<headers::Rewriter, String> {
+    fn parse_all<T: std::str::FromStr<Err = String>>(values: &[String]) -> Result<Vec<T>, String> {
+        values.iter().map(|value| value.parse()).collect()
+    }
+    Ok(headers::Rewriter {
+        request_rules: parse_all(&options.request_header)?,
+        response_rules: parse_all(&options.response_header)?,
+        trusted_proxies: parse_all(&options.trusted_proxy)?,
+    })
+}
+
+/// Sets up TLS for https:// upstreams from the options
+fn upstream_tls_connector(options: &CmdOptions) -> Result<tls::Connector, String> {
+    let client_cert = match (&options.upstream_tls_cert, &options.upstream_tls_key) {
+        (Some(cert), Some(key)) => Some(tls::CertFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+        }),
+        (None, None) => None,
+        _ => {
+            return Err(
+                "--upstream-tls-cert and --upstream-tls-key must be given together.".to_string(),
+            )
         }
+    };
+    tls::Connector::new(
+        options.upstream_ca_bundle.as_deref(),
+        client_cert.as_ref(),
+        options.upstream_tls_verify,
+    )
+    .map_err(|err| err.to_string())
+}
+
+/// Returns when each file was last modified (None if that can't be determined)
+async fn modified_times(paths: &[String]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::new();
+    for path in paths {
+        times.push(
+            tokio::fs::metadata(path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok(),
+        );
+    }
+    times
+}
+
+/// Returns the files whose changes we watch for: the config file and the TLS certificates
+fn watched_files(state: &ProxyState, cli_options: &CmdOptions) -> Vec<String> {
+    let mut paths: Vec<String> = cli_options.config.iter().cloned().collect();
+    if let Some(terminator) = &state.tls {
+        paths.extend(terminator.settings().files());
     }
+    paths
 }
 
-async fn connect_to_upstream(state: Arc<ProxyState>) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
+/// Reloads the config file (if there is one) and the TLS certificates whenever we receive SIGHUP
+/// or notice that one of them has changed
+async fn watch_files(state: Arc<ProxyState>, cli_options: CmdOptions, bind: String) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(error) => {
+            log::error!("Could not listen for SIGHUP: {}", error);
+            return;
+        }
+    };
+    let watch_interval = cli_options.config_watch_interval;
+    let mut last_modified = modified_times(&watched_files(&state, &cli_options)).await;
     loop {
-        let alive_idxes: Vec<usize> = state
-            .upstream_dead
-            .read()
-            .await
+        tokio::select! {
+            _ = hangup.recv() => log::info!("Received SIGHUP, reloading"),
+            _ = delay_for(Duration::from_secs(watch_interval)), if watch_interval != 0 => {
+                if modified_times(&watched_files(&state, &cli_options)).await == last_modified {
+                    continue;
+                }
+                log::info!("Config file or TLS certificates changed, reloading");
+            }
+        }
+        match &cli_options.config {
+            Some(path) => reload_config(&state, &cli_options, path, &bind).await,
+            None => reload_certificates(&state),
+        }
+        // Look at the files again, since the config may now point somewhere else
+        last_modified = modified_times(&watched_files(&state, &cli_options)).await;
+    }
+}
+
+/// Re-reads the TLS certificates from the same files as before
+fn reload_certificates(state: &ProxyState) {
+    if let Some(terminator) = &state.tls {
+        match terminator.reload(terminator.settings()) {
+            Ok(()) => log::info!("Reloaded TLS certificates"),
+            Err(err) => log::error!("Not reloading TLS certificates: {}", err),
+        }
+    }
+}
+
+/// Re-reads the config file and switches over to its settings. Connections that are already open
+/// are left alone, even if their upstream has been removed. If the new config is invalid, we keep
+/// running with the old one.
+async fn reload_config(state: &ProxyState, cli_options: &CmdOptions, path: &str, bind: &str) {
+    let options = match config::load(path).await {
+        Ok(config) => cli_options.with_config(config),
+        Err(error) => {
+            log::error!("Not reloading invalid config file {}: {}", path, error);
+            return;
+        }
+    };
+    let upstream_settings = match check_options(&options) {
+        Ok(upstream_settings) => upstream_settings,
+        Err(err) => {
+            log::error!("Not reloading invalid config file {}: {}", path, err);
+            return;
+        }
+    };
+    let tls_settings = match tls_settings(&options) {
+        Ok(tls_settings) => tls_settings,
+        Err(err) => {
+            log::error!("Not reloading invalid config file {}: {}", path, err);
+            return;
+        }
+    };
+    let header_rewriter = match header_rewriter(&options) {
+        Ok(header_rewriter) => header_rewriter,
+        Err(err) => {
+            log::error!("Not reloading invalid config file {}: {}", path, err);
+            return;
+        }
+    };
+    if options.bind != bind {
+        log::warn!(
+            "Changing the bind address requires a restart; still listening on {}",
+            bind
+        );
+    }
+    match (&state.tls, tls_settings) {
+        (Some(terminator), Some(tls_settings)) => {
+            if let Err(err) = terminator.reload(tls_settings) {
+                log::error!("Not reloading config file {}: {}", path, err);
+                return;
+            }
+        }
+        (None, None) => {}
+        _ => log::warn!("Turning TLS on or off requires a restart"),
+    }
+
+    let removed_addresses: Vec<String> = {
+        let mut router = state.router.write().await;
+        let mut upstreams = state.upstreams.write().await;
+        let removed_addresses = upstreams
+            .addresses
             .iter()
-            .enumerate()
-            .filter_map(|(index, &dead)| if dead { None } else { Some(index) })
+            .filter(|address| !upstream_settings.addresses.contains(address))
+            .cloned()
             .collect();
-        if alive_idxes.is_empty() {
-            return Err(std::io::Error::from(ErrorKind::ConnectionRefused));
+        *upstreams = upstreams.reconfigure(
+            upstream_settings.addresses,
+            upstream_settings.weights,
+            upstream_settings.pools,
+        );
+        *router = upstream_settings.router;
+        removed_addresses
+    };
+    for address in &removed_addresses {
+        state.connection_pool.evict(address).await;
+    }
+    state
+        .active_health_check_interval
+        .store(options.active_health_check_interval, Ordering::SeqCst);
+    *state.active_health_check_path.write().await = options.active_health_check_path;
+    *state.header_rewriter.write().await = header_rewriter;
+    state
+        .max_requests_per_minute
+        .store(options.max_requests_per_minute, Ordering::SeqCst);
+    state
+        .rate_limit_burst
+        .store(options.rate_limit_burst, Ordering::SeqCst);
+    log::info!("Reloaded config file {}", path);
+}
+
+/// Picks an upstream in the given pool that is currently alive, not draining and not cut off by its
+/// circuit breaker, returning its address, outstanding connection count and breaker. Requests
+/// carrying an affinity key are placed with the hash ring, and everything else is left to the
+/// pool's balancer.
+async fn choose_upstream(
+    state: &ProxyState,
+    pool: &str,
+    affinity_key: Option<&str>,
+) -> Option<(String, Arc<AtomicUsize>, Arc<Breaker>)> {
+    let router = state.router.read().await;
+    let upstreams = state.upstreams.read().await;
+    let upstream_idx = match affinity_key {
+        Some(key) => upstreams
+            .hash_ring
+            .lookup(key, |index| upstreams.is_available_in(index, pool))?,
+        None => {
+            let candidates: Vec<Candidate> = (0..upstreams.addresses.len())
+                .filter(|&index| upstreams.is_available_in(index, pool))
+                .map(|index| Candidate {
+                    index,
+                    weight: upstreams.weights[index],
+                    outstanding: upstreams.outstanding[index].load(Ordering::SeqCst),
+                })
+                .collect();
+            if candidates.is_empty() {
+                return None;
+            }
+            // The pool may have been removed by a reload since the request was routed
+            router.pool(pool)?.balancer.choose(&candidates)
+        }
+    };
+    let breaker = Arc::clone(&upstreams.breakers[upstream_idx]);
+    breaker.admit();
+    Some((
+        upstreams.addresses[upstream_idx].clone(),
+        Arc::clone(&upstreams.outstanding[upstream_idx]),
+        breaker,
+    ))
+}
+
+async fn connect_to_upstream(
+    state: Arc<ProxyState>,
+    pool: &str,
+    affinity_key: Option<&str>,
+) -> Result<UpstreamConnection, std::io::Error> {
+    // If we run out of upstreams after one timed out, the client should hear that it was a timeout
+    let mut last_error = ErrorKind::ConnectionRefused;
+    // Each upstream that refuses us has its breaker tripped so that we move on to another one, but
+    // don't go round more than once in case a breaker lets its upstream straight back in
+    let max_attempts = state.upstreams.read().await.addresses.len();
+    let mut attempts = 0;
+    loop {
+        let (upstream_ip, outstanding, breaker) =
+            match choose_upstream(&state, pool, affinity_key).await {
+                Some(upstream) => upstream,
+                None => return Err(std::io::Error::from(last_error)),
+            };
+
+        // Prefer an idle connection from the pool so that we can skip the TCP handshake
+        let (stream, reused) = match state.connection_pool.checkout(&upstream_ip).await {
+            Some(stream) => (stream, true),
+            None => match open_upstream_connection(&state, &upstream_ip).await {
+                Ok(stream) => (stream, false),
+                Err(error) => {
+                    if error.kind() == ErrorKind::TimedOut {
+                        last_error = ErrorKind::TimedOut;
+                    }
+                    breaker.trip();
+                    attempts += 1;
+                    if attempts >= max_attempts {
+                        return Err(std::io::Error::from(last_error));
+                    }
+                    continue;
+                }
+            },
+        };
+        break Ok(UpstreamConnection {
+            address: upstream_ip,
+            pool: pool.to_string(),
+            stream,
+            reused,
+            load: LoadGuard::acquire(&outstanding),
+            breaker,
+        });
+    }
+}
+
+/// Opens a new connection to an upstream, performing the TLS handshake if it has an https://
+/// address. Fails with TimedOut if that takes longer than --connect-timeout.
+async fn open_upstream_connection(
+    state: &ProxyState,
+    upstream_address: &str,
+) -> Result<MaybeTlsStream, std::io::Error> {
+    let target = UpstreamTarget::parse(upstream_address)
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
+    let connect = async {
+        let stream = TcpStream::connect(&target.authority).await?;
+        set_nodelay(&stream);
+        match target.tls_hostname {
+            Some(hostname) => match state.upstream_tls.connect(hostname, stream).await {
+                Ok(stream) => Ok(MaybeTlsStream::Tls(Box::new(stream.into()))),
+                Err(error) => {
+                    log::warn!("TLS handshake with {} failed: {}", upstream_address, error);
+                    Err(error)
+                }
+            },
+            None => Ok(MaybeTlsStream::Plain(stream)),
+        }
+    };
+    let deadline = state
+        .connect_timeout
+        .map(|timeout| time::Instant::now() + timeout);
+    body::with_deadline(deadline, connect)
+        .await
+        .unwrap_or_else(|| {
+            log::warn!("Timed out connecting to {}", upstream_address);
+            Err(std::io::Error::from(ErrorKind::TimedOut))
+        })
+}
+
+/// Builds the 408 Request Timeout response for a client that took too long to send a request. The
+/// rest of the request may still be on its way, so the connection is closed afterwards.
+fn request_timeout_response() -> http::Response<Vec<u8>> {
+    let mut response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+    response
+        .headers_mut()
+        .insert("Connection", http::HeaderValue::from_static("close"));
+    response
+}
+
+/// Builds the response for a request that we couldn't get an upstream's response to: 504 Gateway
+/// Timeout if the upstream took too long, or 502 Bad Gateway if it failed in some other way
+fn upstream_error_response(timed_out: bool) -> http::Response<Vec<u8>> {
+    response::make_http_error(if timed_out {
+        http::StatusCode::GATEWAY_TIMEOUT
+    } else {
+        http::StatusCode::BAD_GATEWAY
+    })
+}
+
+/// Opens the circuit breaker of an upstream that failed outright (passive health check) and closes
+/// its pooled connections
+async fn trip_breaker(state: &ProxyState, upstream_conn: &UpstreamConnection) {
+    upstream_conn.breaker.trip();
+    state.connection_pool.evict(&upstream_conn.address).await;
+}
+
+/// Disables Nagle's algorithm on a connection. Streamed bodies are written in pieces as they
+/// arrive, and we don't want the last piece of a message held back waiting for an ACK.
+fn set_nodelay(stream: &TcpStream) {
+    if let Err(error) = stream.set_nodelay(true) {
+        log::debug!("Failed to set TCP_NODELAY: {}", error);
+    }
+}
+
+/// Why an exchange with an upstream failed
+struct ExchangeError {
+    error: response::Error,
+    /// Whether any of the request may have reached the upstream
+    sent: bool,
+    /// Whether the upstream sent any of a response
+    answered: bool,
+}
+
+/// Sends a request to an upstream server and reads back its response. When streaming bodies, only
+/// the response headers are read, and the body is left in the stream for body::copy.
+async fn exchange_with_upstream(
+    state: &ProxyState,
+    upstream_conn: &mut MaybeTlsStream,
+    request: &http::Request<Vec<u8>>,
+    stream_bodies: bool,
+) -> Result<http::Response<Vec<u8>>, ExchangeError> {
+    // If the first write fails, none of the request got out, which makes it safe to retry whatever
+    // its method
+    let bytes = request::to_bytes(request);
+    let not_sent = |error| ExchangeError {
+        error: response::Error::ConnectionError(error),
+        sent: false,
+        answered: false,
+    };
+    let written = match upstream_conn.write(&bytes).await {
+        Ok(0) => return Err(not_sent(std::io::Error::from(ErrorKind::WriteZero))),
+        Ok(written) => written,
+        Err(error) => return Err(not_sent(error)),
+    };
+    body::write_all(upstream_conn, &bytes[written..])
+        .await
+        .map_err(|error| ExchangeError {
+            error: response::Error::ConnectionError(error),
+            sent: true,
+            answered: false,
+        })?;
+    log::debug!("Forwarded request to server");
+    let deadlines = state.response_deadlines();
+    let mut response_stream = body::Counted::new(upstream_conn);
+    let result = if stream_bodies {
+        response::read_head_from_stream(&mut response_stream, deadlines).await
+    } else {
+        response::read_from_stream(&mut response_stream, request.method(), deadlines).await
+    };
+    let answered = response_stream.count > 0;
+    result.map_err(|error| ExchangeError {
+        error,
+        sent: true,
+        answered,
+    })
+}
+
+/// Forwards a request over the given upstream connection, retrying on another connection if the
+/// exchange fails. Requests with a method that isn't idempotent are only retried if none of the
+/// request reached the upstream, since it might otherwise act on them twice.
+///
+/// * If the connection has been used before (e.g. it came out of the pool), the upstream may have
+///   closed it while it was idle or gone down altogether. We drop the other pooled connections to
+///   that upstream and let connect_to_upstream find out whether the upstream is still reachable.
+///   If the upstream hung up without sending any of a response, it most likely closed the
+///   connection before our request arrived, so the request counts as not sent.
+/// * A failure on a fresh connection means the upstream is broken, so we trip its circuit breaker
+///   and, after a backoff, send the request to another upstream instead. This happens up to
+///   --max-retries times, as long as the retry budget allows.
+///
+/// An upstream that misses a response deadline is left to its circuit breaker and isn't retried:
+/// it may still be working on the request, and the client has already waited long enough.
+/// `request_id` is only used to tag log lines.
+async fn forward_request(
+    state: &Arc<ProxyState>,
+    upstream_conn: &mut UpstreamConnection,
+    request: &http::Request<Vec<u8>>,
+    affinity_key: Option<&str>,
+    stream_bodies: bool,
+    request_id: &str,
+) -> Result<http::Response<Vec<u8>>, response::Error> {
+    state.retry_budget.deposit();
+    let mut retries = 0;
+    loop {
+        let started = Instant::now();
+        let result =
+            exchange_with_upstream(state, &mut upstream_conn.stream, request, stream_bodies).await;
+        record_upstream_request(state, upstream_conn, &result, started);
+        let failure = match result {
+            Ok(response) => {
+                upstream_conn.reused = true;
+                return Ok(response);
+            }
+            Err(failure) => failure,
+        };
+        if failure.error.is_timeout() {
+            return Err(failure.error);
+        }
+        if upstream_conn.reused {
+            state.connection_pool.evict(&upstream_conn.address).await;
+        } else {
+            trip_breaker(state, upstream_conn).await;
+        }
+        let stale = upstream_conn.reused && !failure.answered;
+        if failure.sent && !stale && !retry::is_idempotent(request.method()) {
+            return Err(failure.error);
+        }
+        if upstream_conn.reused {
+            log::debug!(
+                "Connection to {} failed ({:?}); retrying on another connection [{}]",
+                upstream_conn.address,
+                failure.error,
+                request_id
+            );
+        } else {
+            if retries >= state.max_retries {
+                return Err(failure.error);
+            }
+            if !state.retry_budget.withdraw() {
+                log::warn!(
+                    "Not retrying failed request to {}: retry budget used up [{}]",
+                    upstream_conn.address,
+                    request_id
+                );
+                state.metrics.record_retry_budget_exhausted();
+                return Err(failure.error);
+            }
+            log::info!(
+                "Request to {} failed ({:?}); retrying on another upstream [{}]",
+                upstream_conn.address,
+                failure.error,
+                request_id
+            );
+            delay_for(retry::backoff(state.retry_backoff, retries)).await;
+            retries += 1;
+            state.metrics.record_retry();
+        }
+        let pool = upstream_conn.pool.clone();
+        *upstream_conn = connect_to_upstream(Arc::clone(state), &pool, affinity_key)
+            .await
+            .map_err(response::Error::ConnectionError)?;
+    }
+}
+
+/// Updates the per-upstream metrics and the upstream's circuit breaker with the outcome of an
+/// exchange that started at `started`
+fn record_upstream_request<E>(
+    state: &ProxyState,
+    upstream_conn: &UpstreamConnection,
+    result: &Result<http::Response<Vec<u8>>, E>,
+    started: Instant,
+) {
+    let status = result.as_ref().ok().map(|response| response.status());
+    match status {
+        Some(status) if !status.is_server_error() => upstream_conn.breaker.record_success(),
+        _ => upstream_conn.breaker.record_failure(),
+    }
+    state
+        .metrics
+        .record_upstream_request(&upstream_conn.address, status, started.elapsed());
+}
+
+/// Holds on to an upstream connection that can carry another request. When balancing each
+/// request, it goes back to the pool so that any client can use it; otherwise, it stays pinned to
+/// this client.
+async fn keep_upstream_conn(
+    state: &ProxyState,
+    upstream_conn: UpstreamConnection,
+    pinned_conn: &mut Option<UpstreamConnection>,
+) {
+    if state.balances_each_request() {
+        state
+            .connection_pool
+            .checkin(&upstream_conn.address, upstream_conn.stream)
+            .await;
+    } else {
+        *pinned_conn = Some(upstream_conn);
+    }
+}
+
+/// Forwards a request whose body (if any) is still waiting in the client stream, copying the
+/// request and response bodies across as they arrive. Returns false if the client connection
+/// can't carry another request (e.g. because a body was cut off partway) and should be closed.
+async fn stream_request(
+    state: &Arc<ProxyState>,
+    client_conn: &mut MaybeTlsStream,
+    mut upstream_conn: UpstreamConnection,
+    mut request: http::Request<Vec<u8>>,
+    affinity_key: Option<&str>,
+    pinned_conn: &mut Option<UpstreamConnection>,
+    mut entry: access_log::Entry,
+) -> bool {
+    let client_ip = entry.client_ip().to_string();
+    let framing = match request::body_framing(&request) {
+        Ok(framing) => framing,
+        Err(error) => {
+            log::debug!(
+                "Error parsing request: {:?} [{}]",
+                error,
+                entry.request_id()
+            );
+            let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+            send_response(state, client_conn, &client_ip, response, Some(&entry)).await;
+            // We can't tell where the body ends, so we can't find the start of the next request
+            return false;
         }
+    };
 
-        let random_idx = alive_idxes[rng.gen_range(0, alive_idxes.len())];
-        let upstream_ip = &state.upstream_addresses[random_idx];
+    // A request without a body can be retried on another connection, just like a buffered one.
+    // Once we've started passing a body along, though, we can't take it back. The response deadline
+    // also covers streaming the response body.
+    let forwarded = Instant::now();
+    let (response, response_deadline) = if framing == Framing::Empty {
+        let response_deadline = state.response_deadlines().complete;
+        let response = forward_request(
+            state,
+            &mut upstream_conn,
+            &request,
+            affinity_key,
+            true,
+            entry.request_id(),
+        )
+        .await;
+        (response, response_deadline)
+    } else {
+        let started = Instant::now();
+        let already_read = std::mem::take(request.body_mut());
+        let sent = match request::write_head_to_stream(&request, &mut upstream_conn.stream).await {
+            Ok(()) => {
+                let mut upstream_stream = body::Counted::new(&mut upstream_conn.stream);
+                let copied = body::with_deadline(
+                    state.client_timeouts.body_deadline(),
+                    body::copy(
+                        client_conn,
+                        already_read,
+                        &mut upstream_stream,
+                        framing,
+                        true,
+                    ),
+                )
+                .await;
+                entry.bytes_in = upstream_stream.count;
+                copied.unwrap_or_else(|| {
+                    Err(body::Error::Read(std::io::Error::from(ErrorKind::TimedOut)))
+                })
+            }
+            Err(error) => Err(body::Error::Write(error)),
+        };
+        let deadlines = state.response_deadlines();
+        let response = match sent {
+            Ok(()) => response::read_head_from_stream(&mut upstream_conn.stream, deadlines).await,
+            Err(body::Error::Read(error)) if error.kind() == ErrorKind::TimedOut => {
+                log::info!(
+                    "Timed out reading request body from client [{}]",
+                    entry.request_id()
+                );
+                send_response(
+                    state,
+                    client_conn,
+                    &client_ip,
+                    request_timeout_response(),
+                    Some(&entry),
+                )
+                .await;
+                return false;
+            }
+            Err(body::Error::Read(error)) => {
+                log::info!(
+                    "Error reading request body from client stream: {} [{}]",
+                    error,
+                    entry.request_id()
+                );
+                return false;
+            }
+            Err(body::Error::Write(error)) => Err(response::Error::ConnectionError(error)),
+            Err(error) => {
+                log::debug!(
+                    "Error reading request body: {:?} [{}]",
+                    error,
+                    entry.request_id()
+                );
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                send_response(state, client_conn, &client_ip, response, Some(&entry)).await;
+                return false;
+            }
+        };
+        record_upstream_request(state, &upstream_conn, &response, started);
+        (response, deadlines.complete)
+    };
+    entry.upstream = Some(upstream_conn.address.clone());
+    entry.upstream_duration = Some(forwarded.elapsed());
+    let mut response = match response {
+        Ok(response) => response,
+        Err(error) => {
+            log::error!(
+                "Error exchanging request with upstream {}: {:?} [{}]",
+                upstream_conn.address,
+                error,
+                entry.request_id()
+            );
+            let response = upstream_error_response(error.is_timeout());
+            send_response(state, client_conn, &client_ip, response, Some(&entry)).await;
+            return false;
+        }
+    };
+    if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+        relay_upgraded(state, client_conn, upstream_conn, &request, response, entry).await;
+        return false;
+    }
+    let response_framing = match response::body_framing(&response, request.method()) {
+        Ok(response_framing) => response_framing,
+        Err(error) => {
+            log::error!(
+                "Error reading response from upstream {}: {:?} [{}]",
+                upstream_conn.address,
+                error,
+                entry.request_id()
+            );
+            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            send_response(state, client_conn, &client_ip, response, Some(&entry)).await;
+            return false;
+        }
+    };
 
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => break Ok(stream),
-            Err(_) => state.upstream_dead.write().await[random_idx] = true,
+    // Pass the response along to the client. If anything goes wrong from here on, the client has
+    // already seen the status line, so all we can do is hang up.
+    let reusable = pool::can_reuse(&request, &response);
+    state
+        .header_rewriter
+        .read()
+        .await
+        .rewrite_response(&mut response);
+    headers::set_request_id(response.headers_mut(), entry.request_id());
+    let closing = close_if_shutting_down(state, &mut response);
+    log::info!(
+        "{} <- {} [{}]",
+        client_ip,
+        response::format_response_line(&response),
+        entry.request_id()
+    );
+    state.metrics.record_response(response.status());
+    let already_read = std::mem::take(response.body_mut());
+    if let Err(error) = response::write_head_to_stream(&response, client_conn).await {
+        log::warn!(
+            "Failed to send response to client: {} [{}]",
+            error,
+            entry.request_id()
+        );
+        return false;
+    }
+    let mut client_stream = body::Counted::new(client_conn);
+    let copied = body::with_deadline(
+        response_deadline,
+        body::copy(
+            &mut upstream_conn.stream,
+            already_read,
+            &mut client_stream,
+            response_framing,
+            chunked::accepts_trailers(request.headers()),
+        ),
+    )
+    .await;
+    log_access(state, &entry, response.status(), client_stream.count);
+    match copied {
+        Some(Ok(())) => {}
+        Some(Err(error)) => {
+            log::warn!(
+                "Failed to stream response body from {} to client: {:?} [{}]",
+                upstream_conn.address,
+                error,
+                entry.request_id()
+            );
+            return false;
+        }
+        None => {
+            log::warn!(
+                "Timed out streaming response body from {} to client [{}]",
+                upstream_conn.address,
+                entry.request_id()
+            );
+            return false;
         }
     }
+    log::debug!("Forwarded response to client [{}]", entry.request_id());
+
+    if reusable {
+        keep_upstream_conn(state, upstream_conn, pinned_conn).await;
+    }
+    // The client finds the end of a body without a length by waiting for us to hang up
+    response_framing != Framing::UntilClose && !closing
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Passes a 101 Switching Protocols response along to the client, and then relays bytes between
+/// the client and the upstream until the upgraded connection is finished with. Neither connection
+/// can carry HTTP requests afterwards.
+async fn relay_upgraded(
+    state: &ProxyState,
+    client_conn: &mut MaybeTlsStream,
+    mut upstream_conn: UpstreamConnection,
+    request: &http::Request<Vec<u8>>,
+    mut response: http::Response<Vec<u8>>,
+    entry: access_log::Entry,
+) {
+    let client_ip = entry.client_ip();
+    let protocol = match request.headers().get(http::header::UPGRADE) {
+        Some(protocol) => String::from_utf8_lossy(protocol.as_bytes()).to_string(),
+        None => {
+            log::error!(
+                "Upstream {} switched protocols, but the client didn't ask it to [{}]",
+                upstream_conn.address,
+                entry.request_id()
+            );
+            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            send_response(state, client_conn, client_ip, response, Some(&entry)).await;
+            return;
+        }
+    };
+    // The body holds anything the upstream sent after its headers in the new protocol
+    state
+        .header_rewriter
+        .read()
+        .await
+        .rewrite_response(&mut response);
+    send_response(state, client_conn, client_ip, response, Some(&entry)).await;
     log::info!(
-        "{} <- {}",
+        "{} <-> {}: switched to {} [{}]",
         client_ip,
-        response::format_response_line(&response)
+        upstream_conn.address,
+        protocol,
+        entry.request_id()
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
+    match tunnel::relay(
+        client_conn,
+        &mut upstream_conn.stream,
+        state.upgrade_idle_timeout,
+    )
+    .await
+    {
+        Ok(()) => log::debug!(
+            "Upgraded connection from {} closed [{}]",
+            client_ip,
+            entry.request_id()
+        ),
+        Err(error) => log::info!(
+            "Upgraded connection between {} and {} closed: {} [{}]",
+            client_ip,
+            upstream_conn.address,
+            error,
+            entry.request_id()
+        ),
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// Adds `Connection: close` to a response if we are shutting down, so that the client doesn't send
+/// another request on this connection. Returns true if the connection should be closed once the
+/// response has been sent.
+fn close_if_shutting_down(state: &ProxyState, response: &mut http::Response<Vec<u8>>) -> bool {
+    if !state.shutdown.is_started() {
+        return false;
+    }
+    response
+        .headers_mut()
+        .insert("Connection", http::HeaderValue::from_static("close"));
+    true
+}
 
-    if state.max_requests_per_minute != 0
-        && *state
-            .requests_counters
-            .write()
-            .await
-            .entry(client_ip.clone())
-            .and_modify(|counts| *counts += 1)
-            .or_insert(1)
-            > state.max_requests_per_minute
-    {
-        let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-        send_response(&mut client_conn, &response).await;
+/// Writes the access log line for a request, if there is an access log
+fn log_access(
+    state: &ProxyState,
+    entry: &access_log::Entry,
+    status: http::StatusCode,
+    bytes_out: u64,
+) {
+    if let Some(access_log) = &state.access_log {
+        if !access_log.record(entry, status, bytes_out) {
+            state.metrics.record_access_log_dropped();
+        }
+    }
+}
+
+/// Sends a response to the client. `entry` describes the request it answers, if we got as far as
+/// reading one.
+async fn send_response(
+    state: &ProxyState,
+    client_conn: &mut MaybeTlsStream,
+    client_ip: &str,
+    mut response: http::Response<Vec<u8>>,
+    entry: Option<&access_log::Entry>,
+) {
+    if let Some(entry) = entry {
+        headers::set_request_id(response.headers_mut(), entry.request_id());
+    }
+    state.metrics.record_response(response.status());
+    log::info!(
+        "{} <- {} [{}]",
+        client_ip,
+        response::format_response_line(&response),
+        entry.map_or("-", access_log::Entry::request_id)
+    );
+    // Without a request, there's nothing (like HEAD) to stop the response having a body
+    let request_method = entry.map_or(&http::Method::GET, access_log::Entry::method);
+    let result = response::write_to_stream(&response, request_method, client_conn).await;
+    if let Some(entry) = entry {
+        log_access(
+            state,
+            entry,
+            response.status(),
+            response.body().len() as u64,
+        );
+    }
+    if let Err(error) = result {
+        log::warn!(
+            "Failed to send response to client: {} [{}]",
+            error,
+            entry.map_or("-", access_log::Entry::request_id)
+        );
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: Arc<ProxyState>) {
+    let client_ip = match stream.peer_addr() {
+        Ok(address) => address.ip().to_string(),
+        // The client has already hung up
+        Err(_) => return,
+    };
+    log::info!("Connection received from {}", client_ip);
+    set_nodelay(&stream);
+    let mut client_conn = match &state.tls {
+        // The handshake is held to the header timeout, so that a client can't tie up the
+        // connection by sending its ClientHello slowly
+        Some(terminator) => match body::with_deadline(
+            state.client_timeouts.header_deadline(),
+            terminator.accept(stream),
+        )
+        .await
+        {
+            Some(Ok(stream)) => MaybeTlsStream::Tls(Box::new(stream.into())),
+            Some(Err(error)) => {
+                log::info!("TLS handshake with {} failed: {}", client_ip, error);
+                return;
+            }
+            None => {
+                log::info!("TLS handshake with {} timed out", client_ip);
+                return;
+            }
+        },
+        None => MaybeTlsStream::Plain(stream),
+    };
+    let _open_connection = LoadGuard::acquire(&state.metrics.client_connections);
+    if state.mode == Mode::Tcp {
+        tcp::handle_connection(state, client_conn, client_ip).await;
         return;
     }
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+    // Open a connection to a random destination server (or borrow an idle one from the pool). By
+    // default, every request from this client goes to that same server. With per-request
+    // balancing, we instead pick an upstream each time a request arrives. If there are routing
+    // rules, we can't tell which pool the client wants until its first request arrives.
+    let mut pinned_conn = None;
+    let unrouted_pool = {
+        let router = state.router.read().await;
+        Some(router.fallback_pool().to_string()).filter(|_| !router.has_routes())
+    };
+    if let (Some(pool), false) = (unrouted_pool, state.balances_each_request()) {
+        match connect_to_upstream(Arc::clone(&state), &pool, None).await {
+            Ok(upstream_conn) => pinned_conn = Some(upstream_conn),
+            Err(error) => {
+                let response = upstream_error_response(error.kind() == ErrorKind::TimedOut);
+                send_response(&state, &mut client_conn, &client_ip, response, None).await;
+                return;
+            }
         }
+    }
+
+    // HTTP/2 clients are told apart by ALPN under TLS, or by the connection preface in cleartext.
+    // Their streams are balanced individually, so the pinned connection goes back to the pool.
+    let h2 = match &mut client_conn {
+        // A client that sends nothing at all is left to time out as an idle HTTP/1 connection
+        MaybeTlsStream::Plain(stream) => body::with_deadline(
+            state.client_timeouts.idle_deadline(),
+            http2::starts_with_preface(stream),
+        )
+        .await
+        .unwrap_or(false),
+        tls => tls.negotiated_h2(),
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+    if h2 {
+        if let Some(upstream_conn) = pinned_conn {
+            state
+                .connection_pool
+                .checkin(&upstream_conn.address, upstream_conn.stream)
+                .await;
+        }
+        http2::serve(client_conn, state, client_ip).await;
+        return;
+    }
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Read a request from the client. When streaming bodies, only the headers are read here.
+        let read = async {
+            if state.stream_bodies {
+                request::read_head_from_stream(&mut client_conn, &state.client_timeouts).await
+            } else {
+                request::read_from_stream(&mut client_conn, &state.client_timeouts).await
+            }
+        };
+        // If we start shutting down while waiting for the next request, hang up rather than keep
+        // the shutdown waiting on an idle client
+        let request = tokio::select! {
+            request = read => request,
+            _ = state.shutdown.started() => {
+                log::debug!("Shutting down idle client connection");
+                return;
+            }
+        };
+        let mut request = match request {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
+                // The upstream connection is still good, so let the next client use it
+                if let Some(upstream_conn) = pinned_conn {
+                    state
+                        .connection_pool
+                        .checkin(&upstream_conn.address, upstream_conn.stream)
+                        .await;
+                }
+                return;
+            }
+            Err(request::Error::IdleTimeout) => {
+                log::debug!("Client connection has been idle for too long. Shutting it down");
+                if let Some(upstream_conn) = pinned_conn {
+                    state
+                        .connection_pool
+                        .checkin(&upstream_conn.address, upstream_conn.stream)
+                        .await;
+                }
+                return;
+            }
+            Err(request::Error::Timeout) => {
+                log::info!("Timed out reading request from client");
+                send_response(
+                    &state,
+                    &mut client_conn,
+                    &client_ip,
+                    request_timeout_response(),
+                    None,
+                )
+                .await;
                 return;
             }
             // Handle I/O error in reading from the client
@@ -260,52 +2079,174 @@ async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::InvalidChunkSize
+                    | request::Error::MalformedChunkedBody => http::StatusCode::BAD_REQUEST,
                     request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    request::Error::IdleTimeout | request::Error::Timeout => {
+                        http::StatusCode::REQUEST_TIMEOUT
+                    }
                     request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
                 });
-                send_response(&mut client_conn, &response).await;
+                send_response(&state, &mut client_conn, &client_ip, response, None).await;
+                continue;
+            }
+        };
+
+        let request_id = state
+            .header_rewriter
+            .read()
+            .await
+            .request_id(&request, &client_ip);
+        let mut entry = access_log::Entry::new(&client_ip, &request, request_id);
+
+        // Turn the request away if the client has used up its share. We've read the request first,
+        // so that the client gets to see the 429 rather than a connection reset.
+        if let Some(limit) = state.rate_limit() {
+            if let Err(rejection) = state.rate_limiter.check(&client_ip, limit) {
+                state.metrics.record_rate_limited();
+                let mut response = rejection.response();
+                // When streaming, a request body is still waiting in the stream, so we can't find
+                // the start of the next request
+                let body_unread = state.stream_bodies
+                    && !matches!(request::body_framing(&request), Ok(Framing::Empty));
+                if body_unread {
+                    response
+                        .headers_mut()
+                        .insert("Connection", http::HeaderValue::from_static("close"));
+                }
+                send_response(&state, &mut client_conn, &client_ip, response, Some(&entry)).await;
+                if body_unread {
+                    return;
+                }
                 continue;
             }
+        }
+
+        // Use the client's pinned upstream connection if it is in the pool this request is routed
+        // to; otherwise, pick an upstream in that pool for this request (using its hash key, if we
+        // are routing by hash)
+        let pool = state.router.read().await.route(&request).to_string();
+        let affinity_key = state
+            .hash_key
+            .as_ref()
+            .and_then(|hash_key| hash_key.extract(&request, &client_ip));
+        let mut upstream_conn = match pinned_conn.take() {
+            Some(upstream_conn) if upstream_conn.pool == pool => upstream_conn,
+            pinned => {
+                // A connection to another pool is still good for some other client
+                if let Some(upstream_conn) = pinned {
+                    state
+                        .connection_pool
+                        .checkin(&upstream_conn.address, upstream_conn.stream)
+                        .await;
+                }
+                match connect_to_upstream(Arc::clone(&state), &pool, affinity_key.as_deref()).await
+                {
+                    Ok(upstream_conn) => upstream_conn,
+                    Err(error) => {
+                        let response = upstream_error_response(error.kind() == ErrorKind::TimedOut);
+                        send_response(&state, &mut client_conn, &client_ip, response, Some(&entry))
+                            .await;
+                        return;
+                    }
+                }
+            }
         };
         log::info!(
-            "{} -> {}: {}",
+            "{} -> {}: {} [{}]",
             client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
+            upstream_conn.address,
+            request::format_request_line(&request),
+            entry.request_id()
         );
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // Drop the headers meant only for us, tell the upstream who the client is (with
+        // X-Forwarded-For and friends), and apply the configured header rules
+        let version = request.version();
+        state.header_rewriter.read().await.rewrite_request(
+            &mut request,
+            &client_ip,
+            state.client_proto(),
+            version,
+            entry.request_id(),
+        );
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
-            );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+        if state.stream_bodies {
+            let keep_alive = stream_request(
+                &state,
+                &mut client_conn,
+                upstream_conn,
+                request,
+                affinity_key.as_deref(),
+                &mut pinned_conn,
+                entry,
+            )
+            .await;
+            if !keep_alive {
+                return;
+            }
+            continue;
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
+        // Forward the request to the server and read the server's response
+        let forwarded = Instant::now();
+        let response = forward_request(
+            &state,
+            &mut upstream_conn,
+            &request,
+            affinity_key.as_deref(),
+            false,
+            entry.request_id(),
+        )
+        .await;
+        entry.upstream = Some(upstream_conn.address.clone());
+        entry.upstream_duration = Some(forwarded.elapsed());
+        let mut response = match response {
             Ok(response) => response,
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+                log::error!(
+                    "Error exchanging request with upstream {}: {:?} [{}]",
+                    upstream_conn.address,
+                    error,
+                    entry.request_id()
+                );
+                let response = upstream_error_response(error.is_timeout());
+                send_response(&state, &mut client_conn, &client_ip, response, Some(&entry)).await;
                 return;
             }
         };
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            relay_upgraded(
+                &state,
+                &mut client_conn,
+                upstream_conn,
+                &request,
+                response,
+                entry,
+            )
+            .await;
+            return;
+        }
+        if !chunked::accepts_trailers(request.headers()) {
+            response.extensions_mut().remove::<chunked::Trailers>();
+        }
+        // Hang on to the upstream connection if it can carry another request. (If it can't, the
+        // next request will get a new connection from connect_to_upstream.)
+        if pool::can_reuse(&request, &response) {
+            keep_upstream_conn(&state, upstream_conn, &mut pinned_conn).await;
+        }
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        state
+            .header_rewriter
+            .read()
+            .await
+            .rewrite_response(&mut response);
+        let closing = close_if_shutting_down(&state, &mut response);
+        send_response(&state, &mut client_conn, &client_ip, response, Some(&entry)).await;
+        log::debug!("Forwarded response to client [{}]", entry.request_id());
+        if closing {
+            return;
+        }
     }
 }
@@ -0,0 +1,406 @@
+use crate::circuit;
+use crate::upstreams::Upstreams;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the buckets of the upstream latency histogram
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Labels for the status code classes, indexed by the first digit of the status code minus one
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// Counters for a single upstream
+#[derive(Default)]
+struct UpstreamMetrics {
+    /// Number of requests sent to the upstream
+    requests: u64,
+    /// Number of requests that failed without a response (e.g. the connection broke)
+    errors: u64,
+    /// Number of responses received from the upstream, by status code class
+    responses: [u64; STATUS_CLASSES.len()],
+    /// Number of responses whose latency fell into each bucket (not cumulative)
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    /// Total latency of all responses, in seconds
+    latency_sum: f64,
+    /// Number of active health checks the upstream passed
+    health_checks_passed: u64,
+    /// Number of active health checks the upstream failed
+    health_checks_failed: u64,
+}
+
+/// Counters describing what balancebeam has been doing, which can be scraped by Prometheus from
+/// the admin API's /metrics endpoint
+pub struct Metrics {
+    /// Per-upstream counters, keyed by address. Upstreams that have been removed are kept, since
+    /// Prometheus counters shouldn't go backwards.
+    upstreams: Mutex<BTreeMap<String, UpstreamMetrics>>,
+    /// Number of responses sent to clients (including errors generated by balancebeam), by status
+    /// code class
+    responses: [AtomicU64; STATUS_CLASSES.len()],
+    /// Number of client connections that are currently open
+    pub client_connections: Arc<AtomicUsize>,
+    /// Number of requests rejected with 429 Too Many Requests by the rate limiter
+    rate_limited: AtomicU64,
+    /// Number of times a failed request was retried on another upstream
+    retries: AtomicU64,
+    /// Number of failed requests that weren't retried because the retry budget was used up
+    retry_budget_exhausted: AtomicU64,
+    /// Number of access log lines dropped because the writer thread fell behind
+    access_log_dropped: AtomicU64,
+}
+
+/// Returns the index into STATUS_CLASSES for a status code
+fn status_class(status: http::StatusCode) -> usize {
+    (status.as_u16() as usize / 100).clamp(1, STATUS_CLASSES.len()) - 1
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            upstreams: Mutex::new(BTreeMap::new()),
+            responses: Default::default(),
+            client_connections: Arc::new(AtomicUsize::new(0)),
+            rate_limited: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            retry_budget_exhausted: AtomicU64::new(0),
+            access_log_dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a request sent to an upstream, along with the status of its response (or None if
+    /// there was no response) and how long the upstream took to respond
+    pub fn record_upstream_request(
+        &self,
+        upstream: &str,
+        status: Option<http::StatusCode>,
+        latency: Duration,
+    ) {
+        let mut upstreams = self.upstreams.lock();
+        let metrics = upstreams.entry(upstream.to_string()).or_default();
+        metrics.requests += 1;
+        match status {
+            Some(status) => {
+                metrics.responses[status_class(status)] += 1;
+                let latency = latency.as_secs_f64();
+                if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| latency <= le) {
+                    metrics.latency_buckets[bucket] += 1;
+                }
+                metrics.latency_sum += latency;
+            }
+            None => metrics.errors += 1,
+        }
+    }
+
+    /// Records the result of an active health check
+    pub fn record_health_check(&self, upstream: &str, passed: bool) {
+        let mut upstreams = self.upstreams.lock();
+        let metrics = upstreams.entry(upstream.to_string()).or_default();
+        if passed {
+            metrics.health_checks_passed += 1;
+        } else {
+            metrics.health_checks_failed += 1;
+        }
+    }
+
+    /// Records a response sent to a client
+    pub fn record_response(&self, status: http::StatusCode) {
+        self.responses[status_class(status)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a request rejected by the rate limiter
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a failed request being retried on another upstream
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a failed request that wasn't retried because the retry budget was used up
+    pub fn record_retry_budget_exhausted(&self) {
+        self.retry_budget_exhausted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an access log line that was dropped because the writer thread fell behind
+    pub fn record_access_log_dropped(&self) {
+        self.access_log_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format. The current upstreams are
+    /// needed for the gauges describing their state.
+    pub fn render(&self, current_upstreams: &Upstreams) -> String {
+        let mut out = String::new();
+        let upstreams = self.upstreams.lock();
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_requests_total",
+            "counter",
+            "Requests sent to each upstream",
+        );
+        for (address, metrics) in upstreams.iter() {
+            let labels = format!("upstream=\"{}\"", escape(address));
+            write_sample(
+                &mut out,
+                "balancebeam_upstream_requests_total",
+                &labels,
+                metrics.requests,
+            );
+        }
+        write_header(
+            &mut out,
+            "balancebeam_upstream_errors_total",
+            "counter",
+            "Requests to each upstream that failed without a response",
+        );
+        for (address, metrics) in upstreams.iter() {
+            let labels = format!("upstream=\"{}\"", escape(address));
+            write_sample(
+                &mut out,
+                "balancebeam_upstream_errors_total",
+                &labels,
+                metrics.errors,
+            );
+        }
+        write_header(
+            &mut out,
+            "balancebeam_upstream_responses_total",
+            "counter",
+            "Responses received from each upstream, by status code class",
+        );
+        for (address, metrics) in upstreams.iter() {
+            for (class, count) in STATUS_CLASSES.iter().zip(metrics.responses.iter()) {
+                let labels = format!("upstream=\"{}\",code=\"{}\"", escape(address), class);
+                write_sample(
+                    &mut out,
+                    "balancebeam_upstream_responses_total",
+                    &labels,
+                    *count,
+                );
+            }
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_response_seconds",
+            "histogram",
+            "Time each upstream took to respond",
+        );
+        for (address, metrics) in upstreams.iter() {
+            let address = escape(address);
+            let mut cumulative = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(metrics.latency_buckets.iter()) {
+                cumulative += count;
+                let labels = format!("upstream=\"{}\",le=\"{}\"", address, le);
+                write_sample(
+                    &mut out,
+                    "balancebeam_upstream_response_seconds_bucket",
+                    &labels,
+                    cumulative,
+                );
+            }
+            let total: u64 = metrics.responses.iter().sum();
+            let labels = format!("upstream=\"{}\",le=\"+Inf\"", address);
+            write_sample(
+                &mut out,
+                "balancebeam_upstream_response_seconds_bucket",
+                &labels,
+                total,
+            );
+            let labels = format!("upstream=\"{}\"", address);
+            write_sample(
+                &mut out,
+                "balancebeam_upstream_response_seconds_sum",
+                &labels,
+                metrics.latency_sum,
+            );
+            write_sample(
+                &mut out,
+                "balancebeam_upstream_response_seconds_count",
+                &labels,
+                total,
+            );
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_health_checks_total",
+            "counter",
+            "Active health checks of each upstream, by result",
+        );
+        for (address, metrics) in upstreams.iter() {
+            let address = escape(address);
+            let labels = format!("upstream=\"{}\",result=\"passed\"", address);
+            write_sample(
+                &mut out,
+                "balancebeam_health_checks_total",
+                &labels,
+                metrics.health_checks_passed,
+            );
+            let labels = format!("upstream=\"{}\",result=\"failed\"", address);
+            write_sample(
+                &mut out,
+                "balancebeam_health_checks_total",
+                &labels,
+                metrics.health_checks_failed,
+            );
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_up",
+            "gauge",
+            "Whether each upstream is currently considered alive",
+        );
+        for (index, address) in current_upstreams.addresses.iter().enumerate() {
+            let labels = format!("upstream=\"{}\"", escape(address));
+            let up = if current_upstreams.dead[index] { 0 } else { 1 };
+            write_sample(&mut out, "balancebeam_upstream_up", &labels, up);
+        }
+        write_header(
+            &mut out,
+            "balancebeam_upstream_circuit_state",
+            "gauge",
+            "State of each upstream's circuit breaker (1 for the current state, 0 for the others)",
+        );
+        for (index, address) in current_upstreams.addresses.iter().enumerate() {
+            let current = current_upstreams.breakers[index].state();
+            for state in &[
+                circuit::State::Closed,
+                circuit::State::Open,
+                circuit::State::HalfOpen,
+            ] {
+                let labels = format!("upstream=\"{}\",state=\"{}\"", escape(address), state);
+                let value = if *state == current { 1 } else { 0 };
+                write_sample(
+                    &mut out,
+                    "balancebeam_upstream_circuit_state",
+                    &labels,
+                    value,
+                );
+            }
+        }
+        write_header(
+            &mut out,
+            "balancebeam_upstream_active_connections",
+            "gauge",
+            "Connections to each upstream that are currently handed out to clients",
+        );
+        for (index, address) in current_upstreams.addresses.iter().enumerate() {
+            let labels = format!("upstream=\"{}\"", escape(address));
+            write_sample(
+                &mut out,
+                "balancebeam_upstream_active_connections",
+                &labels,
+                current_upstreams.outstanding[index].load(Ordering::SeqCst),
+            );
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_responses_total",
+            "counter",
+            "Responses sent to clients, by status code class",
+        );
+        for (class, count) in STATUS_CLASSES.iter().zip(self.responses.iter()) {
+            let labels = format!("code=\"{}\"", class);
+            write_sample(
+                &mut out,
+                "balancebeam_responses_total",
+                &labels,
+                count.load(Ordering::Relaxed),
+            );
+        }
+        write_header(
+            &mut out,
+            "balancebeam_client_connections",
+            "gauge",
+            "Client connections that are currently open",
+        );
+        write_sample(
+            &mut out,
+            "balancebeam_client_connections",
+            "",
+            self.client_connections.load(Ordering::SeqCst),
+        );
+        write_header(
+            &mut out,
+            "balancebeam_rate_limited_total",
+            "counter",
+            "Requests rejected with 429 Too Many Requests by the rate limiter",
+        );
+        write_sample(
+            &mut out,
+            "balancebeam_rate_limited_total",
+            "",
+            self.rate_limited.load(Ordering::Relaxed),
+        );
+        write_header(
+            &mut out,
+            "balancebeam_retries_total",
+            "counter",
+            "Failed requests that were retried on another upstream",
+        );
+        write_sample(
+            &mut out,
+            "balancebeam_retries_total",
+            "",
+            self.retries.load(Ordering::Relaxed),
+        );
+        write_header(
+            &mut out,
+            "balancebeam_retry_budget_exhausted_total",
+            "counter",
+            "Failed requests that were not retried because the retry budget was used up",
+        );
+        write_sample(
+            &mut out,
+            "balancebeam_retry_budget_exhausted_total",
+            "",
+            self.retry_budget_exhausted.load(Ordering::Relaxed),
+        );
+        write_header(
+            &mut out,
+            "balancebeam_access_log_dropped_total",
+            "counter",
+            "Access log lines dropped because writing them fell behind",
+        );
+        write_sample(
+            &mut out,
+            "balancebeam_access_log_dropped_total",
+            "",
+            self.access_log_dropped.load(Ordering::Relaxed),
+        );
+        out
+    }
+}
+
+/// Writes the HELP and TYPE lines that introduce a metric
+fn write_header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+/// Writes a single sample line, e.g. `name{label="value"} 42`
+fn write_sample<T: std::fmt::Display>(out: &mut String, name: &str, labels: &str, value: T) {
+    if labels.is_empty() {
+        writeln!(out, "{} {}", name, value).unwrap();
+    } else {
+        writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+    }
+}
+
+/// Escapes a label value as required by the exposition format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,130 @@
+use crate::balancer::LoadGuard;
+use crate::chunked;
+use crate::circuit::Breaker;
+use crate::tls::MaybeTlsStream;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// A connection to an upstream server that is sitting in the pool, waiting to be reused
+struct IdleConnection {
+    stream: MaybeTlsStream,
+    /// When the connection was returned to the pool
+    idle_since: Instant,
+}
+
+/// A connection to an upstream server that was handed out by connect_to_upstream. It may be a
+/// brand new connection, or it may have been borrowed from the pool.
+pub struct UpstreamConnection {
+    /// Address of the upstream server (as given on the command line)
+    pub address: String,
+    /// The pool the upstream was chosen from
+    pub pool: String,
+    pub stream: MaybeTlsStream,
+    /// Whether this connection was previously used for other requests. A reused connection may
+    /// have been closed by the upstream while it was idle, so a failure on one is worth retrying
+    /// on a fresh connection.
+    pub reused: bool,
+    /// Counts this connection towards the upstream's outstanding connections until it is dropped
+    #[allow(dead_code)]
+    pub load: LoadGuard,
+    /// Circuit breaker of the upstream, told how the requests sent over this connection went
+    pub breaker: Arc<Breaker>,
+}
+
+/// Keeps idle keep-alive connections to upstream servers so that clients don't have to pay for a
+/// fresh TCP (and TLS) handshake every time they connect to balancebeam.
+pub struct ConnectionPool {
+    /// Maximum number of idle connections to keep for each upstream (0 disables pooling)
+    max_idle_per_upstream: usize,
+    /// How long a connection may sit idle in the pool before we close it
+    idle_timeout: Duration,
+    /// Idle connections, keyed by upstream address. The most recently returned connection is at
+    /// the end of each list.
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle_per_upstream: usize, idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            max_idle_per_upstream,
+            idle_timeout,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes an idle connection to the given upstream out of the pool, if there is one that hasn't
+    /// timed out yet.
+    pub async fn checkout(&self, address: &str) -> Option<MaybeTlsStream> {
+        let mut idle = self.idle.lock().await;
+        let connections = idle.get_mut(address)?;
+        while let Some(connection) = connections.pop() {
+            if connection.idle_since.elapsed() < self.idle_timeout {
+                return Some(connection.stream);
+            }
+        }
+        None
+    }
+
+    /// Returns a connection to the pool so that it can be used by a later client. If the pool for
+    /// this upstream is already full, the connection is closed instead.
+    pub async fn checkin(&self, address: &str, stream: MaybeTlsStream) {
+        if self.max_idle_per_upstream == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().await;
+        let connections = idle.entry(address.to_string()).or_insert_with(Vec::new);
+        if connections.len() < self.max_idle_per_upstream {
+            connections.push(IdleConnection {
+                stream,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    /// Closes all idle connections to the given upstream. This is used when an upstream fails,
+    /// since its pooled connections are unlikely to be any good.
+    pub async fn evict(&self, address: &str) {
+        self.idle.lock().await.remove(address);
+    }
+
+    /// Closes any connections that have been idle for longer than the idle timeout
+    pub async fn reap_expired(&self) {
+        let idle_timeout = self.idle_timeout;
+        let mut idle = self.idle.lock().await;
+        for connections in idle.values_mut() {
+            connections.retain(|connection| connection.idle_since.elapsed() < idle_timeout);
+        }
+        idle.retain(|_, connections| !connections.is_empty());
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+}
+
+/// Returns true if the upstream connection that carried this request/response pair can be used for
+/// another request. This is not the case if either side asked to close the connection, or if the
+/// response body was delimited by the upstream closing the connection (rather than by
+/// Content-Length or chunked encoding).
+pub fn can_reuse(request: &http::Request<Vec<u8>>, response: &http::Response<Vec<u8>>) -> bool {
+    let wants_close = |headers: &http::HeaderMap| {
+        headers
+            .get_all("connection")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("close"))
+    };
+    if wants_close(request.headers()) || wants_close(response.headers()) {
+        return false;
+    }
+    let has_body = !(request.method() == http::Method::HEAD
+        || response.status().as_u16() < 200
+        || response.status() == http::StatusCode::NO_CONTENT
+        || response.status() == http::StatusCode::NOT_MODIFIED);
+    !has_body
+        || response.headers().contains_key("content-length")
+        || chunked::is_chunked(response.headers())
+}
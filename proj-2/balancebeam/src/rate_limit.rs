@@ -0,0 +1,167 @@
+use crate::response;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How many requests a client may send: up to `burst` back to back, after which it earns
+/// `per_minute` more every minute (up to `burst` again)
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub per_minute: usize,
+    pub burst: usize,
+}
+
+impl Limit {
+    /// Builds a limit from the --max-requests-per-minute and --rate-limit-burst settings. Returns
+    /// None if rate limiting is off; a burst of 0 means the same as the per-minute rate.
+    pub fn new(per_minute: usize, burst: usize) -> Option<Limit> {
+        if per_minute == 0 {
+            return None;
+        }
+        let burst = if burst == 0 { per_minute } else { burst };
+        Some(Limit { per_minute, burst })
+    }
+
+    /// How long it takes a client to earn the given number of requests
+    fn time_to_earn(&self, tokens: f64) -> Duration {
+        Duration::from_secs_f64(tokens * 60.0 / self.per_minute as f64)
+    }
+}
+
+/// A client's token bucket. Each request takes a token, and tokens trickle back in at the
+/// per-minute rate. Requests made at other instances can leave it with fewer than zero tokens.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: Limit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    /// Adds the tokens earned since the last update
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.per_minute as f64 / 60.0).min(limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Why a request was turned away, and when the client may try again
+#[derive(Debug)]
+pub struct Rejection {
+    limit: Limit,
+    /// Time until the client has earned another request
+    retry_after: Duration,
+    /// Time until the client's bucket is full again
+    reset: Duration,
+}
+
+impl Rejection {
+    /// Builds the 429 response, telling the client when to come back
+    pub fn response(&self) -> http::Response<Vec<u8>> {
+        let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+        let headers = response.headers_mut();
+        headers.insert("Retry-After", whole_seconds(self.retry_after).into());
+        headers.insert("X-RateLimit-Limit", self.limit.burst.into());
+        headers.insert("X-RateLimit-Remaining", 0.into());
+        headers.insert("X-RateLimit-Reset", whole_seconds(self.reset).into());
+        response
+    }
+}
+
+/// Rounds a duration up to whole seconds, since that's what the headers carry
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs_f64().ceil() as u64
+}
+
+/// Keeps track of how many requests each client has left. Implementations may share that state
+/// with other balancebeam instances, so that a client can't get around the limit by spreading its
+/// requests across them.
+pub trait Store: Send + Sync {
+    /// Takes a token from the client's bucket, or returns a Rejection if the bucket is empty
+    fn check(&self, client: &str, limit: Limit) -> Result<(), Rejection>;
+
+    /// Forgets clients whose buckets have filled back up, since a full bucket behaves the same as
+    /// a new one. Forgets everyone if rate limiting has been turned off.
+    fn evict_idle(&self, limit: Option<Limit>);
+}
+
+/// Token buckets kept in this process, keyed by client IP. This is the default store.
+pub struct LocalStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    /// Requests made by clients somewhere else (i.e. at other instances) that haven't been taken
+    /// out of their buckets yet. They are settled on the next check, once we know the limit.
+    debits: Mutex<HashMap<String, u64>>,
+}
+
+impl LocalStore {
+    pub fn new() -> LocalStore {
+        LocalStore {
+            buckets: Mutex::new(HashMap::new()),
+            debits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records requests a client made somewhere else. They count against the client's bucket even
+    /// if that leaves it empty (or in debt), so the client has to wait for them to be earned back.
+    pub fn debit(&self, client: &str, requests: u64) {
+        *self.debits.lock().entry(client.to_string()).or_insert(0) += requests;
+    }
+
+    /// Takes the outstanding debits out of the buckets
+    fn settle_debits(&self, buckets: &mut HashMap<String, Bucket>, limit: Limit, now: Instant) {
+        for (client, requests) in self.debits.lock().drain() {
+            let bucket = buckets
+                .entry(client)
+                .or_insert_with(|| Bucket::full(limit, now));
+            bucket.refill(limit, now);
+            bucket.tokens -= requests as f64;
+        }
+    }
+}
+
+impl Store for LocalStore {
+    fn check(&self, client: &str, limit: Limit) -> Result<(), Rejection> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        self.settle_debits(&mut buckets, limit, now);
+        let bucket = buckets
+            .entry(client.to_string())
+            .or_insert_with(|| Bucket::full(limit, now));
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Rejection {
+                limit,
+                retry_after: limit.time_to_earn(1.0 - bucket.tokens),
+                reset: limit.time_to_earn(limit.burst as f64 - bucket.tokens),
+            })
+        }
+    }
+
+    fn evict_idle(&self, limit: Option<Limit>) {
+        let mut buckets = self.buckets.lock();
+        match limit {
+            Some(limit) => {
+                let now = Instant::now();
+                self.settle_debits(&mut buckets, limit, now);
+                buckets.retain(|_, bucket| {
+                    bucket.refill(limit, now);
+                    bucket.tokens < limit.burst as f64
+                });
+            }
+            None => {
+                buckets.clear();
+                self.debits.lock().clear();
+            }
+        }
+    }
+}
@@ -1,9 +1,11 @@
+use crate::body;
+use crate::chunked;
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::time::{Duration, Instant};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+pub const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
@@ -19,10 +21,47 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The request body is chunked, but a chunk-size line is not a valid hexadecimal number
+    InvalidChunkSize,
+    /// The request body is chunked, but a chunk or trailer is malformed or the client hung up
+    /// before sending the last chunk
+    MalformedChunkedBody,
+    /// Client didn't start another request before the idle timeout
+    IdleTimeout,
+    /// Client took longer than the header or body timeout to send the request
+    Timeout,
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
+/// How long a client may take over each part of a request (None = as long as it likes)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// Until the first byte of the request, i.e. how long a keep-alive connection may sit idle
+    pub idle: Option<Duration>,
+    /// From the first byte of the request to the end of the headers
+    pub header: Option<Duration>,
+    /// For the whole body, once the headers are in
+    pub body: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Returns the deadline for a connection that has just gone idle
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        self.idle.map(|idle| Instant::now() + idle)
+    }
+
+    /// Returns the deadline for headers (or a TLS handshake) that have just started to arrive
+    pub fn header_deadline(&self) -> Option<Instant> {
+        self.header.map(|header| Instant::now() + header)
+    }
+
+    /// Returns the deadline for a body whose headers have just been read
+    pub fn body_deadline(&self) -> Option<Instant> {
+        self.body.map(|body| Instant::now() + body)
+    }
+}
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -103,22 +142,35 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    timeouts: &Timeouts,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
     let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
     let mut bytes_read = 0;
+    // The idle timeout runs until the request starts, and the header timeout from then on
+    let mut deadline = timeouts.idle.map(|idle| Instant::now() + idle);
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let new_bytes =
+            body::with_deadline(deadline, stream.read(&mut request_buffer[bytes_read..]))
+                .await
+                .ok_or(if bytes_read == 0 {
+                    Error::IdleTimeout
+                } else {
+                    Error::Timeout
+                })?
+                .or_else(|err| Err(Error::ConnectionError(err)))?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
         }
+        if bytes_read == 0 {
+            deadline = timeouts.header.map(|header| Instant::now() + header);
+        }
         bytes_read += new_bytes;
 
         // See if we've read a valid request so far
@@ -141,7 +193,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 ///
 /// You will need to modify this function in Milestone 2.
 async fn read_body(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
 ) -> Result<(), Error> {
@@ -181,47 +233,129 @@ async fn read_body(
 }
 
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// closes the connection prematurely, sends an invalid request or runs out of time.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream(
+    stream: &mut (impl AsyncRead + Unpin),
+    timeouts: &Timeouts,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(stream, timeouts).await?;
+    body::with_deadline(
+        timeouts.body_deadline(),
+        read_any_body(stream, &mut request),
+    )
+    .await
+    .ok_or(Error::Timeout)??;
+    Ok(request)
+}
 
+/// Reads the body of a request whose headers have been read, however it is framed
+async fn read_any_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<(), Error> {
+    // Read body if the client sent it in chunks. Transfer-Encoding takes precedence over
+    // Content-Length, and we drop Content-Length so the upstream can't interpret the body
+    // differently from us.
+    if chunked::is_chunked(request.headers()) {
+        request.headers_mut().remove("content-length");
+        let already_read = std::mem::take(request.body_mut());
+        let (body, trailers) = chunked::read_body(stream, already_read, MAX_BODY_SIZE)
+            .await
+            .map_err(|error| match error {
+                chunked::Error::InvalidChunkSize => Error::InvalidChunkSize,
+                chunked::Error::MalformedChunk => Error::MalformedChunkedBody,
+                chunked::Error::BodyTooLarge => Error::RequestBodyTooLarge,
+                chunked::Error::Io(err) => Error::ConnectionError(err),
+            })?;
+        *request.body_mut() = body;
+        request.extensions_mut().insert(trailers);
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
+    } else if let Some(content_length) = get_content_length(request)? {
         if content_length > MAX_BODY_SIZE {
             return Err(Error::RequestBodyTooLarge);
         } else {
-            read_body(stream, &mut request, content_length).await?;
+            read_body(stream, request, content_length).await?;
         }
     }
+    Ok(())
+}
+
+/// Reads the request line and headers from a stream, but leaves the body (if any) in the stream so
+/// that it can be streamed to the upstream with body::copy. The body of the returned request holds
+/// any bytes that were read past the end of the headers. Since the body isn't buffered here, there
+/// is no limit on its size.
+pub async fn read_head_from_stream(
+    stream: &mut (impl AsyncRead + Unpin),
+    timeouts: &Timeouts,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let mut request = read_headers(stream, timeouts).await?;
+    // As in read_from_stream, Transfer-Encoding takes precedence over Content-Length
+    if chunked::is_chunked(request.headers()) {
+        request.headers_mut().remove("content-length");
+    }
     Ok(request)
 }
 
+/// Returns how the end of the request body is marked. A request only has a body if it is chunked
+/// or has a nonzero Content-Length.
+pub fn body_framing(request: &http::Request<Vec<u8>>) -> Result<body::Framing, Error> {
+    if chunked::is_chunked(request.headers()) {
+        return Ok(body::Framing::Chunked);
+    }
+    match get_content_length(request)? {
+        None | Some(0) => Ok(body::Framing::Empty),
+        Some(content_length) => Ok(body::Framing::ContentLength(content_length)),
+    }
+}
+
+/// Serializes the request line and headers, followed by the empty line that ends the headers
+fn format_head(request: &http::Request<Vec<u8>>) -> Vec<u8> {
+    let mut head = format_request_line(request).into_bytes();
+    head.extend_from_slice(b"\r\n");
+    for (header_name, header_value) in request.headers() {
+        head.extend_from_slice(header_name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header_value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Writes only the request line and headers to the stream, ignoring the request body. The body
+/// can then be sent with body::copy.
+pub async fn write_head_to_stream(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), std::io::Error> {
+    body::write_all(stream, &format_head(request)).await
+}
+
+/// Serializes a whole request (head and body) to bytes
+pub fn to_bytes(request: &http::Request<Vec<u8>>) -> Vec<u8> {
+    let mut bytes = format_head(request);
+    if chunked::is_chunked(request.headers()) {
+        let trailers = request.extensions().get::<chunked::Trailers>();
+        bytes.extend_from_slice(&chunked::encode(request.body(), trailers));
+    } else {
+        bytes.extend_from_slice(request.body());
+    }
+    bytes
+}
+
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
-    stream
-        .write(&format_request_line(request).into_bytes())
-        .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream
-            .write(&format!("{}: ", header_name).as_bytes())
-            .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
-    }
-    Ok(())
+    // Send the whole request with one write, so that the upstream doesn't see the headers trickle
+    // in a few bytes at a time
+    body::write_all(stream, &to_bytes(request)).await
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
@@ -1,5 +1,7 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::body;
+use crate::chunked;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::time::Instant;
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
@@ -17,10 +19,37 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The response body is chunked, but a chunk-size line is not a valid hexadecimal number
+    InvalidChunkSize,
+    /// The response body is chunked, but a chunk or trailer is malformed or the server hung up
+    /// before sending the last chunk
+    MalformedChunkedBody,
+    /// The server didn't respond before the first byte or response deadline
+    Timeout,
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
+impl Error {
+    /// Returns true if the server took too long, whether to respond or to accept a connection
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Timeout => true,
+            Error::ConnectionError(error) => error.kind() == std::io::ErrorKind::TimedOut,
+            _ => false,
+        }
+    }
+}
+
+/// When a server has to have responded by (None = no deadline)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deadlines {
+    /// For the first byte of the response
+    pub first_byte: Option<Instant>,
+    /// For the whole response (only the head, when the body is streamed)
+    pub complete: Option<Instant>,
+}
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -80,7 +109,10 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut (impl AsyncRead + Unpin),
+    first_byte_deadline: Option<Instant>,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -88,10 +120,12 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
     let mut bytes_read = 0;
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let deadline = first_byte_deadline.filter(|_| bytes_read == 0);
+        let new_bytes =
+            body::with_deadline(deadline, stream.read(&mut response_buffer[bytes_read..]))
+                .await
+                .ok_or(Error::Timeout)?
+                .or_else(|err| Err(Error::ConnectionError(err)))?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
             return Err(Error::IncompleteResponse);
@@ -116,9 +150,26 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 ///
 /// You will need to modify this function in Milestone 2.
 async fn read_body(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     response: &mut http::Response<Vec<u8>>,
 ) -> Result<(), Error> {
+    // A chunked response ends with a zero-length chunk, regardless of any Content-Length header
+    if chunked::is_chunked(response.headers()) {
+        response.headers_mut().remove("content-length");
+        let already_read = std::mem::take(response.body_mut());
+        let (body, trailers) = chunked::read_body(stream, already_read, MAX_BODY_SIZE)
+            .await
+            .map_err(|error| match error {
+                chunked::Error::InvalidChunkSize => Error::InvalidChunkSize,
+                chunked::Error::MalformedChunk => Error::MalformedChunkedBody,
+                chunked::Error::BodyTooLarge => Error::ResponseBodyTooLarge,
+                chunked::Error::Io(err) => Error::ConnectionError(err),
+            })?;
+        *response.body_mut() = body;
+        response.extensions_mut().insert(trailers);
+        return Ok(());
+    }
+
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
@@ -159,50 +210,124 @@ async fn read_body(
     Ok(())
 }
 
+/// Returns true if a response with this status code to a request with this method has a body.
+/// Responses to HEAD requests, as well as 1xx (informational), 204 (no content), and 304 (not
+/// modified) responses, never do.
+fn has_body(status: http::StatusCode, request_method: &http::Method) -> bool {
+    !(request_method == http::Method::HEAD
+        || status.as_u16() < 200
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED)
+}
+
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// closes the connection prematurely, sends an invalid response or misses a deadline.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn read_from_stream(
-    stream: &mut TcpStream,
+    stream: &mut (impl AsyncRead + Unpin),
     request_method: &http::Method,
+    deadlines: Deadlines,
+) -> Result<http::Response<Vec<u8>>, Error> {
+    body::with_deadline(deadlines.complete, async {
+        let mut response = read_headers(stream, deadlines.first_byte).await?;
+        if has_body(response.status(), request_method) {
+            read_body(stream, &mut response).await?;
+        }
+        Ok(response)
+    })
+    .await
+    .ok_or(Error::Timeout)?
+}
+
+/// Reads the status line and headers from a stream, but leaves the body (if any) in the stream so
+/// that it can be streamed to the client with body::copy. The body of the returned response holds
+/// any bytes that were read past the end of the headers. Since the body isn't buffered here, there
+/// is no limit on its size.
+pub async fn read_head_from_stream(
+    stream: &mut (impl AsyncRead + Unpin),
+    deadlines: Deadlines,
 ) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await?;
+    let mut response = body::with_deadline(
+        deadlines.complete,
+        read_headers(stream, deadlines.first_byte),
+    )
+    .await
+    .ok_or(Error::Timeout)??;
+    // As in read_body, a chunked response ends with a zero-length chunk, regardless of any
+    // Content-Length header
+    if chunked::is_chunked(response.headers()) {
+        response.headers_mut().remove("content-length");
     }
     Ok(response)
 }
 
+/// Returns how the end of the response body is marked. If the response has no Content-Length and
+/// isn't chunked, the body continues until the server closes the connection.
+pub fn body_framing(
+    response: &http::Response<Vec<u8>>,
+    request_method: &http::Method,
+) -> Result<body::Framing, Error> {
+    if !has_body(response.status(), request_method) {
+        Ok(body::Framing::Empty)
+    } else if chunked::is_chunked(response.headers()) {
+        Ok(body::Framing::Chunked)
+    } else {
+        match get_content_length(response)? {
+            Some(0) => Ok(body::Framing::Empty),
+            Some(content_length) => Ok(body::Framing::ContentLength(content_length)),
+            None => Ok(body::Framing::UntilClose),
+        }
+    }
+}
+
+/// Serializes the status line and headers, followed by the empty line that ends the headers
+fn format_head(response: &http::Response<Vec<u8>>) -> Vec<u8> {
+    let mut head = format_response_line(response).into_bytes();
+    head.extend_from_slice(b"\r\n");
+    for (header_name, header_value) in response.headers() {
+        head.extend_from_slice(header_name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header_value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Writes only the status line and headers to the stream, ignoring the response body. The body
+/// can then be sent with body::copy.
+pub async fn write_head_to_stream(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), std::io::Error> {
+    body::write_all(stream, &format_head(response)).await
+}
+
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
+/// `request_method` is the method of the request being answered, which decides whether the
+/// response has a body at all.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    request_method: &http::Method,
+    stream: &mut (impl AsyncWrite + Unpin),
 ) -> Result<(), std::io::Error> {
-    stream
-        .write(&format_response_line(response).into_bytes())
-        .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in response.headers() {
-        stream
-            .write(&format!("{}: ", header_name).as_bytes())
-            .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    // Send the whole response with one write, so that the client doesn't see the headers trickle
+    // in a few bytes at a time
+    let mut bytes = format_head(response);
+    // Responses to HEAD requests (and 1xx, 204 and 304 responses) end with the headers, even if
+    // they describe a chunked body
+    if has_body(response.status(), request_method) {
+        if chunked::is_chunked(response.headers()) {
+            let trailers = response.extensions().get::<chunked::Trailers>();
+            bytes.extend_from_slice(&chunked::encode(response.body(), trailers));
+        } else {
+            bytes.extend_from_slice(response.body());
+        }
     }
-    Ok(())
+    body::write_all(stream, &bytes).await
 }
 
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
@@ -0,0 +1,75 @@
+//! Retrying requests that fail against an upstream. A request is only retried if doing so can't
+//! have unwanted side effects: idempotent requests always qualify, while other requests only do if
+//! the upstream can't have seen any of them. Retries wait a random ("jittered") backoff that grows
+//! with every attempt, so that a struggling upstream isn't hit by every client in lockstep.
+//!
+//! Retries also have to fit in a proxy-wide budget. Every request earns a fraction of a retry, and
+//! every retry spends a whole one, so when lots of requests are failing (e.g. because all the
+//! upstreams are overloaded) we stop retrying rather than multiplying the load.
+
+use parking_lot::Mutex;
+use rand::Rng;
+use std::time::Duration;
+
+/// Number of retries that can be made back to back before the budget has to be earned. This lets a
+/// quiet proxy retry the occasional failure.
+const BUDGET_BURST: f64 = 10.0;
+
+/// Longest backoff before a retry, however many attempts have been made
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Returns true if sending the request twice has the same effect as sending it once, so that it is
+/// safe to retry even if the upstream may have started handling it
+pub fn is_idempotent(method: &http::Method) -> bool {
+    matches!(
+        *method,
+        http::Method::GET
+            | http::Method::HEAD
+            | http::Method::PUT
+            | http::Method::DELETE
+            | http::Method::OPTIONS
+    )
+}
+
+/// Returns how long to wait before retry number `attempt` (starting from 0): somewhere between half
+/// and all of `base` doubled for each earlier attempt
+pub fn backoff(base: Duration, attempt: u32) -> Duration {
+    let max = base
+        .checked_mul(1 << attempt.min(16))
+        .map_or(MAX_BACKOFF, |max| max.min(MAX_BACKOFF));
+    max.mul_f64(rand::thread_rng().gen_range(0.5, 1.0))
+}
+
+/// Limits retries to a fraction of the requests that are being forwarded (--retry-budget)
+pub struct RetryBudget {
+    /// Retries earned by each request
+    ratio: f64,
+    /// Retries that can be made right now
+    balance: Mutex<f64>,
+}
+
+impl RetryBudget {
+    /// Creates a budget allowing retries to make up `percent` percent of requests
+    pub fn new(percent: usize) -> RetryBudget {
+        RetryBudget {
+            ratio: percent as f64 / 100.0,
+            balance: Mutex::new(BUDGET_BURST),
+        }
+    }
+
+    /// Records a request, earning its share of a retry
+    pub fn deposit(&self) {
+        let mut balance = self.balance.lock();
+        *balance = (*balance + self.ratio).min(BUDGET_BURST);
+    }
+
+    /// Spends a retry, returning false if there are none left
+    pub fn withdraw(&self) -> bool {
+        let mut balance = self.balance.lock();
+        if *balance < 1.0 {
+            return false;
+        }
+        *balance -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,194 @@
+//! Routing requests to named pools of upstreams. Each pool has its own upstreams, balancing
+//! strategy and health check path. A request is checked against the routing rules in order, and
+//! the first rule that matches its path or Host header picks the pool; requests that match no rule
+//! go to the fallback pool.
+//!
+//! Upstreams given with --upstream form the pool named "default", so without any --pool or --route
+//! options everything goes to them, as before.
+
+use crate::balancer::Balancer;
+use regex::Regex;
+use std::str::FromStr;
+
+/// The pool formed by the upstreams given with --upstream
+pub const DEFAULT_POOL: &str = "default";
+
+/// What a routing rule looks for in a request
+enum Match {
+    /// The path starts with this
+    Prefix(String),
+    /// The path is exactly this
+    Exact(String),
+    /// The path matches this regular expression (anywhere in the path, unless it is anchored)
+    Regex(Regex),
+    /// The Host header names this host, ignoring case and any port
+    Host(String),
+}
+
+/// A routing rule (--route), sending the requests it matches to a pool
+pub struct Route {
+    matcher: Match,
+    pool: String,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Parses a rule of the form kind:value=pool, e.g. prefix:/api/=api
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid route {:?} (expected prefix:<path>=<pool>, exact:<path>=<pool>, \
+                regex:<pattern>=<pool> or host:<hostname>=<pool>)",
+                s
+            )
+        };
+        // Pool names can't contain '=', but a regex can
+        let (rule, pool) = s.rsplit_once('=').ok_or_else(invalid)?;
+        let (kind, value) = rule.split_once(':').ok_or_else(invalid)?;
+        if pool.is_empty() || value.is_empty() {
+            return Err(invalid());
+        }
+        let matcher = match kind {
+            "prefix" => Match::Prefix(value.to_string()),
+            "exact" => Match::Exact(value.to_string()),
+            "regex" => Match::Regex(
+                Regex::new(value)
+                    .map_err(|err| format!("invalid regex in route {:?}: {}", s, err))?,
+            ),
+            "host" => Match::Host(value.to_string()),
+            _ => return Err(invalid()),
+        };
+        Ok(Route {
+            matcher,
+            pool: pool.to_string(),
+        })
+    }
+}
+
+impl Route {
+    fn matches<T>(&self, request: &http::Request<T>) -> bool {
+        let path = request.uri().path();
+        match &self.matcher {
+            Match::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            Match::Exact(exact) => path == exact,
+            Match::Regex(regex) => regex.is_match(path),
+            Match::Host(host) => {
+                request_host(request).is_some_and(|h| h.eq_ignore_ascii_case(host))
+            }
+        }
+    }
+}
+
+/// Returns the host a request is addressed to, without the port
+fn request_host<T>(request: &http::Request<T>) -> Option<&str> {
+    let host = match request.headers().get(http::header::HOST) {
+        Some(host) => host.to_str().ok()?,
+        // A request with an absolute URI may leave out the Host header
+        None => return request.uri().host(),
+    };
+    match host.find(']') {
+        // An IPv6 address, which has colons of its own
+        Some(end) => Some(&host[..=end]),
+        None => host.split(':').next(),
+    }
+}
+
+/// A named group of upstreams that requests can be routed to. The upstreams themselves are kept in
+/// ProxyState.upstreams, which records the pool each of them belongs to.
+pub struct Pool {
+    pub name: String,
+    /// Decides which of the pool's upstreams each new connection goes to
+    pub balancer: Box<dyn Balancer>,
+    /// Where active health checks of the pool's upstreams are sent (None = use
+    /// --active-health-check-path)
+    pub health_check_path: Option<String>,
+}
+
+/// The pools along with the rules for choosing between them
+pub struct Router {
+    routes: Vec<Route>,
+    pools: Vec<Pool>,
+    /// The pool for requests that match no rule (and for every connection in TCP mode)
+    fallback_pool: String,
+}
+
+impl Router {
+    /// Creates a router, checking that the rules and the fallback only refer to pools that exist
+    pub fn new(
+        routes: Vec<Route>,
+        pools: Vec<Pool>,
+        fallback_pool: String,
+    ) -> Result<Router, String> {
+        let router = Router {
+            routes,
+            pools,
+            fallback_pool,
+        };
+        if router.pool(&router.fallback_pool).is_none() {
+            return Err(format!(
+                "The fallback pool {:?} does not exist. Define it with --pool (or --upstream, for \
+                the default pool), or choose another one with --fallback-pool.",
+                router.fallback_pool
+            ));
+        }
+        if let Some(route) = router
+            .routes
+            .iter()
+            .find(|route| router.pool(&route.pool).is_none())
+        {
+            return Err(format!(
+                "--route sends requests to pool {:?}, which does not exist.",
+                route.pool
+            ));
+        }
+        Ok(router)
+    }
+
+    /// Returns the name of the pool a request should go to
+    pub fn route<T>(&self, request: &http::Request<T>) -> &str {
+        self.routes
+            .iter()
+            .find(|route| route.matches(request))
+            .map_or(&self.fallback_pool, |route| &route.pool)
+    }
+
+    /// Returns true if there are any rules, i.e. requests may go to different pools
+    pub fn has_routes(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    pub fn fallback_pool(&self) -> &str {
+        &self.fallback_pool
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&Pool> {
+        self.pools.iter().find(|pool| pool.name == name)
+    }
+}
+
+/// Parses a --pool argument of the form name=upstream,upstream,..., returning the pool's name and
+/// its upstreams (which are given just like --upstream arguments)
+pub fn parse_pool(pool: &str) -> Result<(String, Vec<String>), String> {
+    match pool.split_once('=') {
+        Some((name, upstreams)) if !name.is_empty() && !upstreams.is_empty() => Ok((
+            name.to_string(),
+            upstreams.split(',').map(str::to_string).collect(),
+        )),
+        _ => Err(format!(
+            "invalid pool {:?} (expected name=host:port[@weight],...)",
+            pool
+        )),
+    }
+}
+
+/// Parses a per-pool setting of the form pool=value, as given to the option `flag`
+pub fn parse_pool_setting<'a>(setting: &'a str, flag: &str) -> Result<(&'a str, &'a str), String> {
+    match setting.split_once('=') {
+        Some((pool, value)) if !pool.is_empty() && !value.is_empty() => Ok((pool, value)),
+        _ => Err(format!(
+            "invalid {} {:?} (expected pool=value)",
+            flag, setting
+        )),
+    }
+}
@@ -0,0 +1,71 @@
+//! Graceful shutdown. On SIGTERM we stop accepting connections and let the ones we already have
+//! wind down: HTTP/1.1 clients get `Connection: close` on their next response (and idle ones are
+//! hung up on), and HTTP/2 clients are sent a GOAWAY. Once every connection has finished, or
+//! --shutdown-grace-period has passed, balancebeam exits.
+
+use crate::balancer::LoadGuard;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
+
+/// How often we check whether the remaining connections have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+    /// Number of client connections that are still being handled
+    connections: Arc<AtomicUsize>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown {
+            sender,
+            receiver,
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Counts a client connection as open for as long as the guard is alive
+    pub fn track(&self) -> LoadGuard {
+        LoadGuard::acquire(&self.connections)
+    }
+
+    /// Tells every connection that we are shutting down
+    pub fn begin(&self) {
+        // The receiver we hold keeps the channel open, so this can't fail
+        let _ = self.sender.broadcast(true);
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Completes once shutdown has begun
+    pub async fn started(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    /// Number of client connections that are still being handled
+    pub fn open_connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Waits until every tracked connection has finished, or until the grace period is over.
+    /// Returns the number of connections left behind.
+    pub async fn drain(&self, grace_period: Duration) -> usize {
+        let deadline = Instant::now() + grace_period;
+        while self.open_connections() > 0 && Instant::now() < deadline {
+            time::delay_until((Instant::now() + DRAIN_POLL_INTERVAL).min(deadline)).await;
+        }
+        self.open_connections()
+    }
+}
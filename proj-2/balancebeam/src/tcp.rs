@@ -0,0 +1,195 @@
+//! Layer-4 proxying (--mode tcp). Each client connection is tied to a fresh connection to an
+//! upstream, and bytes are copied across without being parsed, so any TCP protocol (databases,
+//! Redis, ...) can be balanced. Upstreams are chosen and health-checked as in HTTP mode, except
+//! that a health check only needs to be able to connect.
+//!
+//! Since the upstream only sees balancebeam's address, it can be told the client's address with a
+//! PROXY protocol header (v1 text or v2 binary) sent at the start of the connection.
+
+use crate::hash_ring::HashKey;
+use crate::tls::MaybeTlsStream;
+use crate::{body, tunnel, ProxyState};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The protocol balancebeam proxies (--mode)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Http,
+    Tcp,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Mode::Http),
+            "tcp" => Ok(Mode::Tcp),
+            _ => Err(format!("unknown mode {:?} (expected http or tcp)", s)),
+        }
+    }
+}
+
+/// Which PROXY protocol header, if any, to send to upstreams (--proxy-protocol)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyProtocol {
+    None,
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ProxyProtocol::None),
+            "v1" => Ok(ProxyProtocol::V1),
+            "v2" => Ok(ProxyProtocol::V2),
+            _ => Err(format!(
+                "unknown PROXY protocol version {:?} (expected none, v1 or v2)",
+                s
+            )),
+        }
+    }
+}
+
+/// The signature every PROXY protocol v2 header starts with
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Turns an IPv4 address into an IPv4-mapped IPv6 address, so that it can be sent alongside an
+/// IPv6 one
+fn to_ipv6(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+        ip => ip,
+    }
+}
+
+/// Builds the PROXY protocol header telling the upstream that `source` connected to
+/// `destination`. Returns an empty header for ProxyProtocol::None.
+pub fn proxy_header(
+    version: ProxyProtocol,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    // Both addresses have to be of the same family
+    let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            (IpAddr::V4(source_ip), IpAddr::V4(destination_ip))
+        }
+        (source_ip, destination_ip) => (to_ipv6(source_ip), to_ipv6(destination_ip)),
+    };
+    match version {
+        ProxyProtocol::None => Vec::new(),
+        ProxyProtocol::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source_ip.is_ipv4() { "TCP4" } else { "TCP6" },
+            source_ip,
+            destination_ip,
+            source.port(),
+            destination.port()
+        )
+        .into_bytes(),
+        ProxyProtocol::V2 => {
+            let mut addresses = Vec::new();
+            let family = match (source_ip, destination_ip) {
+                (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                    addresses.extend_from_slice(&source_ip.octets());
+                    addresses.extend_from_slice(&destination_ip.octets());
+                    0x11 // TCP over IPv4
+                }
+                (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+                    addresses.extend_from_slice(&source_ip.octets());
+                    addresses.extend_from_slice(&destination_ip.octets());
+                    0x21 // TCP over IPv6
+                }
+                _ => unreachable!("addresses were converted to the same family"),
+            };
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command
+            header.push(0x21);
+            header.push(family);
+            header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addresses);
+            header
+        }
+    }
+}
+
+/// Relays a client connection to an upstream until both sides have closed it
+pub async fn handle_connection(
+    state: Arc<ProxyState>,
+    mut client_conn: MaybeTlsStream,
+    client_ip: String,
+) {
+    if let Some(limit) = state.rate_limit() {
+        if state.rate_limiter.check(&client_ip, limit).is_err() {
+            log::info!("Turning away {}: rate limit exceeded", client_ip);
+            state.metrics.record_rate_limited();
+            return;
+        }
+    }
+
+    let affinity_key = match state.hash_key {
+        Some(HashKey::ClientIp) => Some(client_ip.as_str()),
+        _ => None,
+    };
+    // There are no requests to route, so every connection goes to the fallback pool
+    let pool = state.router.read().await.fallback_pool().to_string();
+    let mut upstream_conn =
+        match crate::connect_to_upstream(Arc::clone(&state), &pool, affinity_key).await {
+            Ok(upstream_conn) => upstream_conn,
+            Err(error) => {
+                log::error!("No upstream available for {}: {}", client_ip, error);
+                return;
+            }
+        };
+    log::info!("{} -> {}: TCP", client_ip, upstream_conn.address);
+    // A TCP upstream sends no responses to judge it by, so accepting the connection will have to do
+    upstream_conn.breaker.record_success();
+
+    if state.proxy_protocol != ProxyProtocol::None {
+        let (source, destination) = match (client_conn.peer_addr(), client_conn.local_addr()) {
+            (Ok(source), Ok(destination)) => (source, destination),
+            (Err(error), _) | (_, Err(error)) => {
+                log::warn!(
+                    "Could not get the addresses of {}'s connection: {}",
+                    client_ip,
+                    error
+                );
+                return;
+            }
+        };
+        let header = proxy_header(state.proxy_protocol, source, destination);
+        if let Err(error) = body::write_all(&mut upstream_conn.stream, &header).await {
+            log::error!(
+                "Could not send PROXY header to upstream {}: {}",
+                upstream_conn.address,
+                error
+            );
+            return;
+        }
+    }
+
+    match tunnel::relay(
+        &mut client_conn,
+        &mut upstream_conn.stream,
+        state.tcp_idle_timeout,
+    )
+    .await
+    {
+        Ok(()) => log::debug!("TCP connection from {} closed", client_ip),
+        Err(error) => log::info!(
+            "TCP connection between {} and {} closed: {}",
+            client_ip,
+            upstream_conn.address,
+            error
+        ),
+    }
+}
@@ -0,0 +1,504 @@
+//! TLS on both sides of the proxy.
+//!
+//! Client connections are terminated with a certificate picked by the hostname the client asks for
+//! (SNI), falling back to the default certificate. Certificates can be reloaded while connections
+//! are being served: handshakes that are already underway keep the certificate they started with.
+//!
+//! Upstreams given as https://host[:port] are connected to with TLS, verifying their certificates
+//! against a CA bundle (Mozilla's roots by default) and optionally presenting a client
+//! certificate.
+
+use crate::http2;
+use crate::tcp::Mode;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert,
+    RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, TLSError, WebPKIVerifier,
+};
+use tokio_rustls::webpki::{self, DNSNameRef};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector, TlsStream};
+
+/// A certificate chain and its private key, both PEM files
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertFiles {
+    pub cert: String,
+    pub key: String,
+}
+
+/// Which certificates to serve
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Served to clients that don't use SNI, or ask for a hostname without its own certificate
+    pub default: Option<CertFiles>,
+    /// Certificates for particular hostnames. A hostname may start with `*.` to cover every
+    /// subdomain one level down.
+    pub by_hostname: Vec<(String, CertFiles)>,
+}
+
+impl Settings {
+    /// Builds the settings from the --tls-cert, --tls-key and --tls-sni-cert options, returning
+    /// None if TLS is off
+    pub fn from_options(
+        cert: Option<&String>,
+        key: Option<&String>,
+        sni_certs: &[String],
+    ) -> Result<Option<Settings>, String> {
+        let default = match (cert, key) {
+            (Some(cert), Some(key)) => Some(CertFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            (None, None) => None,
+            _ => return Err("--tls-cert and --tls-key must be given together.".to_string()),
+        };
+        let mut by_hostname = Vec::new();
+        for sni_cert in sni_certs {
+            by_hostname.push(parse_sni_cert(sni_cert)?);
+        }
+        if default.is_none() && by_hostname.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Settings {
+            default,
+            by_hostname,
+        }))
+    }
+
+    /// Returns every file the certificates are read from
+    pub fn files(&self) -> Vec<String> {
+        self.default
+            .iter()
+            .chain(self.by_hostname.iter().map(|(_, files)| files))
+            .flat_map(|files| vec![files.cert.clone(), files.key.clone()])
+            .collect()
+    }
+}
+
+/// Parses a --tls-sni-cert argument of the form hostname=cert.pem,key.pem
+fn parse_sni_cert(sni_cert: &str) -> Result<(String, CertFiles), String> {
+    let invalid = || {
+        format!(
+            "Invalid SNI certificate {:?} (expected hostname=cert.pem,key.pem)",
+            sni_cert
+        )
+    };
+    let (hostname, files) = sni_cert.split_once('=').ok_or_else(invalid)?;
+    let (cert, key) = files.split_once(',').ok_or_else(invalid)?;
+    if hostname.is_empty() || cert.is_empty() || key.is_empty() {
+        return Err(invalid());
+    }
+    Ok((
+        hostname.to_ascii_lowercase(),
+        CertFiles {
+            cert: cert.to_string(),
+            key: key.to_string(),
+        },
+    ))
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// A certificate or key file couldn't be read
+    Io(String, io::Error),
+    /// A certificate file doesn't contain any PEM certificates
+    NoCertificates(String),
+    /// A key file doesn't contain a PEM private key (PKCS#8 or RSA)
+    NoPrivateKey(String),
+    /// A CA bundle doesn't contain any usable certificates
+    NoCaCertificates(String),
+    /// The key isn't of a supported type, or doesn't go with the certificate
+    InvalidKey(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "could not read {}: {}", path, err),
+            Error::NoCertificates(path) => write!(f, "no certificates found in {}", path),
+            Error::NoPrivateKey(path) => write!(f, "no private key found in {}", path),
+            Error::NoCaCertificates(path) => write!(f, "no CA certificates found in {}", path),
+            Error::InvalidKey(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Opens a PEM file for pemfile to parse
+fn open_pem(path: &str) -> Result<BufReader<std::fs::File>, Error> {
+    std::fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| Error::Io(path.to_string(), err))
+}
+
+/// Reads a PEM certificate chain
+fn read_certs(path: &str) -> Result<Vec<Certificate>, Error> {
+    let certs = pemfile::certs(&mut open_pem(path)?)
+        .map_err(|()| Error::NoCertificates(path.to_string()))?;
+    if certs.is_empty() {
+        return Err(Error::NoCertificates(path.to_string()));
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key from a PEM file
+fn read_key(path: &str) -> Result<PrivateKey, Error> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut open_pem(path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open_pem(path)?).unwrap_or_default();
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| Error::NoPrivateKey(path.to_string()))
+}
+
+/// Reads a certificate chain and its private key
+fn load_cert(files: &CertFiles) -> Result<CertifiedKey, Error> {
+    let certs = read_certs(&files.cert)?;
+    let key = read_key(&files.key)?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|()| Error::InvalidKey(format!("unsupported private key in {}", files.key)))?;
+    let certified_key = CertifiedKey::new(certs, Arc::new(signing_key));
+    certified_key
+        .cross_check_end_entity_cert(None)
+        .map_err(|err| Error::InvalidKey(format!("{}: {}", files.cert, err)))?;
+    Ok(certified_key)
+}
+
+/// The certificates currently being served
+struct Certificates {
+    settings: Settings,
+    default: Option<CertifiedKey>,
+    by_hostname: HashMap<String, CertifiedKey>,
+}
+
+impl Certificates {
+    fn load(settings: Settings) -> Result<Certificates, Error> {
+        let default = settings.default.as_ref().map(load_cert).transpose()?;
+        let mut by_hostname = HashMap::new();
+        for (hostname, files) in &settings.by_hostname {
+            by_hostname.insert(hostname.clone(), load_cert(files)?);
+        }
+        Ok(Certificates {
+            settings,
+            default,
+            by_hostname,
+        })
+    }
+
+    /// Picks the certificate for a hostname: an exact match, then a wildcard match, then the
+    /// default
+    fn select(&self, hostname: Option<&str>) -> Option<CertifiedKey> {
+        if let Some(hostname) = hostname {
+            let hostname = hostname.to_ascii_lowercase();
+            if let Some(cert) = self.by_hostname.get(&hostname) {
+                return Some(cert.clone());
+            }
+            if let Some((_, parent)) = hostname.split_once('.') {
+                if let Some(cert) = self.by_hostname.get(&format!("*.{}", parent)) {
+                    return Some(cert.clone());
+                }
+            }
+        }
+        self.default.clone()
+    }
+}
+
+/// Hands rustls the certificate for each handshake, from whatever was loaded most recently
+struct Resolver {
+    certificates: RwLock<Certificates>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let hostname = client_hello.server_name().map(<&str>::from);
+        let cert = self.certificates.read().select(hostname);
+        if cert.is_none() {
+            log::info!("No TLS certificate for hostname {:?}", hostname);
+        }
+        cert
+    }
+}
+
+/// Accepts TLS connections from clients
+pub struct Terminator {
+    acceptor: TlsAcceptor,
+    resolver: Arc<Resolver>,
+}
+
+impl Terminator {
+    /// Loads the certificates. In HTTP mode, clients can pick HTTP/2 or HTTP/1.1 with ALPN; in TCP
+    /// mode we don't know what protocol the upstreams speak, so we don't offer any.
+    pub fn new(settings: Settings, mode: Mode) -> Result<Terminator, Error> {
+        let resolver = Arc::new(Resolver {
+            certificates: RwLock::new(Certificates::load(settings)?),
+        });
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>;
+        if mode == Mode::Http {
+            config.set_protocols(&[http2::ALPN_H2.to_vec(), b"http/1.1".to_vec()]);
+        }
+        Ok(Terminator {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            resolver,
+        })
+    }
+
+    /// Performs the TLS handshake with a client
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<server::TlsStream<TcpStream>> {
+        self.acceptor.accept(stream).await
+    }
+
+    /// Re-reads the certificates, possibly from different files. If any of them can't be loaded,
+    /// the old ones stay in place.
+    pub fn reload(&self, settings: Settings) -> Result<(), Error> {
+        let certificates = Certificates::load(settings)?;
+        *self.resolver.certificates.write() = certificates;
+        Ok(())
+    }
+
+    /// The settings the current certificates were loaded with
+    pub fn settings(&self) -> Settings {
+        self.resolver.certificates.read().settings.clone()
+    }
+}
+
+/// How closely upstream certificates are checked (--upstream-tls-verify)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verification {
+    /// The certificate must be signed by a trusted CA and be valid for the upstream's hostname
+    Full,
+    /// The certificate must be signed by a trusted CA, but may be for any hostname
+    CaOnly,
+    /// Any certificate is accepted, so the connection can be intercepted. Only meant for testing.
+    None,
+}
+
+impl FromStr for Verification {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Verification::Full),
+            "ca-only" => Ok(Verification::CaOnly),
+            "none" => Ok(Verification::None),
+            _ => Err(format!(
+                "unknown verification mode {:?} (expected full, ca-only or none)",
+                s
+            )),
+        }
+    }
+}
+
+/// Checks upstream certificates according to --upstream-tls-verify, for the modes that rustls's
+/// own verifier doesn't cover
+struct UpstreamVerifier {
+    verification: Verification,
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        if self.verification == Verification::None {
+            return Ok(ServerCertVerified::assertion());
+        }
+        // webpki checks the chain before the hostname, so a hostname mismatch means the chain
+        // itself is fine
+        match WebPKIVerifier::new().verify_server_cert(
+            roots,
+            presented_certs,
+            dns_name,
+            ocsp_response,
+        ) {
+            Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName))
+                if self.verification == Verification::CaOnly =>
+            {
+                Ok(ServerCertVerified::assertion())
+            }
+            result => result,
+        }
+    }
+}
+
+/// Where to connect to reach an upstream, parsed from its address
+#[derive(Debug, PartialEq)]
+pub struct UpstreamTarget<'a> {
+    /// host:port to open the TCP connection to
+    pub authority: String,
+    /// The hostname to verify the upstream's certificate against (and send through SNI), if the
+    /// upstream is reached over TLS
+    pub tls_hostname: Option<&'a str>,
+}
+
+impl UpstreamTarget<'_> {
+    /// Parses an upstream address: either host:port, or https://host[:port] for an upstream that
+    /// speaks TLS (on port 443 unless given). TLS upstreams must be named by hostname rather than
+    /// IP address, since that is what their certificate is checked against.
+    pub fn parse(address: &str) -> Result<UpstreamTarget<'_>, String> {
+        let host = match address.strip_prefix("https://") {
+            Some(host) => host,
+            None => {
+                return Ok(UpstreamTarget {
+                    authority: address.to_string(),
+                    tls_hostname: None,
+                })
+            }
+        };
+        let (hostname, authority) = match host.rsplit_once(':') {
+            Some((hostname, _)) => (hostname, host.to_string()),
+            None => (host, format!("{}:443", host)),
+        };
+        if DNSNameRef::try_from_ascii_str(hostname).is_err() {
+            return Err(format!(
+                "invalid upstream {} (https:// upstreams must be given by hostname)",
+                address
+            ));
+        }
+        Ok(UpstreamTarget {
+            authority,
+            tls_hostname: Some(hostname),
+        })
+    }
+}
+
+/// Opens TLS connections to upstreams
+pub struct Connector {
+    connector: TlsConnector,
+}
+
+impl Connector {
+    /// Sets up TLS for upstream connections. Certificates are checked against the CAs in
+    /// `ca_bundle`, or Mozilla's roots if it isn't given, and `client_cert` is presented to
+    /// upstreams that ask for one.
+    pub fn new(
+        ca_bundle: Option<&str>,
+        client_cert: Option<&CertFiles>,
+        verification: Verification,
+    ) -> Result<Connector, Error> {
+        let mut config = ClientConfig::new();
+        match ca_bundle {
+            Some(path) => {
+                let (valid, _) = config
+                    .root_store
+                    .add_pem_file(&mut open_pem(path)?)
+                    .map_err(|()| Error::NoCaCertificates(path.to_string()))?;
+                if valid == 0 {
+                    return Err(Error::NoCaCertificates(path.to_string()));
+                }
+            }
+            None => config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+        if let Some(files) = client_cert {
+            config
+                .set_single_client_cert(read_certs(&files.cert)?, read_key(&files.key)?)
+                .map_err(|err| Error::InvalidKey(format!("{}: {}", files.key, err)))?;
+        }
+        if verification != Verification::Full {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(UpstreamVerifier { verification }));
+        }
+        Ok(Connector {
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// Performs the TLS handshake with an upstream
+    pub async fn connect(
+        &self,
+        hostname: &str,
+        stream: TcpStream,
+    ) -> io::Result<client::TlsStream<TcpStream>> {
+        let hostname = DNSNameRef::try_from_ascii_str(hostname)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid hostname"))?;
+        self.connector.connect(hostname, stream).await
+    }
+}
+
+/// A connection from a client or to an upstream, which may or may not be using TLS
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl MaybeTlsStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            MaybeTlsStream::Plain(stream) => stream.peer_addr(),
+            MaybeTlsStream::Tls(stream) => stream.get_ref().0.peer_addr(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            MaybeTlsStream::Plain(stream) => stream.local_addr(),
+            MaybeTlsStream::Tls(stream) => stream.get_ref().0.local_addr(),
+        }
+    }
+
+    /// Whether the peer agreed to speak HTTP/2 during the TLS handshake
+    pub fn negotiated_h2(&self) -> bool {
+        match self {
+            MaybeTlsStream::Plain(_) => false,
+            MaybeTlsStream::Tls(stream) => {
+                stream.get_ref().1.get_alpn_protocol() == Some(http2::ALPN_H2)
+            }
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
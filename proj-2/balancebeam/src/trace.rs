@@ -0,0 +1,77 @@
+//! Identifying requests as they pass through balancebeam and on to the upstreams.
+//!
+//! * Every request gets an ID, which the upstream sees in X-Request-Id, the client sees echoed on
+//!   the response, and which appears in the access log and in our log lines about the request. An
+//!   ID sent by a trusted proxy (--trusted-proxy) is kept, so that one ID follows the request all
+//!   the way through; anyone else's is replaced with a new one.
+//! * A request that is part of a W3C Trace Context trace (it has a `traceparent` header) is passed
+//!   on as a child span: the trace ID and flags stay the same, but the parent ID is replaced with a
+//!   new span ID of our own. An invalid traceparent is dropped, along with its tracestate.
+
+use rand::Rng;
+
+/// Longest incoming request ID we keep
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Returns a random 128-bit ID, as 32 hex digits
+pub fn new_request_id() -> String {
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
+}
+
+/// Returns true if an incoming request ID is fit to pass on: not too long, and made of characters
+/// that can't break up a log line
+pub fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:+/=@".contains(&byte))
+}
+
+/// Returns the traceparent to send upstream for a request that arrived with `traceparent`: the
+/// same trace, with a new span of ours as the parent. Returns None if `traceparent` is invalid.
+pub fn child_traceparent(traceparent: &str) -> Option<String> {
+    // version-trace_id-parent_id-flags. Later versions may add fields after the flags.
+    let fields: Vec<&str> = traceparent.trim().splitn(5, '-').collect();
+    let (version, trace_id, parent_id, flags) = match fields.as_slice() {
+        [version, trace_id, parent_id, flags] => (*version, *trace_id, *parent_id, *flags),
+        [version, trace_id, parent_id, flags, _] if *version != "00" => {
+            (*version, *trace_id, *parent_id, *flags)
+        }
+        _ => return None,
+    };
+    let valid = is_hex(version, 2)
+        && version != "ff"
+        && is_hex(trace_id, 32)
+        && !is_zero(trace_id)
+        && is_hex(parent_id, 16)
+        && !is_zero(parent_id)
+        && is_hex(flags, 2);
+    if !valid {
+        return None;
+    }
+    // We only know how to write version 00
+    Some(format!("00-{}-{}-{}", trace_id, new_span_id(), flags))
+}
+
+/// Returns a random, non-zero 64-bit span ID, as 16 hex digits
+fn new_span_id() -> String {
+    let mut rng = rand::thread_rng();
+    loop {
+        let id: u64 = rng.gen();
+        if id != 0 {
+            return format!("{:016x}", id);
+        }
+    }
+}
+
+/// Returns true if `s` is `len` lowercase hex digits
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len
+        && s.bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn is_zero(s: &str) -> bool {
+    s.bytes().all(|byte| byte == b'0')
+}
@@ -0,0 +1,69 @@
+//! Relays bytes between a client and an upstream without looking at them. This is how every
+//! connection is handled in TCP mode, and how HTTP connections are handled once they have switched
+//! to another protocol (e.g. WebSockets) with a 101 Switching Protocols response.
+
+use crate::body;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{delay_for, timeout, Duration};
+
+/// How much we read from one side before passing it along to the other
+const BUFFER_SIZE: usize = 16384;
+
+/// Copies bytes in both directions until both sides have closed their end. When one side closes,
+/// the other side's write half is shut down so that it sees the end of the stream too. Fails with
+/// TimedOut if nothing gets through in either direction for `idle_timeout`.
+pub async fn relay(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    upstream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    idle_timeout: Option<Duration>,
+) -> io::Result<()> {
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let mut client_buffer = vec![0_u8; BUFFER_SIZE];
+    let mut upstream_buffer = vec![0_u8; BUFFER_SIZE];
+    let mut client_open = true;
+    let mut upstream_open = true;
+    while client_open || upstream_open {
+        let idle = async {
+            match idle_timeout {
+                Some(idle_timeout) => delay_for(idle_timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            bytes_read = client_read.read(&mut client_buffer), if client_open => {
+                let bytes = &client_buffer[..bytes_read?];
+                client_open = pass_along(bytes, &mut upstream_write, idle_timeout).await?;
+            }
+            bytes_read = upstream_read.read(&mut upstream_buffer), if upstream_open => {
+                let bytes = &upstream_buffer[..bytes_read?];
+                upstream_open = pass_along(bytes, &mut client_write, idle_timeout).await?;
+            }
+            _ = idle => return Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout")),
+        }
+    }
+    Ok(())
+}
+
+/// Writes bytes read from one side to the other, or shuts down the other side's write half if the
+/// read hit the end of the stream. Returns whether the reading side is still open.
+async fn pass_along(
+    bytes: &[u8],
+    dest: &mut (impl AsyncWrite + Unpin),
+    idle_timeout: Option<Duration>,
+) -> io::Result<bool> {
+    if bytes.is_empty() {
+        // The other side may already have hung up altogether, which is fine
+        let _ = dest.shutdown().await;
+        return Ok(false);
+    }
+    // A peer that stops reading counts as idle too
+    match idle_timeout {
+        Some(idle_timeout) => timeout(idle_timeout, body::write_all(dest, bytes))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "idle timeout"))??,
+        None => body::write_all(dest, bytes).await?,
+    }
+    Ok(true)
+}
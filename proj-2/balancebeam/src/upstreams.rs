@@ -0,0 +1,169 @@
+use crate::circuit::{self, Breaker};
+use crate::hash_ring::HashRing;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+/// The upstream servers we are proxying to, along with what we know about each of them. The Vecs
+/// are indexed by upstream index. Upstreams can be added and removed at runtime (through the admin
+/// API or by reloading the configuration), so an index is only meaningful while the lock on the set
+/// is held; anything that outlives the lock should refer to upstreams by address instead.
+pub struct Upstreams {
+    /// Addresses of servers that we are proxying to
+    pub addresses: Vec<String>,
+    /// Relative weight of each upstream, used by the weighted strategy
+    pub weights: Vec<usize>,
+    /// Name of the pool each upstream belongs to
+    pub pools: Vec<String>,
+    /// Number of connections to each upstream that are currently handed out to clients
+    pub outstanding: Vec<Arc<AtomicUsize>>,
+    /// Whether the upstream failed its last active health check
+    pub dead: Vec<bool>,
+    /// Circuit breaker of each upstream, which keeps requests away from it while it is failing
+    pub breakers: Vec<Arc<Breaker>>,
+    /// Whether the upstream is being drained, i.e. it gets no new connections while the existing
+    /// ones finish
+    pub draining: Vec<bool>,
+    /// Consistent-hash ring over the addresses, used when a hash key is set
+    pub hash_ring: HashRing,
+    /// Number of points each upstream gets on the hash ring
+    virtual_nodes: usize,
+    /// Settings for the circuit breakers of upstreams
+    breaker_settings: circuit::Settings,
+}
+
+impl Upstreams {
+    pub fn new(
+        addresses: Vec<String>,
+        weights: Vec<usize>,
+        pools: Vec<String>,
+        virtual_nodes: usize,
+        breaker_settings: circuit::Settings,
+    ) -> Upstreams {
+        let outstanding = (0..addresses.len())
+            .map(|_| Arc::new(AtomicUsize::new(0)))
+            .collect();
+        let dead = vec![false; addresses.len()];
+        let breakers = addresses
+            .iter()
+            .map(|address| Arc::new(Breaker::new(address.clone(), breaker_settings)))
+            .collect();
+        let draining = vec![false; addresses.len()];
+        let hash_ring = HashRing::new(&addresses, virtual_nodes);
+        Upstreams {
+            addresses,
+            weights,
+            pools,
+            outstanding,
+            dead,
+            breakers,
+            draining,
+            hash_ring,
+            virtual_nodes,
+            breaker_settings,
+        }
+    }
+
+    /// Builds the set of upstreams to switch to when the configuration is reloaded. Upstreams that
+    /// are in both sets keep their health, circuit breaker, draining flag and outstanding
+    /// connection count, so that connections handed out before the reload are still counted; new
+    /// upstreams start out alive.
+    pub fn reconfigure(
+        &self,
+        addresses: Vec<String>,
+        weights: Vec<usize>,
+        pools: Vec<String>,
+    ) -> Upstreams {
+        let mut upstreams = Upstreams::new(
+            addresses,
+            weights,
+            pools,
+            self.virtual_nodes,
+            self.breaker_settings,
+        );
+        for index in 0..upstreams.addresses.len() {
+            if let Some(old_index) = self.position(&upstreams.addresses[index]) {
+                upstreams.outstanding[index] = Arc::clone(&self.outstanding[old_index]);
+                upstreams.dead[index] = self.dead[old_index];
+                upstreams.breakers[index] = Arc::clone(&self.breakers[old_index]);
+                upstreams.draining[index] = self.draining[old_index];
+            }
+        }
+        upstreams
+    }
+
+    /// Adds an upstream to a pool. It starts out alive. Returns false if it is already in the set.
+    pub fn add(&mut self, address: String, weight: usize, pool: String) -> bool {
+        if self.position(&address).is_some() {
+            return false;
+        }
+        self.addresses.push(address.clone());
+        self.weights.push(weight);
+        self.pools.push(pool);
+        self.outstanding.push(Arc::new(AtomicUsize::new(0)));
+        self.dead.push(false);
+        self.breakers
+            .push(Arc::new(Breaker::new(address, self.breaker_settings)));
+        self.draining.push(false);
+        self.hash_ring = HashRing::new(&self.addresses, self.virtual_nodes);
+        true
+    }
+
+    /// Removes an upstream. Connections that were already handed out for it are left alone.
+    /// Returns false if it isn't in the set.
+    pub fn remove(&mut self, address: &str) -> bool {
+        let index = match self.position(address) {
+            Some(index) => index,
+            None => return false,
+        };
+        self.addresses.remove(index);
+        self.weights.remove(index);
+        self.pools.remove(index);
+        self.outstanding.remove(index);
+        self.dead.remove(index);
+        self.breakers.remove(index);
+        self.draining.remove(index);
+        self.hash_ring = HashRing::new(&self.addresses, self.virtual_nodes);
+        true
+    }
+
+    /// Returns true if new connections may be sent to the upstream, i.e. it is neither dead nor
+    /// draining, and its circuit breaker lets requests through
+    pub fn is_available(&self, index: usize) -> bool {
+        !self.dead[index] && !self.draining[index] && self.breakers[index].allows_request()
+    }
+
+    /// Returns true if the upstream belongs to the pool and may be sent new connections
+    pub fn is_available_in(&self, index: usize, pool: &str) -> bool {
+        self.pools[index] == pool && self.is_available(index)
+    }
+
+    /// Returns the index of the upstream with the given address, if it is still in the set
+    pub fn position(&self, address: &str) -> Option<usize> {
+        self.addresses
+            .iter()
+            .position(|upstream_address| upstream_address == address)
+    }
+
+    /// Returns the circuit breaker of the upstream at `address`, if it is still in the set
+    pub fn breaker(&self, address: &str) -> Option<&Arc<Breaker>> {
+        self.position(address).map(|index| &self.breakers[index])
+    }
+
+    /// Marks an upstream as dead or alive. Does nothing if the upstream has been removed.
+    pub fn set_dead(&mut self, address: &str, dead: bool) {
+        if let Some(index) = self.position(address) {
+            self.dead[index] = dead;
+        }
+    }
+
+    /// Starts or stops draining an upstream. Returns false if it isn't in the set.
+    pub fn set_draining(&mut self, address: &str, draining: bool) -> bool {
+        match self.position(address) {
+            Some(index) => {
+                self.draining[index] = draining;
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -2,6 +2,8 @@ mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, Server};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::delay_for;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -99,3 +101,41 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Make sure that balancebeam keeps upstream connections open between clients. Send several
+/// requests, each from a brand new client connection, and check that the upstream server only saw
+/// a single connection from balancebeam.
+#[tokio::test]
+async fn test_upstream_connections_reused() {
+    let n_requests = 6;
+    let (balancebeam, upstream) = setup().await;
+
+    for i in 0..n_requests {
+        log::info!("Sending request {} on a new client connection", i);
+        let path = format!("/pooled-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        // Give balancebeam a moment to notice the client hung up and return the upstream
+        // connection to the pool
+        delay_for(Duration::from_millis(100)).await;
+    }
+
+    let num_connections = upstream.connections_received();
+    log::info!(
+        "Upstream server received {} connections for {} requests",
+        num_connections,
+        n_requests
+    );
+    assert_eq!(
+        num_connections, 1,
+        "balancebeam should have reused a single upstream connection across clients"
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, n_requests);
+
+    log::info!("All done :)");
+}
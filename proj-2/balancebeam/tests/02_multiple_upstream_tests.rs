@@ -5,11 +5,7 @@ use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 use std::time::Duration;
 use tokio::time::delay_for;
 
-async fn setup_with_params(
-    n_upstreams: usize,
-    active_health_check_interval: Option<usize>,
-    max_requests_per_minute: Option<usize>,
-) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+async fn start_upstreams(n_upstreams: usize) -> (Vec<Box<dyn Server>>, Vec<String>) {
     init_logging();
     let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
     for _ in 0..n_upstreams {
@@ -19,6 +15,15 @@ async fn setup_with_params(
         .iter()
         .map(|upstream| upstream.address())
         .collect();
+    (upstreams, upstream_addresses)
+}
+
+async fn setup_with_params(
+    n_upstreams: usize,
+    active_health_check_interval: Option<usize>,
+    max_requests_per_minute: Option<usize>,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    let (upstreams, upstream_addresses) = start_upstreams(n_upstreams).await;
     let upstream_addresses: Vec<&str> = upstream_addresses
         .iter()
         .map(|addr| addr.as_str())
@@ -36,13 +41,37 @@ async fn setup(n_upstreams: usize) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     setup_with_params(n_upstreams, None, None).await
 }
 
-/// Send a bunch of requests to the load balancer, and ensure they are evenly distributed across the
-/// upstream servers
-#[tokio::test]
-async fn test_load_distribution() {
-    let n_upstreams = 3;
-    let n_requests = 90;
-    let (balancebeam, mut upstreams) = setup(n_upstreams).await;
+async fn setup_with_args(n_upstreams: usize, args: &[&str]) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    let (upstreams, upstream_addresses) = start_upstreams(n_upstreams).await;
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_addresses, args).await;
+    (balancebeam, upstreams)
+}
+
+/// Start one upstream per weight, send a bunch of requests (each on a new client connection) to a
+/// load balancer using the given strategy, and return the number of requests each upstream received
+async fn distribute_requests(strategy: &str, weights: &[usize], n_requests: usize) -> Vec<usize> {
+    let (mut upstreams, upstream_addresses) = start_upstreams(weights.len()).await;
+    let upstream_args: Vec<String> = upstream_addresses
+        .iter()
+        .zip(weights)
+        .map(|(address, weight)| format!("{}@{}", address, weight))
+        .collect();
+    let upstream_args: Vec<&str> = upstream_args.iter().map(|arg| arg.as_str()).collect();
+    // Push active health checks out of the way so that they don't show up in the request counts
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_args,
+        &[
+            "--strategy",
+            strategy,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
 
     for i in 0..n_requests {
         let path = format!("/request-{}", i);
@@ -60,22 +89,237 @@ async fn test_load_distribution() {
         request_counters.insert(0, upstream.stop().await);
     }
     log::info!(
-        "Number of requests received by each upstream: {:?}",
+        "Number of requests received by each upstream with the {} strategy: {:?}",
+        strategy,
         request_counters
     );
-    let avg_req_count =
-        request_counters.iter().sum::<usize>() as f64 / request_counters.len() as f64;
-    log::info!("Average number of requests per upstream: {}", avg_req_count);
-    for upstream_req_count in request_counters {
-        if (upstream_req_count as f64 - avg_req_count).abs() > 0.4 * avg_req_count {
+    request_counters
+}
+
+/// Check that each upstream received roughly its share of the requests, based on its weight
+fn check_distribution(request_counters: &[usize], weights: &[usize], tolerance: f64) {
+    let total_requests = request_counters.iter().sum::<usize>() as f64;
+    let total_weight = weights.iter().sum::<usize>() as f64;
+    for (&upstream_req_count, &weight) in request_counters.iter().zip(weights) {
+        let expected_req_count = total_requests * weight as f64 / total_weight;
+        if (upstream_req_count as f64 - expected_req_count).abs() > tolerance * expected_req_count {
             log::error!(
-                "Upstream request count {} differs too much from the average! Load doesn't seem \
-                evenly distributed.",
-                upstream_req_count
+                "Upstream request count {} differs too much from the expected {}! Load doesn't \
+                seem to be distributed as expected.",
+                upstream_req_count,
+                expected_req_count
             );
             panic!("Upstream request count differs too much");
         }
     }
+}
+
+/// Send a bunch of requests to the load balancer with each strategy, and ensure they are
+/// distributed across the upstream servers the way that strategy should distribute them
+#[tokio::test]
+async fn test_load_distribution() {
+    init_logging();
+    let even_weights = [1, 1, 1];
+    let n_requests = 90;
+
+    // Random, least-connections and power-of-two-choices should all spread sequential requests
+    // roughly evenly
+    for strategy in &["random", "least-connections", "p2c"] {
+        let request_counters = distribute_requests(strategy, &even_weights, n_requests).await;
+        check_distribution(&request_counters, &even_weights, 0.4);
+    }
+
+    // Round-robin should spread them exactly evenly
+    let request_counters = distribute_requests("round-robin", &even_weights, n_requests).await;
+    assert_eq!(request_counters, vec![30, 30, 30]);
+
+    // Weighted random should give each upstream a share proportional to its weight
+    let weights = [1, 2, 3];
+    let request_counters = distribute_requests("weighted", &weights, 2 * n_requests).await;
+    check_distribution(&request_counters, &weights, 0.4);
+
+    log::info!("All done :)");
+}
+
+/// With the least-connections strategy, open several client connections at once and keep them
+/// open. Each one should be sent to a different upstream, since the others are already busy.
+#[tokio::test]
+async fn test_least_connections_avoids_busy_upstreams() {
+    let n_upstreams = 3;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, &["--strategy", "least-connections"]).await;
+
+    // Each reqwest client keeps its connection to balancebeam open until it is dropped
+    let mut clients = Vec::new();
+    for i in 0..n_upstreams {
+        let client = reqwest::Client::new();
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        clients.push(client);
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(request_counters, vec![1; n_upstreams]);
+
+    log::info!("All done :)");
+}
+
+/// With per-request balancing enabled, send a bunch of requests over a single keep-alive client
+/// connection, and ensure they are still spread across all of the upstream servers
+#[tokio::test]
+async fn test_per_request_load_distribution() {
+    let n_upstreams = 3;
+    let n_requests = 60;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, &["--per-request-balancing"]).await;
+
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert_eq!(request_counters.iter().sum::<usize>(), n_requests);
+    for upstream_req_count in request_counters {
+        assert!(
+            upstream_req_count > 0,
+            "An upstream received no requests even though requests are balanced individually"
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// With per-request balancing enabled, kill an upstream in the middle of a keep-alive client
+/// connection and make sure the remaining requests on that connection still succeed
+#[tokio::test]
+async fn test_per_request_failover() {
+    let n_upstreams = 2;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, &["--per-request-balancing"]).await;
+
+    let client = reqwest::Client::new();
+    for i in 0..10 {
+        if i == 4 {
+            log::info!("Killing one of the upstream servers");
+            upstreams.pop().unwrap().stop().await;
+        }
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "balancebeam returned unexpected response. Failover may not be working."
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// Send a request carrying the given user's key, and return the address of the upstream that
+/// answered it
+async fn upstream_for_user(
+    client: &reqwest::Client,
+    balancebeam: &BalanceBeam,
+    user: usize,
+) -> String {
+    let response = client
+        .get(&format!("http://{}/user-{}", balancebeam.address, user))
+        .header("x-user", format!("user-{}", user))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    response
+        .headers()
+        .get("x-upstream-address")
+        .expect("Upstream response is missing x-upstream-address")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Route by hashing a header. Requests carrying the same key should always go to the same upstream,
+/// and when an upstream dies, only the keys that belonged to it should move elsewhere.
+#[tokio::test]
+async fn test_consistent_hash_affinity() {
+    let n_upstreams = 3;
+    let n_users = 20;
+    let (balancebeam, mut upstreams) =
+        setup_with_args(n_upstreams, &["--hash-key", "header:x-user"]).await;
+
+    let client = reqwest::Client::new();
+    log::info!("Checking that each user sticks to one upstream");
+    let mut assignments = Vec::new();
+    for user in 0..n_users {
+        let upstream = upstream_for_user(&client, &balancebeam, user).await;
+        for _ in 0..3 {
+            assert_eq!(
+                upstream_for_user(&client, &balancebeam, user).await,
+                upstream,
+                "Requests with the same key were sent to different upstreams"
+            );
+        }
+        assignments.push(upstream);
+    }
+    log::info!("Users were assigned to upstreams: {:?}", assignments);
+
+    log::info!("Killing one of the upstream servers");
+    let dead_upstream = upstreams.pop().unwrap();
+    let dead_address = dead_upstream.address();
+    dead_upstream.stop().await;
+
+    log::info!("Checking that only the dead upstream's users were moved");
+    for (user, previous_upstream) in assignments.iter().enumerate() {
+        let upstream = upstream_for_user(&client, &balancebeam, user).await;
+        if *previous_upstream == dead_address {
+            assert_ne!(upstream, dead_address);
+        } else {
+            assert_eq!(
+                &upstream, previous_upstream,
+                "A user on a healthy upstream was remapped when another upstream died"
+            );
+        }
+    }
 
     log::info!("All done :)");
 }
@@ -294,3 +538,67 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Make sure the rate limiter allows a burst of requests, tells rate limited clients when to come
+/// back, and lets them in again once they've earned another request
+#[tokio::test]
+async fn test_rate_limit_burst_and_refill() {
+    let (mut upstreams, upstream_addresses) = start_upstreams(1).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_addresses[0]],
+        &["--max-requests-per-minute", "60", "--rate-limit-burst", "3"],
+    )
+    .await;
+
+    log::info!("Sending a burst of requests over a single connection");
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/", balancebeam.address);
+    for _ in 0..3 {
+        let response = client
+            .get(&url)
+            .send()
+            .await
+            .expect("Error sending request");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .expect("Error sending request");
+    assert_eq!(response.status().as_u16(), 429);
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .unwrap_or_else(|| panic!("429 response is missing {}", name))
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap()
+    };
+    assert_eq!(header("retry-after"), 1);
+    assert_eq!(header("x-ratelimit-limit"), 3);
+    assert_eq!(header("x-ratelimit-remaining"), 0);
+    assert!(header("x-ratelimit-reset") >= 2);
+
+    log::info!("Waiting to earn another request");
+    delay_for(Duration::from_millis(1100)).await;
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .expect("Error sending request");
+    assert_eq!(response.status().as_u16(), 200);
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .expect("Error sending request");
+    assert_eq!(response.status().as_u16(), 429);
+
+    // Shut down balancebeam first, so that the upstream doesn't wait for its pooled connections
+    drop(balancebeam);
+    assert_eq!(upstreams.pop().unwrap().stop().await, 4);
+    log::info!("All done :)");
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use common::{init_logging, send_raw, BalanceBeam, ChunkedServer, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Make sure a chunked response from the upstream is decoded correctly, and that the upstream
+/// connection can be reused afterwards (i.e. we didn't wait for it to close to find the end of the
+/// body). reqwest doesn't send "TE: trailers", so the upstream's trailers should be dropped (the
+/// version of hyper it uses can't parse them).
+#[tokio::test]
+async fn test_chunked_response() {
+    init_logging();
+    let upstream = ChunkedServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    for i in 0..3 {
+        log::info!("Sending request {}", i);
+        let response_text = timeout(Duration::from_secs(5), balancebeam.get("/chunked"))
+            .await
+            .expect(
+                "balancebeam took too long to respond. Is it waiting for the upstream to hang up?",
+            )
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "Hello, world");
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// Make sure trailers sent after the last chunk are passed through to a client that accepts them
+#[tokio::test]
+async fn test_chunked_response_trailers() {
+    init_logging();
+    let upstream = ChunkedServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = send_raw(
+        &balancebeam.address,
+        "GET /trailers HTTP/1.1\r\nHost: balancebeam\r\nTE: trailers\r\n\r\n",
+        "x-checksum: abc123\r\n\r\n",
+    )
+    .await;
+    log::info!("Received response: {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("transfer-encoding: chunked\r\n"));
+    assert!(response.contains("Hello, world"));
+    assert!(
+        response.ends_with("0\r\nx-checksum: abc123\r\n\r\n"),
+        "Trailers were not forwarded after the last chunk"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Send a chunked request body, and make sure the upstream receives all of it
+#[tokio::test]
+async fn test_chunked_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = send_raw(
+        &balancebeam.address,
+        "POST /upload HTTP/1.1\r\nHost: balancebeam\r\nTransfer-Encoding: chunked\r\n\r\n\
+        6\r\nHello \r\n6;ext=1\r\nworld!\r\n0\r\n\r\n",
+        "\n\nHello world!",
+    )
+    .await;
+    log::info!("Received response: {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("POST /upload HTTP/1.1"));
+    assert!(response.contains("transfer-encoding: chunked"));
+    assert!(response.contains("\n\nHello world!"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure malformed chunk sizes are rejected, both from clients (400) and upstreams (502)
+#[tokio::test]
+async fn test_malformed_chunk_size() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    log::info!("Sending a request with an invalid chunk size");
+    let response = send_raw(
+        &balancebeam.address,
+        "POST /upload HTTP/1.1\r\nHost: balancebeam\r\nTransfer-Encoding: chunked\r\n\r\n\
+        zz\r\nHello\r\n0\r\n\r\n",
+        "HTTP 400 Bad Request",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    // Shut down balancebeam first, so that the upstream doesn't wait for it to close the idle
+    // connection it opened for the client
+    drop(balancebeam);
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 0,
+        "A request with a malformed body should not be forwarded to the upstream"
+    );
+
+    log::info!("Checking an upstream that sends an invalid chunk size");
+    let upstream = ChunkedServer::new_with_body("5\r\nHello\r\n-1\r\noops\r\n0\r\n\r\n").await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = reqwest::get(&format!("http://{}/bad-upstream", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    log::info!("All done :)");
+}
+
+/// Make sure a chunk size too big to add to the body so far is rejected, rather than overflowing
+#[tokio::test]
+async fn test_oversized_chunk_size() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = send_raw(
+        &balancebeam.address,
+        "POST /upload HTTP/1.1\r\nHost: balancebeam\r\nTransfer-Encoding: chunked\r\n\r\n\
+        1\r\na\r\nffffffffffffffff\r\n",
+        "HTTP 413 Payload Too Large",
+    )
+    .await;
+    log::info!("Received response: {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    drop(balancebeam);
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+
+    log::info!("All done :)");
+}
+
+/// Make sure the response to a HEAD request ends with its headers, even though they describe a
+/// chunked body, so that the next response on the connection isn't thrown off
+#[tokio::test]
+async fn test_chunked_head_response() {
+    init_logging();
+    // Like any server, this one sends no body in response to HEAD
+    let upstream = ChunkedServer::new_with_body("").await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = send_raw(
+        &balancebeam.address,
+        "HEAD / HTTP/1.1\r\nHost: balancebeam\r\n\r\n",
+        "\r\n\r\n",
+    )
+    .await;
+    log::info!("Received response: {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("transfer-encoding: chunked\r\n"));
+    assert!(
+        response.ends_with("\r\n\r\n") && response.matches("\r\n\r\n").count() == 1,
+        "Nothing should follow the headers"
+    );
+
+    log::info!("All done :)");
+}
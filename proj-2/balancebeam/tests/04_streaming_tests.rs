@@ -0,0 +1,152 @@
+mod common;
+
+use common::{init_logging, send_raw, BalanceBeam, ChunkedServer, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Bigger than the 10MB limit balancebeam puts on buffered bodies
+const LARGE_BODY_SIZE: usize = 12_000_000;
+
+/// Reads from the stream until `expected` has been received, returning everything that was read
+async fn read_until(stream: &mut TcpStream, expected: &str) -> String {
+    let mut received = Vec::new();
+    while !String::from_utf8_lossy(&received).contains(expected) {
+        let mut buffer = [0_u8; 512];
+        match timeout(Duration::from_secs(5), stream.read(&mut buffer)).await {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+            Ok(Ok(bytes_read)) => received.extend_from_slice(&buffer[..bytes_read]),
+        }
+    }
+    String::from_utf8_lossy(&received).to_string()
+}
+
+/// Make sure request and response bodies bigger than the buffering limit get through when
+/// streaming, while they are still rejected when buffering
+#[tokio::test]
+async fn test_stream_large_bodies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let body = "x".repeat(LARGE_BODY_SIZE);
+
+    log::info!("Sending a large request to balancebeam with buffered bodies");
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = reqwest::Client::new()
+        .post(&format!("http://{}/upload", balancebeam.address))
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 413);
+
+    log::info!("Sending a large request to balancebeam with streamed bodies");
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--stream-bodies"]).await;
+    let response_text = balancebeam
+        .post("/upload", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("POST /upload HTTP/1.1"));
+    assert!(response_text.contains(&format!("content-length: {}", LARGE_BODY_SIZE)));
+    assert!(
+        response_text.ends_with(&format!("\n\n{}", body)),
+        "The upstream did not receive the whole body, or the client did not receive the whole \
+        response"
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam passes along the start of a request body before the client has sent the
+/// rest of it
+#[tokio::test]
+async fn test_stream_partial_body() {
+    init_logging();
+    let upstream_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 65535));
+    let mut upstream = TcpListener::bind(&upstream_address)
+        .await
+        .expect("Could not bind upstream listener");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--stream-bodies", "--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let (mut upstream_conn, _) = timeout(Duration::from_secs(5), upstream.accept())
+        .await
+        .expect("balancebeam did not connect to the upstream")
+        .expect("Error accepting connection from balancebeam");
+
+    log::info!("Sending the first half of the body");
+    client
+        .write_all(b"POST /upload HTTP/1.1\r\nHost: balancebeam\r\nContent-Length: 10\r\n\r\nhello")
+        .await
+        .expect("Could not send request to balancebeam");
+    let received = read_until(&mut upstream_conn, "\r\n\r\nhello").await;
+    assert!(
+        received.ends_with("\r\n\r\nhello"),
+        "The upstream didn't receive the first half of the body before the client sent the rest \
+        (received {:?})",
+        received
+    );
+
+    log::info!("Sending the second half of the body");
+    client
+        .write_all(b"world")
+        .await
+        .expect("Could not send request to balancebeam");
+    let received = read_until(&mut upstream_conn, "world").await;
+    assert_eq!(received, "world");
+    upstream_conn
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+        .await
+        .expect("Could not send response to balancebeam");
+    let response = read_until(&mut client, "\r\n\r\nok").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nok"));
+
+    log::info!("All done :)");
+}
+
+/// Make sure chunked responses are streamed intact, with trailers only passed to clients that
+/// accept them
+#[tokio::test]
+async fn test_stream_chunked_response() {
+    init_logging();
+    let upstream = ChunkedServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &["--stream-bodies"]).await;
+
+    for i in 0..3 {
+        log::info!("Sending request {}", i);
+        let response_text = timeout(Duration::from_secs(5), balancebeam.get("/chunked"))
+            .await
+            .expect("balancebeam took too long to respond")
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "Hello, world");
+    }
+
+    let response = send_raw(
+        &balancebeam.address,
+        "GET /trailers HTTP/1.1\r\nHost: balancebeam\r\nTE: trailers\r\n\r\n",
+        "x-checksum: abc123\r\n\r\n",
+    )
+    .await;
+    log::info!("Received response: {:?}", response);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(
+        response.ends_with("\r\n\r\n5\r\nHello\r\n7\r\n, world\r\n0\r\nx-checksum: abc123\r\n\r\n"),
+        "The chunked body was not passed along intact"
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 4);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,160 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// A config file in the system's temp directory, which is deleted when dropped
+struct ConfigFile {
+    path: PathBuf,
+}
+
+impl ConfigFile {
+    fn new(extension: &str) -> ConfigFile {
+        let path = std::env::temp_dir().join(format!(
+            "balancebeam-test-{}.{}",
+            rand::thread_rng().gen::<u64>(),
+            extension
+        ));
+        ConfigFile { path }
+    }
+
+    fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    fn write(&self, contents: &str) {
+        std::fs::write(&self.path, contents).expect("Could not write config file");
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Sends a request to balancebeam, returning the address of the upstream that answered it
+async fn upstream_for_request(client: &reqwest::Client, balancebeam: &BalanceBeam) -> String {
+    let response = client
+        .get(&format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    response
+        .headers()
+        .get("x-upstream-address")
+        .expect("Upstream response is missing x-upstream-address")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Make sure upstreams are read from a TOML config file, that changes to the file are picked up
+/// without dropping open connections, and that an invalid file is ignored
+#[tokio::test]
+async fn test_config_file_reload() {
+    init_logging();
+    let first_upstream = EchoServer::new().await;
+    let second_upstream = EchoServer::new().await;
+    let config = ConfigFile::new("toml");
+    config.write(&format!(
+        "upstream = [\"{}\"]\nactive_health_check_interval = 3600\n",
+        first_upstream.address
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &["--config", config.path(), "--config-watch-interval", "1"],
+    )
+    .await;
+
+    log::info!("Sending a request before changing the config file");
+    let open_client = reqwest::Client::new();
+    assert_eq!(
+        upstream_for_request(&open_client, &balancebeam).await,
+        first_upstream.address
+    );
+
+    log::info!("Switching to the second upstream");
+    config.write(&format!(
+        "upstream = [\"{}@2\"]\nactive_health_check_interval = 3600\n",
+        second_upstream.address
+    ));
+    delay_for(Duration::from_secs(3)).await;
+    assert_eq!(
+        upstream_for_request(&reqwest::Client::new(), &balancebeam).await,
+        second_upstream.address,
+        "New connections should go to the upstream from the reloaded config"
+    );
+    assert_eq!(
+        upstream_for_request(&open_client, &balancebeam).await,
+        first_upstream.address,
+        "The connection that was open before the reload should still go to the old upstream"
+    );
+
+    log::info!("Writing an invalid config file");
+    config.write("upstream = [\"127.0.0.1:1@0\"]\n");
+    delay_for(Duration::from_secs(3)).await;
+    assert_eq!(
+        upstream_for_request(&reqwest::Client::new(), &balancebeam).await,
+        second_upstream.address,
+        "balancebeam should keep using the old config when the new one is invalid"
+    );
+
+    // Shut down balancebeam first, so that the upstreams don't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(first_upstream).stop().await;
+    Box::new(second_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure a YAML config file is reloaded on SIGHUP
+#[tokio::test]
+async fn test_config_reload_on_sighup() {
+    init_logging();
+    let first_upstream = EchoServer::new().await;
+    let second_upstream = EchoServer::new().await;
+    let config = ConfigFile::new("yaml");
+    config.write(&format!(
+        "upstream:\n  - \"{}\"\nactive_health_check_interval: 3600\n",
+        first_upstream.address
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &["--config", config.path(), "--config-watch-interval", "0"],
+    )
+    .await;
+    assert_eq!(
+        upstream_for_request(&reqwest::Client::new(), &balancebeam).await,
+        first_upstream.address
+    );
+
+    log::info!("Changing the config file without sending SIGHUP");
+    config.write(&format!(
+        "upstream:\n  - \"{}\"\nactive_health_check_interval: 3600\n",
+        second_upstream.address
+    ));
+    delay_for(Duration::from_secs(2)).await;
+    assert_eq!(
+        upstream_for_request(&reqwest::Client::new(), &balancebeam).await,
+        first_upstream.address,
+        "The config file should not be reloaded until balancebeam gets SIGHUP"
+    );
+
+    log::info!("Sending SIGHUP");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    delay_for(Duration::from_secs(1)).await;
+    assert_eq!(
+        upstream_for_request(&reqwest::Client::new(), &balancebeam).await,
+        second_upstream.address
+    );
+
+    // Shut down balancebeam first, so that the upstreams don't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(first_upstream).stop().await;
+    Box::new(second_upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,290 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+
+/// Starts balancebeam with the admin API enabled, returning it along with the admin API's address
+async fn setup(upstreams: &[&str]) -> (BalanceBeam, String) {
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        upstreams,
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    (balancebeam, admin_address)
+}
+
+/// Sends a request to the admin API, returning the status code and the parsed JSON body
+async fn admin_request(
+    method: reqwest::Method,
+    admin_address: &str,
+    path: &str,
+    body: &str,
+) -> (u16, serde_json::Value) {
+    let response = reqwest::Client::new()
+        .request(method, &format!("http://{}{}", admin_address, path))
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    let status = response.status().as_u16();
+    let text = response
+        .text()
+        .await
+        .expect("Error reading admin API response");
+    let json = serde_json::from_str(&text).expect("Admin API response is not valid JSON");
+    (status, json)
+}
+
+/// Returns the upstream addresses from an admin API response listing the upstreams
+fn listed_addresses(list: &serde_json::Value) -> Vec<&str> {
+    list.as_array()
+        .expect("Admin API response is not a list of upstreams")
+        .iter()
+        .map(|upstream| upstream["address"].as_str().unwrap())
+        .collect()
+}
+
+/// Sends a request to balancebeam, returning the address of the upstream that answered it
+async fn upstream_for_request(client: &reqwest::Client, balancebeam: &BalanceBeam) -> String {
+    let response = client
+        .get(&format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    response
+        .headers()
+        .get("x-upstream-address")
+        .expect("Upstream response is missing x-upstream-address")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// Make sure upstreams can be listed, added and removed through the admin API
+#[tokio::test]
+async fn test_admin_add_remove_upstreams() {
+    init_logging();
+    let first_upstream = EchoServer::new().await;
+    let second_upstream = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&first_upstream.address]).await;
+
+    let (status, list) =
+        admin_request(reqwest::Method::GET, &admin_address, "/upstreams", "").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        listed_addresses(&list),
+        vec![first_upstream.address.as_str()]
+    );
+    assert_eq!(list[0]["dead"], false);
+    assert_eq!(list[0]["draining"], false);
+
+    log::info!("Adding the second upstream");
+    let new_upstream = format!(
+        "{{\"address\": \"{}\", \"weight\": 2}}",
+        second_upstream.address
+    );
+    let (status, list) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        "/upstreams",
+        &new_upstream,
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert_eq!(
+        listed_addresses(&list),
+        vec![
+            first_upstream.address.as_str(),
+            second_upstream.address.as_str()
+        ]
+    );
+    assert_eq!(list[1]["weight"], 2);
+    let (status, _) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        "/upstreams",
+        &new_upstream,
+    )
+    .await;
+    assert_eq!(status, 409, "Adding an upstream twice should be rejected");
+
+    log::info!("Removing the first upstream");
+    let path = format!("/upstreams/{}", first_upstream.address);
+    let (status, list) = admin_request(reqwest::Method::DELETE, &admin_address, &path, "").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        listed_addresses(&list),
+        vec![second_upstream.address.as_str()]
+    );
+    let client = reqwest::Client::new();
+    for _ in 0..5 {
+        assert_eq!(
+            upstream_for_request(&client, &balancebeam).await,
+            second_upstream.address
+        );
+    }
+
+    let (status, _) = admin_request(reqwest::Method::DELETE, &admin_address, &path, "").await;
+    assert_eq!(status, 404, "Removing an unknown upstream should fail");
+    let path = format!("/upstreams/{}", second_upstream.address);
+    let (status, _) = admin_request(reqwest::Method::DELETE, &admin_address, &path, "").await;
+    assert_eq!(status, 409, "Removing the last upstream should be rejected");
+
+    // Shut down balancebeam first, so that the upstreams don't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(first_upstream).stop().await;
+    Box::new(second_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure a draining upstream gets no new connections, while connections that are already open
+/// keep working
+#[tokio::test]
+async fn test_admin_drain_upstream() {
+    init_logging();
+    let first_upstream = EchoServer::new().await;
+    let second_upstream = EchoServer::new().await;
+    let (balancebeam, admin_address) =
+        setup(&[&first_upstream.address, &second_upstream.address]).await;
+
+    let open_client = reqwest::Client::new();
+    let drained_address = upstream_for_request(&open_client, &balancebeam).await;
+    let other_address = if drained_address == first_upstream.address {
+        &second_upstream.address
+    } else {
+        &first_upstream.address
+    };
+
+    log::info!("Draining {}", drained_address);
+    let path = format!("/upstreams/{}/drain", drained_address);
+    let (status, list) = admin_request(reqwest::Method::POST, &admin_address, &path, "").await;
+    assert_eq!(status, 200);
+    let drained = list
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|upstream| upstream["address"] == drained_address.as_str())
+        .unwrap();
+    assert_eq!(drained["draining"], true);
+    for _ in 0..5 {
+        assert_eq!(
+            &upstream_for_request(&reqwest::Client::new(), &balancebeam).await,
+            other_address,
+            "A draining upstream should not get new connections"
+        );
+    }
+    assert_eq!(
+        upstream_for_request(&open_client, &balancebeam).await,
+        drained_address,
+        "Connections to a draining upstream should keep working"
+    );
+
+    log::info!("Undraining {}", drained_address);
+    let (status, list) = admin_request(reqwest::Method::DELETE, &admin_address, &path, "").await;
+    assert_eq!(status, 200);
+    assert!(list
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|upstream| upstream["draining"] == false));
+
+    // Shut down balancebeam first, so that the upstreams don't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(first_upstream).stop().await;
+    Box::new(second_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure https:// upstreams, whose addresses have slashes in them, can be drained and removed,
+/// with or without percent-encoding the address
+#[tokio::test]
+async fn test_admin_https_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (balancebeam, admin_address) = setup(&[&upstream.address]).await;
+    let https_address = "https://localhost:8443";
+    let encoded_address = "https%3A%2F%2Flocalhost%3A8443";
+
+    log::info!("Adding {}", https_address);
+    let (status, list) = admin_request(
+        reqwest::Method::POST,
+        &admin_address,
+        "/upstreams",
+        &format!("{{\"address\": \"{}\"}}", https_address),
+    )
+    .await;
+    assert_eq!(status, 201);
+    assert!(listed_addresses(&list).contains(&https_address));
+
+    log::info!("Draining and undraining {}", https_address);
+    let path = format!("/upstreams/{}/drain", encoded_address);
+    let (status, list) = admin_request(reqwest::Method::POST, &admin_address, &path, "").await;
+    assert_eq!(status, 200);
+    let added = list
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|upstream| upstream["address"] == https_address)
+        .unwrap();
+    assert_eq!(added["draining"], true);
+    let path = format!("/upstreams/{}/drain", https_address);
+    let (status, list) = admin_request(reqwest::Method::DELETE, &admin_address, &path, "").await;
+    assert_eq!(status, 200);
+    assert!(list
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|upstream| upstream["draining"] == false));
+
+    log::info!("Removing {}", https_address);
+    let path = format!("/upstreams/{}", encoded_address);
+    let (status, list) = admin_request(reqwest::Method::DELETE, &admin_address, &path, "").await;
+    assert_eq!(status, 200);
+    assert_eq!(listed_addresses(&list), vec![upstream.address.as_str()]);
+
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure the admin API holds clients to --client-header-timeout, like the proxy does
+#[tokio::test]
+async fn test_admin_header_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--client-header-timeout",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Sending half of an admin API request and then stalling");
+    let response = common::send_raw(
+        &admin_address,
+        "GET /upstreams HTTP/1.1\r\nHost: example.com\r\n",
+        "\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 408"),
+        "Expected a 408 response, got {:?}",
+        response
+    );
+
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use rand::Rng;
+
+/// Fetches the metrics from the admin API
+async fn fetch_metrics(admin_address: &str) -> String {
+    let response = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error fetching metrics");
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    response.text().await.expect("Error reading metrics")
+}
+
+/// Returns the value of the sample with the given name and labels, panicking if it's missing
+fn sample(metrics: &str, name_and_labels: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            if series == name_and_labels {
+                value.parse().ok()
+            } else {
+                None
+            }
+        })
+        .unwrap_or_else(|| panic!("Metric {} is missing from:\n{}", name_and_labels, metrics))
+}
+
+/// Make sure requests, responses, latencies, health checks and rate limiting show up in the
+/// Prometheus metrics
+#[tokio::test]
+async fn test_metrics() {
+    init_logging();
+    let good_upstream = EchoServer::new().await;
+    let bad_upstream = ErrorServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&good_upstream.address, &bad_upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "1",
+            "--max-requests-per-minute",
+            "5",
+            "--per-request-balancing",
+        ],
+    )
+    .await;
+
+    // Let at least one round of health checks run, which takes the failing upstream out of the
+    // rotation
+    tokio::time::delay_for(std::time::Duration::from_secs(2)).await;
+
+    log::info!("Sending requests");
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        let response = client
+            .get(&format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    log::info!("Exceeding the rate limit");
+    let mut rate_limited = 0;
+    for _ in 0..5 {
+        let response = reqwest::Client::new()
+            .get(&format!("http://{}/", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        if response.status().as_u16() == 429 {
+            rate_limited += 1;
+        }
+    }
+    assert!(
+        rate_limited > 0,
+        "Some requests should have been rate limited"
+    );
+
+    let metrics = fetch_metrics(&admin_address).await;
+    log::debug!("Metrics:\n{}", metrics);
+    let good = &good_upstream.address;
+    let bad = &bad_upstream.address;
+    assert!(
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_upstream_requests_total{{upstream=\"{}\"}}",
+                good
+            )
+        ) >= 3.0
+    );
+    assert!(
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_upstream_responses_total{{upstream=\"{}\",code=\"2xx\"}}",
+                good
+            )
+        ) >= 3.0
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_upstream_response_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}}",
+                good
+            )
+        ),
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_upstream_response_seconds_count{{upstream=\"{}\"}}",
+                good
+            )
+        )
+    );
+    assert!(
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_health_checks_total{{upstream=\"{}\",result=\"passed\"}}",
+                good
+            )
+        ) >= 1.0
+    );
+    assert!(
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_health_checks_total{{upstream=\"{}\",result=\"failed\"}}",
+                bad
+            )
+        ) >= 1.0
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!("balancebeam_upstream_up{{upstream=\"{}\"}}", good)
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!("balancebeam_upstream_up{{upstream=\"{}\"}}", bad)
+        ),
+        0.0
+    );
+    assert_eq!(
+        sample(
+            &metrics,
+            &format!(
+                "balancebeam_upstream_circuit_state{{upstream=\"{}\",state=\"closed\"}}",
+                good
+            )
+        ),
+        1.0
+    );
+    assert_eq!(
+        sample(&metrics, "balancebeam_rate_limited_total"),
+        rate_limited as f64
+    );
+    assert!(sample(&metrics, "balancebeam_responses_total{code=\"2xx\"}") >= 3.0);
+    assert_eq!(
+        sample(&metrics, "balancebeam_responses_total{code=\"4xx\"}"),
+        rate_limited as f64
+    );
+    // The keep-alive client's connection is still open
+    assert!(sample(&metrics, "balancebeam_client_connections") >= 1.0);
+
+    // Shut down balancebeam first, so that the upstreams don't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(good_upstream).stop().await;
+    Box::new(bad_upstream).stop().await;
+    log::info!("All done :)");
+}
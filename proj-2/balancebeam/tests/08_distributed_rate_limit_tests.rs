@@ -0,0 +1,89 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Sends a request to balancebeam, returning the status code
+async fn send_request(client: &reqwest::Client, balancebeam: &BalanceBeam) -> u16 {
+    client
+        .get(&format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Returns the address of a UDP port that is free right now, for balancebeam to gossip on. The
+/// OS hands out ephemeral ports in turn, so it won't give this one to anyone else straight away.
+fn free_udp_address() -> String {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").expect("Could not bind a UDP socket");
+    socket.local_addr().unwrap().to_string()
+}
+
+/// Make sure instances gossiping with each other hold a client to the limit across all of them
+#[tokio::test]
+async fn test_gossip_shares_rate_limit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let first_gossip = free_udp_address();
+    let second_gossip = free_udp_address();
+    // One request every 10 seconds after a burst of 4, so that nothing refills during the test
+    let limit_args = ["--max-requests-per-minute", "6", "--rate-limit-burst", "4"];
+    let first = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            &limit_args[..],
+            &[
+                "--rate-limit-gossip-bind",
+                &first_gossip,
+                "--rate-limit-peer",
+                &second_gossip,
+            ],
+        ]
+        .concat(),
+    )
+    .await;
+    let second = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            &limit_args[..],
+            &[
+                "--rate-limit-gossip-bind",
+                &second_gossip,
+                "--rate-limit-peer",
+                &first_gossip,
+            ],
+        ]
+        .concat(),
+    )
+    .await;
+
+    log::info!("Using up most of the burst at the first instance");
+    let client = reqwest::Client::new();
+    for _ in 0..3 {
+        assert_eq!(send_request(&client, &first).await, 200);
+    }
+    delay_for(Duration::from_millis(500)).await;
+
+    log::info!("Sending requests to the second instance");
+    assert_eq!(send_request(&client, &second).await, 200);
+    assert_eq!(
+        send_request(&client, &second).await,
+        429,
+        "The second instance should count the requests made at the first"
+    );
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(
+        send_request(&client, &first).await,
+        429,
+        "The first instance should count the request made at the second"
+    );
+
+    // Shut down balancebeam first, so that the upstream doesn't wait for its pooled connections
+    drop(first);
+    drop(second);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
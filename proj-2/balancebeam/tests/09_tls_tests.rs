@@ -0,0 +1,133 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TestCert};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+use tokio_rustls::rustls::{Certificate, ClientConfig, Session};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+/// Sends a request to balancebeam over TLS, asking for `hostname` through SNI and trusting only
+/// the given certificates. Returns the certificate balancebeam presented and the response head.
+async fn tls_get(address: &str, hostname: &str, trusted: &[&TestCert]) -> (Vec<u8>, String) {
+    let mut config = ClientConfig::new();
+    for cert in trusted {
+        config
+            .root_store
+            .add(&Certificate(cert.der.clone()))
+            .expect("Could not trust certificate");
+    }
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut stream = connector
+        .connect(DNSNameRef::try_from_ascii_str(hostname).unwrap(), stream)
+        .await
+        .expect("TLS handshake with balancebeam failed");
+    let presented = stream.get_ref().1.get_peer_certificates().unwrap()[0]
+        .0
+        .clone();
+
+    let request = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", hostname);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.flush().await.unwrap();
+    let mut response = Vec::new();
+    let mut buf = [0_u8; 1024];
+    while !String::from_utf8_lossy(&response).contains("\r\n\r\n") {
+        let bytes_read = stream.read(&mut buf).await.expect("Error reading response");
+        assert!(bytes_read > 0, "balancebeam hung up before responding");
+        response.extend_from_slice(&buf[..bytes_read]);
+    }
+    (presented, String::from_utf8_lossy(&response).to_string())
+}
+
+/// Make sure balancebeam terminates TLS, picking the certificate by the hostname the client asks
+/// for
+#[tokio::test]
+async fn test_tls_termination_with_sni() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let default_cert = TestCert::new("default.test");
+    let api_cert = TestCert::new("api.test");
+    let wildcard_cert = TestCert::new("*.apps.test");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            default_cert.cert_path(),
+            "--tls-key",
+            default_cert.key_path(),
+            "--tls-sni-cert",
+            &api_cert.sni_arg("api.test"),
+            "--tls-sni-cert",
+            &wildcard_cert.sni_arg("*.apps.test"),
+        ],
+    )
+    .await;
+    let trusted = [&default_cert, &api_cert, &wildcard_cert];
+
+    for (hostname, expected_cert) in &[
+        ("default.test", &default_cert),
+        ("api.test", &api_cert),
+        ("shop.apps.test", &wildcard_cert),
+    ] {
+        log::info!("Connecting with SNI hostname {}", hostname);
+        let (presented, response) = tls_get(&balancebeam.address, hostname, &trusted).await;
+        assert!(
+            presented == expected_cert.der,
+            "balancebeam presented the wrong certificate for {}",
+            hostname
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    }
+
+    log::info!("Making sure cleartext HTTP is refused");
+    assert!(balancebeam.get("/").await.is_err());
+
+    // Shut down balancebeam first, so that the upstream doesn't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure certificates are reloaded when their files change, without a restart
+#[tokio::test]
+async fn test_tls_certificate_reload() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut cert = TestCert::new("reload.test");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert.cert_path(),
+            "--tls-key",
+            cert.key_path(),
+            "--config-watch-interval",
+            "1",
+        ],
+    )
+    .await;
+    let old_der = cert.der.clone();
+    let (presented, _) = tls_get(&balancebeam.address, "reload.test", &[&cert]).await;
+    assert!(presented == old_der);
+
+    log::info!("Replacing the certificate");
+    cert.regenerate("reload.test");
+    delay_for(Duration::from_secs(3)).await;
+    let (presented, response) = tls_get(&balancebeam.address, "reload.test", &[&cert]).await;
+    assert!(
+        presented == cert.der,
+        "balancebeam should serve the new certificate"
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+    // Shut down balancebeam first, so that the upstream doesn't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
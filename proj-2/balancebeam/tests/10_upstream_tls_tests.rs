@@ -0,0 +1,196 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, TestCert};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientCertVerifier, NoClientAuth, RootCertStore,
+    ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+
+const RESPONSE_BODY: &str = "hello over tls";
+
+/// An upstream that only speaks HTTPS, answering every request with RESPONSE_BODY. It is shut
+/// down along with the test's runtime.
+struct TlsUpstream {
+    address: String,
+}
+
+impl TlsUpstream {
+    /// Starts serving `cert`. If `client_ca` is given, clients must present a certificate signed by
+    /// it.
+    async fn new(cert: &TestCert, client_ca: Option<&TestCert>) -> TlsUpstream {
+        let verifier: Arc<dyn ClientCertVerifier> = match client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(&Certificate(client_ca.der.clone())).unwrap();
+                AllowAnyAuthenticatedClient::new(roots)
+            }
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(verifier);
+        let certs = pemfile::certs(&mut BufReader::new(File::open(cert.cert_path()).unwrap()));
+        let keys =
+            pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(cert.key_path()).unwrap()));
+        config
+            .set_single_cert(certs.unwrap(), keys.unwrap().remove(0))
+            .expect("Invalid test certificate");
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(Ok(stream)) = incoming.next().await {
+                tokio::spawn(TlsUpstream::serve(acceptor.clone(), stream));
+            }
+        });
+        // Connect by name, so that the certificate can be checked against it
+        TlsUpstream {
+            address: format!("https://localhost:{}", port),
+        }
+    }
+
+    async fn serve(acceptor: TlsAcceptor, stream: TcpStream) {
+        let mut stream = match acceptor.accept(stream).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::info!("Upstream TLS handshake failed: {}", err);
+                return;
+            }
+        };
+        let mut request = Vec::new();
+        let mut buf = [0_u8; 1024];
+        loop {
+            // The requests we get have no bodies, so each one ends with an empty line
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(bytes_read) => request.extend_from_slice(&buf[..bytes_read]),
+                }
+            }
+            request.clear();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                RESPONSE_BODY.len(),
+                RESPONSE_BODY
+            );
+            if stream.write_all(response.as_bytes()).await.is_err() || stream.flush().await.is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+/// Make sure requests are forwarded to https:// upstreams, and that their certificates are checked
+/// against the CA bundle (or not, depending on --upstream-tls-verify)
+#[tokio::test]
+async fn test_https_upstream_verification() {
+    init_logging();
+    let cert = TestCert::new("localhost");
+    let upstream = TlsUpstream::new(&cert, None).await;
+    let wrong_name_cert = TestCert::new("someone-else.test");
+    let wrong_name_upstream = TlsUpstream::new(&wrong_name_cert, None).await;
+
+    for (upstream, args, should_succeed) in &[
+        // The upstream's certificate isn't signed by any of Mozilla's CAs
+        (&upstream, vec![], false),
+        (
+            &upstream,
+            vec!["--upstream-ca-bundle", cert.cert_path()],
+            true,
+        ),
+        (&upstream, vec!["--upstream-tls-verify", "none"], true),
+        (
+            &wrong_name_upstream,
+            vec!["--upstream-ca-bundle", wrong_name_cert.cert_path()],
+            false,
+        ),
+        (
+            &wrong_name_upstream,
+            vec![
+                "--upstream-ca-bundle",
+                wrong_name_cert.cert_path(),
+                "--upstream-tls-verify",
+                "ca-only",
+            ],
+            true,
+        ),
+    ] {
+        log::info!("Starting balancebeam with {:?}", args);
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], args).await;
+        let response = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        if *should_succeed {
+            assert_eq!(response, RESPONSE_BODY);
+        } else {
+            assert!(response.contains("502"), "{}", response);
+        }
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam presents its client certificate to upstreams that require one
+#[tokio::test]
+async fn test_https_upstream_client_certificate() {
+    init_logging();
+    let cert = TestCert::new("localhost");
+    let client_cert = TestCert::new("balancebeam.test");
+    let upstream = TlsUpstream::new(&cert, Some(&client_cert)).await;
+
+    log::info!("Connecting without a client certificate");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--upstream-ca-bundle", cert.cert_path()],
+    )
+    .await;
+    let response = balancebeam.get("/").await.unwrap();
+    assert!(response.contains("502"), "{}", response);
+    drop(balancebeam);
+
+    log::info!("Connecting with a client certificate");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-ca-bundle",
+            cert.cert_path(),
+            "--upstream-tls-cert",
+            client_cert.cert_path(),
+            "--upstream-tls-key",
+            client_cert.key_path(),
+        ],
+    )
+    .await;
+    assert_eq!(balancebeam.get("/").await.unwrap(), RESPONSE_BODY);
+    log::info!("All done :)");
+}
+
+/// Make sure active health checks reach https:// upstreams over TLS, rather than marking them dead
+#[tokio::test]
+async fn test_https_upstream_health_check() {
+    init_logging();
+    let cert = TestCert::new("localhost");
+    let upstream = TlsUpstream::new(&cert, None).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--upstream-ca-bundle",
+            cert.cert_path(),
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+    tokio::time::delay_for(std::time::Duration::from_secs(2)).await;
+    assert_eq!(balancebeam.get("/").await.unwrap(), RESPONSE_BODY);
+    log::info!("All done :)");
+}
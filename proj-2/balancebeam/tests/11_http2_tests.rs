@@ -0,0 +1,121 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TestCert};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{Certificate, ClientConfig, Session};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+/// Make sure a cleartext client can speak HTTP/2 with prior knowledge, and that its streams reach
+/// the upstream as HTTP/1.1 requests
+#[tokio::test]
+async fn test_h2c_prior_knowledge() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+
+    log::info!("Sending several requests at once over one connection");
+    let requests: Vec<_> = (0..5)
+        .map(|i| {
+            let request = hyper::Request::builder()
+                .method("POST")
+                .uri(format!("http://{}/stream/{}", balancebeam.address, i))
+                .header("cookie", "a=1")
+                .header("cookie", "b=2")
+                .body(hyper::Body::from(format!("body of stream {}", i)))
+                .unwrap();
+            tokio::spawn(client.request(request))
+        })
+        .collect();
+    for (i, request) in requests.into_iter().enumerate() {
+        let response = request
+            .await
+            .unwrap()
+            .expect("Error sending HTTP/2 request to balancebeam");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        log::info!("Upstream saw:\n{}", body);
+        assert!(
+            body.starts_with(&format!("POST /stream/{} HTTP/1.1\n", i)),
+            "{}",
+            body
+        );
+        assert!(body.contains("cookie: a=1; b=2\n"), "{}", body);
+        assert!(
+            body.ends_with(&format!("\nbody of stream {}", i)),
+            "{}",
+            body
+        );
+    }
+
+    log::info!("Making sure HTTP/1.1 clients still work");
+    assert!(balancebeam
+        .get("/")
+        .await
+        .unwrap()
+        .starts_with("GET / HTTP/1.1"));
+
+    // Shut down balancebeam first, so that the upstream doesn't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure clients that negotiate h2 through ALPN get HTTP/2 over TLS
+#[tokio::test]
+async fn test_h2_over_tls() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let cert = TestCert::new("h2.test");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--tls-cert", cert.cert_path(), "--tls-key", cert.key_path()],
+    )
+    .await;
+
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add(&Certificate(cert.der.clone()))
+        .unwrap();
+    config.set_protocols(&[b"h2".to_vec()]);
+    let stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(DNSNameRef::try_from_ascii_str("h2.test").unwrap(), stream)
+        .await
+        .expect("TLS handshake with balancebeam failed");
+    assert_eq!(stream.get_ref().1.get_alpn_protocol(), Some(&b"h2"[..]));
+
+    let (mut client, connection) = h2::client::handshake(stream)
+        .await
+        .expect("HTTP/2 handshake with balancebeam failed");
+    tokio::spawn(connection);
+    let request = http::Request::builder()
+        .uri("https://h2.test/over-tls")
+        .body(())
+        .unwrap();
+    let (response, _) = client.send_request(request, true).unwrap();
+    let response = response.await.expect("Error reading HTTP/2 response");
+    assert_eq!(response.status(), 200);
+    let mut body = response.into_body();
+    let mut text = Vec::new();
+    while let Some(data) = body.data().await {
+        text.extend_from_slice(&data.unwrap());
+    }
+    let text = String::from_utf8_lossy(&text);
+    log::info!("Upstream saw:\n{}", text);
+    assert!(text.starts_with("GET /over-tls HTTP/1.1\n"), "{}", text);
+    // The :authority pseudo-header becomes the Host header
+    assert!(text.contains("host: h2.test\n"), "{}", text);
+
+    // Shut down balancebeam first, so that the upstream doesn't wait for its pooled connections
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::{delay_for, timeout};
+use tokio_tungstenite::tungstenite::Message;
+
+/// Starts a WebSocket server that echoes every message back, returning its address
+async fn start_websocket_echo_server() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut websocket = match tokio_tungstenite::accept_async(stream).await {
+                    Ok(websocket) => websocket,
+                    Err(err) => {
+                        log::info!("WebSocket handshake with balancebeam failed: {}", err);
+                        return;
+                    }
+                };
+                while let Some(Ok(message)) = websocket.next().await {
+                    if (message.is_text() || message.is_binary())
+                        && websocket.send(message).await.is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Make sure WebSocket connections are passed through after the upstream switches protocols, with
+/// and without streamed bodies
+#[tokio::test]
+async fn test_websocket_passthrough() {
+    init_logging();
+    let upstream_address = start_websocket_echo_server().await;
+    for args in &[vec![], vec!["--stream-bodies"]] {
+        log::info!("Starting balancebeam with {:?}", args);
+        let mut all_args = vec!["--active-health-check-interval", "3600"];
+        all_args.extend(args);
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream_address], &all_args).await;
+
+        let (mut websocket, response) =
+            tokio_tungstenite::connect_async(format!("ws://{}/echo", balancebeam.address))
+                .await
+                .expect("WebSocket handshake through balancebeam failed");
+        assert_eq!(response.status(), 101);
+        for i in 0..3 {
+            let message = Message::Text(format!("message {}", i));
+            websocket.send(message.clone()).await.unwrap();
+            let echoed = timeout(Duration::from_secs(5), websocket.next())
+                .await
+                .expect("Timed out waiting for the echo")
+                .expect("Connection closed before the echo arrived")
+                .unwrap();
+            assert_eq!(echoed, message);
+        }
+        let message = Message::Binary(vec![0, 1, 2, 255]);
+        websocket.send(message.clone()).await.unwrap();
+        assert_eq!(websocket.next().await.unwrap().unwrap(), message);
+
+        log::info!("Closing the WebSocket");
+        websocket.close(None).await.unwrap();
+        let reply = timeout(Duration::from_secs(5), websocket.next())
+            .await
+            .expect("Timed out waiting for the upstream to close the WebSocket");
+        assert!(
+            matches!(reply, Some(Ok(Message::Close(_))) | None),
+            "{:?}",
+            reply
+        );
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure upgraded connections are closed once they have been idle for too long
+#[tokio::test]
+async fn test_upgrade_idle_timeout() {
+    init_logging();
+    let upstream_address = start_websocket_echo_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--active-health-check-interval",
+            "3600",
+            "--upgrade-idle-timeout",
+            "1",
+        ],
+    )
+    .await;
+    let (mut websocket, _) =
+        tokio_tungstenite::connect_async(format!("ws://{}/echo", balancebeam.address))
+            .await
+            .expect("WebSocket handshake through balancebeam failed");
+    websocket
+        .send(Message::Text("hi".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        websocket.next().await.unwrap().unwrap(),
+        Message::Text("hi".to_string())
+    );
+
+    log::info!("Going quiet");
+    delay_for(Duration::from_secs(2)).await;
+    let next = timeout(Duration::from_secs(5), websocket.next())
+        .await
+        .expect("balancebeam should have closed the idle connection");
+    assert!(
+        !matches!(next, Some(Ok(Message::Text(_)))),
+        "Expected the connection to be closed, got {:?}",
+        next
+    );
+    log::info!("All done :)");
+}
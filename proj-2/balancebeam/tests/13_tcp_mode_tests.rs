@@ -0,0 +1,231 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, TestCert};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{delay_for, timeout};
+use tokio_rustls::rustls::{Certificate, ClientConfig, Session};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+/// A TCP server that echoes back whatever it is sent, counting its connections
+struct TcpEchoServer {
+    address: String,
+    connections: Arc<AtomicUsize>,
+}
+
+impl TcpEchoServer {
+    async fn new() -> TcpEchoServer {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&connections);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buffer = [0_u8; 1024];
+                    while let Ok(bytes_read) = stream.read(&mut buffer).await {
+                        if bytes_read == 0 || stream.write_all(&buffer[..bytes_read]).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        TcpEchoServer {
+            address,
+            connections,
+        }
+    }
+}
+
+/// Sends a message through balancebeam on a new connection and returns what comes back
+async fn echo_through(address: &str, message: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(message).await.unwrap();
+    let mut reply = vec![0_u8; message.len()];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut reply))
+        .await
+        .expect("Timed out waiting for the echo")
+        .expect("balancebeam hung up before echoing");
+    reply
+}
+
+/// Make sure raw TCP connections are relayed and balanced across the upstreams
+#[tokio::test]
+async fn test_tcp_mode_relays_connections() {
+    init_logging();
+    let upstreams = vec![TcpEchoServer::new().await, TcpEchoServer::new().await];
+    let upstream_addresses: Vec<&str> = upstreams.iter().map(|u| u.address.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--mode",
+            "tcp",
+            "--strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    for i in 0..4 {
+        // Something that is definitely not HTTP
+        let message = format!("*1\r\n$4\r\nPING {}\r\n", i).into_bytes();
+        assert_eq!(echo_through(&balancebeam.address, &message).await, message);
+    }
+    for upstream in &upstreams {
+        assert_eq!(upstream.connections.load(Ordering::SeqCst), 2);
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure TLS connections in TCP mode are relayed without offering HTTP/2 through ALPN, since
+/// the upstreams may not speak HTTP at all
+#[tokio::test]
+async fn test_tcp_mode_tls_without_alpn() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let cert = TestCert::new("tcp.test");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--mode",
+            "tcp",
+            "--tls-cert",
+            cert.cert_path(),
+            "--tls-key",
+            cert.key_path(),
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add(&Certificate(cert.der.clone()))
+        .unwrap();
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    let stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(DNSNameRef::try_from_ascii_str("tcp.test").unwrap(), stream)
+        .await
+        .expect("TLS handshake with balancebeam failed");
+    assert_eq!(stream.get_ref().1.get_alpn_protocol(), None);
+
+    let message = b"*1\r\n$4\r\nPING\r\n";
+    stream.write_all(message).await.unwrap();
+    let mut reply = vec![0_u8; message.len()];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut reply))
+        .await
+        .expect("Timed out waiting for the echo")
+        .expect("balancebeam hung up before echoing");
+    assert_eq!(reply, message);
+    assert_eq!(upstream.connections.load(Ordering::SeqCst), 1);
+    log::info!("All done :)");
+}
+
+/// Make sure health checks in TCP mode only need to connect. (The echo server doesn't speak HTTP,
+/// so an HTTP health check would mark it dead.)
+#[tokio::test]
+async fn test_tcp_mode_health_checks() {
+    init_logging();
+    let upstream = TcpEchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--mode", "tcp", "--active-health-check-interval", "1"],
+    )
+    .await;
+    delay_for(Duration::from_secs(2)).await;
+    assert!(
+        upstream.connections.load(Ordering::SeqCst) >= 1,
+        "balancebeam should have health-checked the upstream"
+    );
+    assert_eq!(
+        echo_through(&balancebeam.address, b"still alive").await,
+        b"still alive"
+    );
+    log::info!("All done :)");
+}
+
+/// Connects a client through balancebeam, and returns the client's address along with the
+/// connection balancebeam opened to the upstream
+async fn connect_with_proxy_protocol(version: &str) -> (SocketAddr, TcpStream, BalanceBeam) {
+    let mut upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream.local_addr().unwrap().to_string();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--mode",
+            "tcp",
+            "--proxy-protocol",
+            version,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(b"hello").await.unwrap();
+    let (upstream_conn, _) = timeout(Duration::from_secs(5), upstream.accept())
+        .await
+        .expect("balancebeam did not connect to the upstream")
+        .unwrap();
+    let client_address = client.local_addr().unwrap();
+    // Keep the client connection open until the test is done with the upstream side
+    tokio::spawn(async move {
+        let mut buffer = [0_u8; 16];
+        let _ = client.read(&mut buffer).await;
+    });
+    (client_address, upstream_conn, balancebeam)
+}
+
+/// Make sure upstreams are sent a PROXY protocol v1 header with the client's address
+#[tokio::test]
+async fn test_proxy_protocol_v1() {
+    init_logging();
+    let (client_address, mut upstream_conn, balancebeam) = connect_with_proxy_protocol("v1").await;
+    let expected = format!(
+        "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello",
+        client_address.port(),
+        balancebeam.address.rsplit(':').next().unwrap()
+    );
+    let mut received = vec![0_u8; expected.len()];
+    upstream_conn.read_exact(&mut received).await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&received), expected);
+    log::info!("All done :)");
+}
+
+/// Make sure upstreams are sent a PROXY protocol v2 header with the client's address
+#[tokio::test]
+async fn test_proxy_protocol_v2() {
+    init_logging();
+    let (client_address, mut upstream_conn, balancebeam) = connect_with_proxy_protocol("v2").await;
+    let balancebeam_port: u16 = balancebeam
+        .address
+        .rsplit(':')
+        .next()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut expected = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    expected.extend_from_slice(&[127, 0, 0, 1, 127, 0, 0, 1]);
+    expected.extend_from_slice(&client_address.port().to_be_bytes());
+    expected.extend_from_slice(&balancebeam_port.to_be_bytes());
+    expected.extend_from_slice(b"hello");
+    let mut received = vec![0_u8; expected.len()];
+    upstream_conn.read_exact(&mut received).await.unwrap();
+    assert_eq!(received, expected);
+    log::info!("All done :)");
+}
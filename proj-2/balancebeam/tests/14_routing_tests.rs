@@ -0,0 +1,208 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use rand::Rng;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Sends a request for `path` to balancebeam (with the given Host header, if any), returning the
+/// status code and the address of the upstream that answered
+async fn route_request(
+    client: &reqwest::Client,
+    balancebeam: &BalanceBeam,
+    path: &str,
+    host: Option<&str>,
+) -> (u16, Option<String>) {
+    let mut request = client.get(&format!("http://{}{}", balancebeam.address, path));
+    if let Some(host) = host {
+        request = request.header("Host", host);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let upstream = response
+        .headers()
+        .get("x-upstream-address")
+        .map(|address| address.to_str().unwrap().to_string());
+    (response.status().as_u16(), upstream)
+}
+
+/// Make sure requests are sent to the pool of the first rule matching their path, and to the
+/// fallback pool if none match
+#[tokio::test]
+async fn test_path_routing() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let static_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--pool",
+            &format!("api={}", api_upstream.address),
+            "--pool",
+            &format!("static={}", static_upstream.address),
+            "--route",
+            "exact:/api/static-docs=static",
+            "--route",
+            "prefix:/api/=api",
+            "--route",
+            r"regex:^/assets/.*\.(css|js)$=static",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    // One client, so that its connection to balancebeam is reused as the pool changes
+    let client = reqwest::Client::new();
+    for (path, expected) in &[
+        ("/api/users", &api_upstream),
+        ("/api/users?page=2", &api_upstream),
+        ("/api/static-docs", &static_upstream),
+        ("/assets/site.css", &static_upstream),
+        ("/assets/logo.png", &default_upstream),
+        ("/apis", &default_upstream),
+        ("/", &default_upstream),
+        ("/api/orders", &api_upstream),
+    ] {
+        log::info!("Requesting {}", path);
+        let (status, upstream) = route_request(&client, &balancebeam, path, None).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            upstream.as_deref(),
+            Some(expected.address.as_str()),
+            "{} was routed to the wrong pool",
+            path
+        );
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure requests can be routed by their Host header
+#[tokio::test]
+async fn test_host_routing() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--pool",
+            &format!("api={}", api_upstream.address),
+            "--route",
+            "host:api.example.com=api",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (host, expected) in &[
+        ("api.example.com", &api_upstream),
+        ("API.Example.com:1100", &api_upstream),
+        ("www.example.com", &default_upstream),
+    ] {
+        log::info!("Requesting Host {}", host);
+        let (status, upstream) = route_request(&client, &balancebeam, "/", Some(host)).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            upstream.as_deref(),
+            Some(expected.address.as_str()),
+            "Host {} was routed to the wrong pool",
+            host
+        );
+    }
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that fails requests for / (and so fails the default health check) but
+/// answers every other path, returning its address
+async fn start_picky_upstream() -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 65535));
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+            let status = if request.uri().path() == "/" {
+                StatusCode::NOT_FOUND
+            } else {
+                StatusCode::OK
+            };
+            Ok::<_, Infallible>(
+                Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        }))
+    });
+    let server = hyper::Server::bind(&address.parse().unwrap()).serve(make_service);
+    tokio::spawn(server);
+    address
+}
+
+/// Make sure each pool is health-checked on its own path
+#[tokio::test]
+async fn test_pool_health_check_path() {
+    init_logging();
+    let default_upstream = start_picky_upstream().await;
+    let api_upstream = start_picky_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream],
+        &[
+            "--pool",
+            &format!("api={}", api_upstream),
+            "--route",
+            "prefix:/api/=api",
+            "--pool-health-check-path",
+            "api=/healthz",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to run...");
+    delay_for(Duration::from_secs(2)).await;
+    let client = reqwest::Client::new();
+    let (status, _) = route_request(&client, &balancebeam, "/api/users", None).await;
+    assert_eq!(
+        status, 200,
+        "The api pool's upstream passes its health check"
+    );
+    let (status, _) = route_request(&client, &balancebeam, "/users", None).await;
+    assert_eq!(
+        status, 502,
+        "The default pool's upstream fails the default health check"
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam refuses to start without a fallback pool, and that a named pool can be the
+/// fallback
+#[tokio::test]
+async fn test_fallback_pool_required() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let pool = format!("api={}", upstream.address);
+
+    log::info!("Starting balancebeam without a fallback pool");
+    let balancebeam = BalanceBeam::new_with_args(&[], &["--pool", &pool]).await;
+    assert!(
+        balancebeam.get("/").await.is_err(),
+        "balancebeam should have refused to start"
+    );
+
+    log::info!("Starting balancebeam with a fallback pool");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--pool", &pool, "--fallback-pool", "api"]).await;
+    let client = reqwest::Client::new();
+    let (status, upstream_address) = route_request(&client, &balancebeam, "/", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(upstream_address.as_deref(), Some(upstream.address.as_str()));
+    log::info!("All done :)");
+}
@@ -0,0 +1,165 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer};
+
+/// Sends a request to balancebeam with the given headers, returning the response headers along
+/// with the request as the echo server saw it
+async fn echo_request(
+    balancebeam: &BalanceBeam,
+    headers: &[(&str, &str)],
+) -> (reqwest::header::HeaderMap, String) {
+    let mut request = reqwest::Client::new().get(&format!("http://{}/", balancebeam.address));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let headers = response.headers().clone();
+    let echoed = response.text().await.expect("Error reading response body");
+    (headers, echoed)
+}
+
+/// Make sure upstreams are told about the client with the standard forwarding headers, that a
+/// client can't pass off its own values for them, and that hop-by-hop headers are dropped
+#[tokio::test]
+async fn test_forwarding_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let (headers, echoed) = echo_request(
+        &balancebeam,
+        &[
+            ("Host", "example.com"),
+            ("X-Forwarded-For", "6.6.6.6"),
+            ("X-Forwarded-Proto", "https"),
+            ("Forwarded", "for=6.6.6.6"),
+            ("Connection", "keep-alive, x-connection-secret"),
+            ("X-Connection-Secret", "hunter2"),
+            ("Keep-Alive", "timeout=5"),
+            ("TE", "gzip"),
+            ("Proxy-Authorization", "Basic aHVudGVyMg=="),
+            ("Trailer", "X-Checksum"),
+        ],
+    )
+    .await;
+    log::info!("Upstream received:\n{}", echoed);
+    for expected in &[
+        "x-forwarded-for: 127.0.0.1\n",
+        "x-forwarded-proto: http\n",
+        "x-forwarded-host: example.com\n",
+        "forwarded: for=127.0.0.1;proto=http;host=example.com\n",
+        "via: 1.1 balancebeam\n",
+    ] {
+        assert!(
+            echoed.contains(expected),
+            "Upstream didn't get {:?}",
+            expected
+        );
+    }
+    for unexpected in &[
+        "6.6.6.6",
+        "https",
+        "x-connection-secret",
+        "keep-alive",
+        "te:",
+        "proxy-authorization",
+        "trailer",
+    ] {
+        assert!(
+            !echoed.contains(unexpected),
+            "Upstream shouldn't have received {:?}",
+            unexpected
+        );
+    }
+    assert_eq!(headers.get("via").unwrap(), "1.1 balancebeam");
+    log::info!("All done :)");
+}
+
+/// Make sure the forwarding headers sent by a trusted proxy are kept and extended
+#[tokio::test]
+async fn test_trusted_proxy() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--trusted-proxy", "127.0.0.0/8"]).await;
+
+    let (_, echoed) = echo_request(
+        &balancebeam,
+        &[
+            ("Host", "example.com"),
+            ("X-Forwarded-For", "6.6.6.6"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "shop.example.com"),
+            ("Forwarded", "for=6.6.6.6;proto=https"),
+        ],
+    )
+    .await;
+    log::info!("Upstream received:\n{}", echoed);
+    for expected in &[
+        "x-forwarded-for: 6.6.6.6, 127.0.0.1\n",
+        "x-forwarded-proto: https\n",
+        "x-forwarded-host: shop.example.com\n",
+        "forwarded: for=6.6.6.6;proto=https, for=127.0.0.1;proto=http;host=example.com\n",
+    ] {
+        assert!(
+            echoed.contains(expected),
+            "Upstream didn't get {:?}",
+            expected
+        );
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure the configured rules add, set and remove headers in both directions
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--request-header",
+            "set:X-Environment: test",
+            "--request-header",
+            "add:X-Tag: added",
+            "--request-header",
+            "remove:X-Debug",
+            "--response-header",
+            "set:X-Served-By: balancebeam",
+            "--response-header",
+            "remove:Via",
+        ],
+    )
+    .await;
+
+    let (headers, echoed) = echo_request(
+        &balancebeam,
+        &[
+            ("X-Environment", "production"),
+            ("X-Tag", "original"),
+            ("X-Debug", "1"),
+        ],
+    )
+    .await;
+    log::info!("Upstream received:\n{}", echoed);
+    for expected in &[
+        "x-environment: test\n",
+        "x-tag: original\n",
+        "x-tag: added\n",
+    ] {
+        assert!(
+            echoed.contains(expected),
+            "Upstream didn't get {:?}",
+            expected
+        );
+    }
+    assert!(!echoed.contains("production"));
+    assert!(!echoed.contains("x-debug"));
+    assert_eq!(headers.get("x-served-by").unwrap(), "balancebeam");
+    assert!(headers.get("via").is_none());
+    log::info!("All done :)");
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::delay_for;
+
+/// An upstream that reads each request's headers and then hangs up without responding, counting
+/// the requests it has received
+struct HangUpServer {
+    address: String,
+    requests: Arc<AtomicUsize>,
+}
+
+impl HangUpServer {
+    async fn new() -> HangUpServer {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let counter = Arc::clone(&counter);
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    let mut buffer = [0_u8; 1024];
+                    while !received.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(bytes_read) => received.extend_from_slice(&buffer[..bytes_read]),
+                        }
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        });
+        HangUpServer { address, requests }
+    }
+}
+
+/// An upstream that answers one request on each connection (without saying it will close the
+/// connection) and then hangs up, counting the connections it has received
+struct OneRequestServer {
+    address: String,
+    connections: Arc<AtomicUsize>,
+}
+
+impl OneRequestServer {
+    async fn new() -> OneRequestServer {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&connections);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    let mut buffer = [0_u8; 1024];
+                    while !received.windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(bytes_read) => received.extend_from_slice(&buffer[..bytes_read]),
+                        }
+                    }
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await;
+                });
+            }
+        });
+        OneRequestServer {
+            address,
+            connections,
+        }
+    }
+}
+
+/// Starts balancebeam with a hang-up upstream first in round-robin order, so that the first request
+/// goes to it, followed by an upstream that works
+async fn start_balancebeam(
+    hang_up_upstream: &HangUpServer,
+    good_upstream: &EchoServer,
+    extra_args: &[&str],
+) -> BalanceBeam {
+    let mut args = vec![
+        "--strategy",
+        "round-robin",
+        "--active-health-check-interval",
+        "3600",
+    ];
+    args.extend_from_slice(extra_args);
+    BalanceBeam::new_with_args(&[&hang_up_upstream.address, &good_upstream.address], &args).await
+}
+
+/// Make sure an idempotent request that fails is retried on another upstream
+#[tokio::test]
+async fn test_idempotent_request_retried() {
+    init_logging();
+    let hang_up_upstream = HangUpServer::new().await;
+    let good_upstream = EchoServer::new().await;
+    let balancebeam = start_balancebeam(&hang_up_upstream, &good_upstream, &[]).await;
+
+    for method in &[reqwest::Method::GET, reqwest::Method::PUT] {
+        log::info!("Sending a {} request", method);
+        let response = reqwest::Client::new()
+            .request(method.clone(), &format!("http://{}/", balancebeam.address))
+            .body("retry me")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(
+            response
+                .headers()
+                .get("x-upstream-address")
+                .map(|address| address.to_str().unwrap()),
+            Some(good_upstream.address.as_str()),
+            "The {} request should have been retried on the working upstream",
+            method
+        );
+    }
+    assert_eq!(
+        hang_up_upstream.requests.load(Ordering::SeqCst),
+        1,
+        "The failing upstream should have been marked dead after the first request"
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure a POST request isn't retried once the upstream may have seen it
+#[tokio::test]
+async fn test_non_idempotent_request_not_retried() {
+    init_logging();
+    let hang_up_upstream = HangUpServer::new().await;
+    let good_upstream = EchoServer::new().await;
+    let balancebeam = start_balancebeam(&hang_up_upstream, &good_upstream, &[]).await;
+
+    log::info!("Sending a POST request");
+    let response = reqwest::Client::new()
+        .post(&format!("http://{}/", balancebeam.address))
+        .body("don't send me twice")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(hang_up_upstream.requests.load(Ordering::SeqCst), 1);
+    assert_eq!(
+        good_upstream.connections_received(),
+        0,
+        "The POST request should not have been sent again"
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure --max-retries 0 turns retries off
+#[tokio::test]
+async fn test_retries_disabled() {
+    init_logging();
+    let hang_up_upstream = HangUpServer::new().await;
+    let good_upstream = EchoServer::new().await;
+    let balancebeam =
+        start_balancebeam(&hang_up_upstream, &good_upstream, &["--max-retries", "0"]).await;
+
+    log::info!("Sending a GET request");
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(good_upstream.connections_received(), 0);
+    log::info!("All done :)");
+}
+
+/// Make sure a POST request sent on a pooled connection that the upstream has since closed is sent
+/// again on a new connection, since the upstream can't have seen it
+#[tokio::test]
+async fn test_non_idempotent_request_retried_on_stale_connection() {
+    init_logging();
+    let upstream = OneRequestServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    for i in 0..2 {
+        log::info!("Sending POST request {}", i);
+        let response = client
+            .post(&format!("http://{}/", balancebeam.address))
+            .body("send me once")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.text().await.unwrap(), "ok");
+        // Give the upstream's hang-up time to arrive, so that the connection is stale rather than
+        // closed while we're using it
+        delay_for(Duration::from_millis(200)).await;
+    }
+    assert_eq!(upstream.connections.load(Ordering::SeqCst), 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use rand::Rng;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Starts an upstream that answers with 500 while `failing` is set and 200 otherwise, returning its
+/// address
+async fn start_switchable_upstream(failing: Arc<AtomicBool>) -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 65535));
+    let make_service = make_service_fn(move |_| {
+        let failing = Arc::clone(&failing);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                let status = if failing.load(Ordering::SeqCst) {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::OK
+                };
+                async move {
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .status(status)
+                            .body(Body::empty())
+                            .unwrap(),
+                    )
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&address.parse().unwrap()).serve(make_service);
+    tokio::spawn(server);
+    address
+}
+
+/// Sends a request to balancebeam on a new connection, returning the status code
+async fn get_status(balancebeam: &BalanceBeam) -> u16 {
+    reqwest::get(&format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Fetches the state of an upstream's circuit breaker from the admin API
+async fn circuit_state(admin_address: &str, upstream_address: &str) -> String {
+    let text = reqwest::get(&format!("http://{}/upstreams", admin_address))
+        .await
+        .expect("Error fetching upstreams")
+        .text()
+        .await
+        .expect("Error reading admin API response");
+    let list: serde_json::Value =
+        serde_json::from_str(&text).expect("Admin API response is not valid JSON");
+    list.as_array()
+        .unwrap()
+        .iter()
+        .find(|upstream| upstream["address"] == upstream_address)
+        .map(|upstream| upstream["circuit"].as_str().unwrap().to_string())
+        .expect("Upstream is missing from the admin API")
+}
+
+/// Make sure an upstream that keeps answering with 5xx responses stops getting requests once its
+/// circuit breaker opens
+#[tokio::test]
+async fn test_breaker_opens_on_server_errors() {
+    init_logging();
+    let bad_upstream = ErrorServer::new().await;
+    let good_upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&bad_upstream.address, &good_upstream.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--circuit-breaker-failures",
+            "2",
+            "--circuit-breaker-open-duration",
+            "3600",
+            "--active-health-check-interval",
+            "3600",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests until the failing upstream has failed twice");
+    for expected in &[500, 200, 500] {
+        assert_eq!(get_status(&balancebeam).await, *expected);
+    }
+    assert_eq!(
+        circuit_state(&admin_address, &bad_upstream.address).await,
+        "open"
+    );
+
+    log::info!("Sending more requests, which should all go to the working upstream");
+    for _ in 0..6 {
+        assert_eq!(get_status(&balancebeam).await, 200);
+    }
+    assert_eq!(
+        Box::new(bad_upstream).stop().await,
+        2,
+        "The failing upstream should not have been sent requests after its breaker opened"
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure an open breaker lets trial requests through after the open duration, opening again if
+/// one fails and closing once enough of them succeed
+#[tokio::test]
+async fn test_breaker_half_open_trials() {
+    init_logging();
+    let failing = Arc::new(AtomicBool::new(true));
+    let upstream = start_switchable_upstream(Arc::clone(&failing)).await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024, 65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--circuit-breaker-failures",
+            "1",
+            "--circuit-breaker-open-duration",
+            "1",
+            "--circuit-breaker-trial-requests",
+            "2",
+            "--active-health-check-interval",
+            "3600",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    log::info!("Tripping the breaker");
+    assert_eq!(get_status(&balancebeam).await, 500);
+    assert_eq!(
+        get_status(&balancebeam).await,
+        502,
+        "The only upstream's breaker is open, so there should be nowhere to send the request"
+    );
+
+    log::info!("Failing a trial request");
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(get_status(&balancebeam).await, 500);
+    assert_eq!(circuit_state(&admin_address, &upstream).await, "open");
+    assert_eq!(get_status(&balancebeam).await, 502);
+
+    log::info!("Passing the trial requests");
+    failing.store(false, Ordering::SeqCst);
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(get_status(&balancebeam).await, 200);
+    assert_eq!(circuit_state(&admin_address, &upstream).await, "half-open");
+    assert_eq!(get_status(&balancebeam).await, 200);
+    assert_eq!(circuit_state(&admin_address, &upstream).await, "closed");
+    assert_eq!(get_status(&balancebeam).await, 200);
+    log::info!("All done :)");
+}
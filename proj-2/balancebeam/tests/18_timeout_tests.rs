@@ -0,0 +1,150 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server, TestCert};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{delay_for, timeout};
+
+/// Starts an upstream that accepts connections and reads from them, but never responds, returning
+/// its address
+async fn start_silent_upstream() -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                while let Ok(bytes_read) = stream.read(&mut buffer).await {
+                    if bytes_read == 0 {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Make sure a client that sends its request headers too slowly gets a 408
+#[tokio::test]
+async fn test_client_header_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--client-header-timeout", "1"]).await;
+
+    log::info!("Sending half of a request and then stalling");
+    let response = common::send_raw(
+        &balancebeam.address,
+        "GET / HTTP/1.1\r\nHost: example.com\r\n",
+        "\r\n\r\n",
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 408"),
+        "Expected a 408 response, got {:?}",
+        response
+    );
+    assert_eq!(
+        Box::new(upstream).stop().await,
+        0,
+        "The partial request should not have been forwarded"
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure a client that stalls partway through its TLS ClientHello is hung up on, rather than
+/// holding the connection open forever
+#[tokio::test]
+async fn test_tls_handshake_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let cert = TestCert::new("localhost");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-cert",
+            cert.cert_path(),
+            "--tls-key",
+            cert.key_path(),
+            "--client-header-timeout",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Sending the start of a ClientHello and then stalling");
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    // A handshake record promising 512 bytes, which starts a ClientHello but never finishes it
+    stream
+        .write_all(&[
+            0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03,
+        ])
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 1024];
+    match timeout(Duration::from_secs(5), stream.read(&mut buffer)).await {
+        Ok(Ok(0)) | Ok(Err(_)) => {}
+        Ok(Ok(bytes_read)) => panic!("Expected balancebeam to hang up, got {} bytes", bytes_read),
+        Err(_) => panic!("balancebeam should have hung up once the header timeout passed"),
+    }
+    log::info!("All done :)");
+}
+
+/// Make sure a request to an upstream that never responds fails with a 504
+#[tokio::test]
+async fn test_upstream_first_byte_timeout() {
+    init_logging();
+    let upstream = start_silent_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--upstream-first-byte-timeout",
+            "1",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request to the silent upstream");
+    let response = reqwest::get(&format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+    log::info!("All done :)");
+}
+
+/// Make sure an idle keep-alive connection is closed after the keep-alive timeout, while a request
+/// sent before then is still served
+#[tokio::test]
+async fn test_keep_alive_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--keep-alive-timeout", "1"]).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    log::info!("Sending a request on a fresh connection");
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 4096];
+    let bytes_read = stream.read(&mut buffer).await.unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..bytes_read]).starts_with("HTTP/1.1 200"));
+
+    log::info!("Leaving the connection idle");
+    delay_for(Duration::from_millis(1500)).await;
+    let bytes_read = timeout(Duration::from_secs(5), stream.read(&mut buffer))
+        .await
+        .expect("balancebeam should have closed the idle connection")
+        .unwrap_or(0);
+    assert_eq!(bytes_read, 0, "The idle connection should have been closed");
+    log::info!("All done :)");
+}
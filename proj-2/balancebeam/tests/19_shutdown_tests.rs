@@ -0,0 +1,100 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use nix::sys::signal::Signal;
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+
+/// Starts an upstream that waits for `delay` before answering each request, returning its address
+async fn start_slow_upstream(delay: Duration) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |_: Request<Body>| async move {
+            delay_for(delay).await;
+            Ok::<_, Infallible>(Response::new(Body::from("finally")))
+        }))
+    });
+    let server = hyper::Server::from_tcp(listener)
+        .unwrap()
+        .serve(make_service);
+    tokio::spawn(server);
+    address
+}
+
+/// Make sure a slow request that was already in flight when balancebeam got SIGTERM still
+/// completes, while new connections are turned away
+#[tokio::test]
+async fn test_in_flight_request_completes() {
+    init_logging();
+    let upstream = start_slow_upstream(Duration::from_secs(2)).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "3600"]).await;
+
+    log::info!("Sending a slow request");
+    let url = format!("http://{}/", balancebeam.address);
+    let request = tokio::spawn(async move { reqwest::get(&url).await });
+    delay_for(Duration::from_millis(500)).await;
+
+    log::info!("Sending SIGTERM");
+    balancebeam.send_signal(Signal::SIGTERM);
+    delay_for(Duration::from_millis(200)).await;
+    assert!(
+        TcpStream::connect(&balancebeam.address).await.is_err(),
+        "balancebeam should have stopped accepting connections"
+    );
+
+    log::info!("Waiting for the slow request to finish");
+    let response = request
+        .await
+        .unwrap()
+        .expect("The in-flight request should have completed");
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response
+            .headers()
+            .get("connection")
+            .map(|value| value.to_str().unwrap()),
+        Some("close")
+    );
+    assert_eq!(response.text().await.unwrap(), "finally");
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam gives up on connections that are still open once the grace period is over
+#[tokio::test]
+async fn test_grace_period_expires() {
+    init_logging();
+    let upstream = start_slow_upstream(Duration::from_secs(10)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--shutdown-grace-period",
+            "1",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request that will outlast the grace period");
+    let url = format!("http://{}/", balancebeam.address);
+    let request = tokio::spawn(async move { reqwest::get(&url).await });
+    delay_for(Duration::from_millis(500)).await;
+
+    let signalled = Instant::now();
+    balancebeam.send_signal(Signal::SIGTERM);
+    assert!(
+        request.await.unwrap().is_err(),
+        "The request should have been cut off when balancebeam exited"
+    );
+    assert!(
+        signalled.elapsed() < Duration::from_secs(5),
+        "balancebeam should have exited once the grace period was over"
+    );
+    log::info!("All done :)");
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::delay_for;
+
+/// Returns the pids of the running processes that have `arg` on their command line
+fn pids_with_arg(arg: &str) -> Vec<i32> {
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir("/proc").unwrap().flatten() {
+        let pid = match entry.file_name().to_str().and_then(|pid| pid.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let cmdline = std::fs::read(entry.path().join("cmdline")).unwrap_or_default();
+        if cmdline
+            .split(|&byte| byte == 0)
+            .any(|word| word == arg.as_bytes())
+        {
+            pids.push(pid);
+        }
+    }
+    pids
+}
+
+/// Kills a process when dropped, so that a process the test didn't start itself doesn't outlive it
+struct KillOnDrop(i32);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = kill(Pid::from_raw(self.0), Signal::SIGKILL);
+    }
+}
+
+/// Make sure that after SIGUSR2, a new process takes over the listening socket and the old one
+/// exits, without any requests being turned away in between
+#[tokio::test]
+async fn test_upgrade_keeps_accepting() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    log::info!("Sending requests throughout the upgrade");
+    let done = Arc::new(AtomicBool::new(false));
+    let failures = Arc::new(AtomicUsize::new(0));
+    let requests = {
+        let url = format!("http://{}/", balancebeam.address);
+        let done = Arc::clone(&done);
+        let failures = Arc::clone(&failures);
+        tokio::spawn(async move {
+            let mut sent = 0;
+            while !done.load(Ordering::SeqCst) {
+                // A new client each time, so that every request needs a new connection
+                match reqwest::Client::new().get(&url).send().await {
+                    Ok(response) if response.status().is_success() => {}
+                    _ => {
+                        failures.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+                sent += 1;
+                delay_for(Duration::from_millis(20)).await;
+            }
+            sent
+        })
+    };
+    delay_for(Duration::from_millis(200)).await;
+
+    log::info!("Sending SIGUSR2");
+    balancebeam.send_signal(Signal::SIGUSR2);
+    assert!(
+        balancebeam.wait_for_exit(Duration::from_secs(5)).await,
+        "The old process should have exited once a new one was ready"
+    );
+    // The new process was started with the same arguments, including the address
+    let successor = match pids_with_arg(&balancebeam.address).as_slice() {
+        [pid] => KillOnDrop(*pid),
+        pids => panic!("Expected one new balancebeam process, found {:?}", pids),
+    };
+
+    log::info!("Checking that the new process is serving requests");
+    delay_for(Duration::from_millis(500)).await;
+    done.store(true, Ordering::SeqCst);
+    let sent = requests.await.unwrap();
+    log::info!("Sent {} requests", sent);
+    assert_eq!(
+        failures.load(Ordering::SeqCst),
+        0,
+        "Requests failed during the upgrade"
+    );
+    let response = reqwest::get(&format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to the new process");
+    assert_eq!(response.status().as_u16(), 200);
+    drop(successor);
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam serves on a socket passed in with systemd's socket activation protocol
+/// rather than binding its own
+#[tokio::test]
+async fn test_systemd_socket_activation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // Holding the socket ourselves means balancebeam can't bind the address, so it only works if
+    // it uses the socket we pass in
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let fd = listener.as_raw_fd();
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+
+    log::info!("Starting balancebeam with the socket as fd 3");
+    let binary = std::env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join("balancebeam");
+    // The shell sets LISTEN_PID to its own pid, which balancebeam keeps when the shell execs it
+    let _balancebeam = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" \"$@\" 3<&{}",
+            fd
+        ))
+        .arg(binary)
+        .args(["--bind", &address, "--upstream", &upstream.address])
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Could not start balancebeam");
+    delay_for(Duration::from_secs(1)).await;
+
+    let response = reqwest::get(&format!("http://{}/", address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    drop(listener);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
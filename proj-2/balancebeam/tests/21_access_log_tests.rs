@@ -0,0 +1,238 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use rand::Rng;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// An access log path in the system's temp directory. The log and any rotated files are deleted
+/// when dropped.
+struct LogPath {
+    path: PathBuf,
+}
+
+impl LogPath {
+    fn new() -> LogPath {
+        let path = std::env::temp_dir().join(format!(
+            "balancebeam-test-{}.log",
+            rand::thread_rng().gen::<u64>()
+        ));
+        LogPath { path }
+    }
+
+    fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Path of the rotated file with the given index (0 is the current log)
+    fn rotated(&self, index: usize) -> String {
+        match index {
+            0 => self.path().to_string(),
+            _ => format!("{}.{}", self.path(), index),
+        }
+    }
+
+    /// Reads the lines of the current log
+    fn lines(&self) -> Vec<String> {
+        std::fs::read_to_string(&self.path)
+            .expect("Could not read access log")
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl Drop for LogPath {
+    fn drop(&mut self) {
+        for index in 0..10 {
+            let _ = std::fs::remove_file(self.rotated(index));
+        }
+    }
+}
+
+/// Make sure requests are logged in the Combined Log Format, followed by our extra fields
+#[tokio::test]
+async fn test_combined_format() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log = LogPath::new();
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--access-log", log.path()]).await;
+
+    log::info!("Sending a request");
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/some/path?x=1", balancebeam.address))
+        .header("User-Agent", "access-log-test")
+        .header("Referer", "http://example.com/")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let response_body = response.text().await.unwrap();
+    delay_for(Duration::from_millis(100)).await;
+
+    let lines = log.lines();
+    assert_eq!(lines.len(), 1, "Expected one line, got {:?}", lines);
+    let line = &lines[0];
+    log::info!("Logged: {}", line);
+    assert!(line.starts_with("127.0.0.1 - - ["), "{}", line);
+    let expected = format!(
+        "] \"GET /some/path?x=1 HTTP/1.1\" 200 {} \"http://example.com/\" \"access-log-test\" ",
+        response_body.len()
+    );
+    assert!(line.contains(&expected), "{}", line);
+    let extra: Vec<&str> = line
+        .rsplit('"')
+        .next()
+        .unwrap()
+        .split_whitespace()
+        .collect();
+    assert_eq!(extra.len(), 5, "{}", line);
+    assert_eq!(
+        extra[0], request_id,
+        "The request ID should match the response"
+    );
+    assert_eq!(extra[1], upstream.address);
+    assert_eq!(extra[2], "0");
+    log::info!("All done :)");
+}
+
+/// Make sure the JSON format has the expected fields, including the sizes of the request and
+/// response bodies, and that requests answered by balancebeam itself are logged
+#[tokio::test]
+async fn test_json_format() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log = LogPath::new();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            log.path(),
+            "--access-log-format",
+            "json",
+            "--max-requests-per-minute",
+            "1",
+            "--rate-limit-burst",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request, and then one that gets rate limited");
+    let client = reqwest::Client::new();
+    let response_body = client
+        .post(&format!("http://{}/upload", balancebeam.address))
+        .body("twelve bytes")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .unwrap();
+    let status = client
+        .get(&format!("http://{}/", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status();
+    assert_eq!(status.as_u16(), 429);
+    delay_for(Duration::from_millis(100)).await;
+
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .iter()
+        .map(|line| serde_json::from_str(line).expect("Access log line is not valid JSON"))
+        .collect();
+    assert_eq!(lines.len(), 2, "Expected two lines, got {:?}", lines);
+    assert_eq!(lines[0]["client_ip"], "127.0.0.1");
+    assert_eq!(lines[0]["method"], "POST");
+    assert_eq!(lines[0]["path"], "/upload");
+    assert_eq!(lines[0]["protocol"], "HTTP/1.1");
+    assert_eq!(lines[0]["status"], 200);
+    assert_eq!(lines[0]["upstream"], upstream.address.as_str());
+    assert_eq!(lines[0]["bytes_in"], 12);
+    assert_eq!(lines[0]["bytes_out"], response_body.len());
+    assert!(lines[0]["duration"].as_f64().is_some());
+    assert!(lines[0]["upstream_duration"].as_f64().is_some());
+    assert_ne!(lines[0]["request_id"], lines[1]["request_id"]);
+    assert_eq!(lines[1]["status"], 429);
+    assert_eq!(lines[1]["upstream"], serde_json::Value::Null);
+    log::info!("All done :)");
+}
+
+/// Make sure the log is rotated once it gets too big, keeping only the configured number of old
+/// files
+#[tokio::test]
+async fn test_rotation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log = LogPath::new();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            log.path(),
+            "--access-log-max-size",
+            "400",
+            "--access-log-max-files",
+            "2",
+        ],
+    )
+    .await;
+
+    log::info!("Sending enough requests to rotate the log a few times");
+    for _ in 0..12 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    delay_for(Duration::from_millis(100)).await;
+
+    for index in 0..3 {
+        let size = std::fs::metadata(log.rotated(index))
+            .unwrap_or_else(|_| panic!("{} should exist", log.rotated(index)))
+            .len();
+        assert!(size <= 400, "{} is {} bytes", log.rotated(index), size);
+    }
+    assert!(
+        std::fs::metadata(log.rotated(3)).is_err(),
+        "Only two rotated files should be kept"
+    );
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure every line is in the log by the time balancebeam exits after SIGTERM
+#[tokio::test]
+async fn test_log_written_before_exit() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let log = LogPath::new();
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--access-log", log.path()]).await;
+
+    log::info!("Sending some requests and then SIGTERM");
+    for _ in 0..20 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    balancebeam.send_signal(Signal::SIGTERM);
+    assert!(
+        balancebeam.wait_for_exit(Duration::from_secs(5)).await,
+        "balancebeam should exit after SIGTERM"
+    );
+
+    let lines = log.lines();
+    assert_eq!(lines.len(), 20, "Expected 20 lines, got {:?}", lines);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer};
+
+/// Sends a request to balancebeam with the given headers, returning the response headers along
+/// with the request as the echo server saw it
+async fn echo_request(
+    balancebeam: &BalanceBeam,
+    headers: &[(&str, &str)],
+) -> (reqwest::header::HeaderMap, String) {
+    let mut request = reqwest::Client::new().get(&format!("http://{}/", balancebeam.address));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let headers = response.headers().clone();
+    let echoed = response.text().await.expect("Error reading response body");
+    (headers, echoed)
+}
+
+/// Returns the value of a header in the request as the echo server saw it
+fn echoed_header<'a>(echoed: &'a str, name: &str) -> Option<&'a str> {
+    let prefix = format!("{}: ", name);
+    echoed.lines().find_map(|line| line.strip_prefix(&prefix))
+}
+
+/// Returns the request ID balancebeam echoed back to the client
+fn response_request_id(headers: &reqwest::header::HeaderMap) -> &str {
+    headers
+        .get("x-request-id")
+        .expect("Response should have an X-Request-Id")
+        .to_str()
+        .unwrap()
+}
+
+/// Make sure every request gets a new ID, which both the upstream and the client see
+#[tokio::test]
+async fn test_request_id_generated() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let (headers, echoed) = echo_request(&balancebeam, &[]).await;
+    log::info!("Upstream received:\n{}", echoed);
+    let request_id = response_request_id(&headers);
+    assert_eq!(
+        request_id.len(),
+        32,
+        "The request ID should be 32 hex digits"
+    );
+    assert_eq!(echoed_header(&echoed, "x-request-id"), Some(request_id));
+
+    let (other_headers, _) = echo_request(&balancebeam, &[]).await;
+    assert_ne!(response_request_id(&other_headers), request_id);
+    log::info!("All done :)");
+}
+
+/// Make sure a request ID sent by the client is only kept if the client is a trusted proxy
+#[tokio::test]
+async fn test_incoming_request_id() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    log::info!("Sending a request ID from an untrusted client");
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let (headers, echoed) = echo_request(&balancebeam, &[("X-Request-Id", "chosen-id")]).await;
+    assert_ne!(response_request_id(&headers), "chosen-id");
+    assert_eq!(
+        echoed_header(&echoed, "x-request-id"),
+        Some(response_request_id(&headers))
+    );
+
+    log::info!("Sending a request ID from a trusted proxy");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--trusted-proxy", "127.0.0.1"]).await;
+    let (headers, echoed) = echo_request(&balancebeam, &[("X-Request-Id", "chosen-id")]).await;
+    assert_eq!(response_request_id(&headers), "chosen-id");
+    assert_eq!(echoed_header(&echoed, "x-request-id"), Some("chosen-id"));
+
+    log::info!("Sending a request ID that would break up log lines");
+    let (headers, _) = echo_request(&balancebeam, &[("X-Request-Id", "chosen id")]).await;
+    assert_ne!(response_request_id(&headers), "chosen id");
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam joins a W3C trace as a child span, and drops invalid trace headers
+#[tokio::test]
+async fn test_traceparent() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    log::info!("Sending a request that is part of a trace");
+    let (_, echoed) = echo_request(
+        &balancebeam,
+        &[
+            (
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ),
+            ("tracestate", "congo=t61rcWkgMzE"),
+        ],
+    )
+    .await;
+    log::info!("Upstream received:\n{}", echoed);
+    let traceparent = echoed_header(&echoed, "traceparent").expect("traceparent was dropped");
+    let fields: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(fields.len(), 4, "{}", traceparent);
+    assert_eq!(fields[0], "00");
+    assert_eq!(fields[1], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(fields[2].len(), 16, "{}", traceparent);
+    assert_ne!(
+        fields[2], "00f067aa0ba902b7",
+        "The parent should be a new span"
+    );
+    assert_eq!(fields[3], "01");
+    assert_eq!(
+        echoed_header(&echoed, "tracestate"),
+        Some("congo=t61rcWkgMzE")
+    );
+
+    log::info!("Sending an invalid traceparent");
+    let (_, echoed) = echo_request(
+        &balancebeam,
+        &[
+            (
+                "traceparent",
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            ),
+            ("tracestate", "congo=t61rcWkgMzE"),
+        ],
+    )
+    .await;
+    assert_eq!(echoed_header(&echoed, "traceparent"), None);
+    assert_eq!(echoed_header(&echoed, "tracestate"), None);
+
+    log::info!("Sending a request that isn't part of a trace");
+    let (_, echoed) = echo_request(&balancebeam, &[]).await;
+    assert_eq!(echoed_header(&echoed, "traceparent"), None);
+    log::info!("All done :)");
+}
@@ -0,0 +1,125 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use rand::Rng;
+use std::sync::{atomic, Arc};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// The body ChunkedServer sends by default: "Hello, world" split over two chunks (one with a chunk
+/// extension), followed by a trailer
+#[allow(dead_code)]
+pub const DEFAULT_CHUNKED_BODY: &str =
+    "5\r\nHello\r\n7;ext=1\r\n, world\r\n0\r\nx-checksum: abc123\r\n\r\n";
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+}
+
+/// Answers every request with a fixed chunked response. Hyper can't be used here because it
+/// doesn't send trailers over HTTP/1.1, so this server speaks HTTP over a raw TcpStream. It ignores
+/// request bodies, so it should only be sent requests without one.
+pub struct ChunkedServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    #[allow(dead_code)]
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+async fn serve_connection(
+    mut stream: TcpStream,
+    server_state: Arc<ServerState>,
+    encoded_body: &'static str,
+) {
+    let mut request_buffer = Vec::new();
+    loop {
+        // Read until we've seen the end of the request headers
+        while let Some(headers_end) = request_buffer
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+        {
+            request_buffer.drain(..headers_end + 4);
+            server_state
+                .requests_received
+                .fetch_add(1, atomic::Ordering::SeqCst);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: x-checksum\r\n\r\n{}",
+                encoded_body
+            );
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+        let mut buffer = [0_u8; 512];
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(bytes_read) => request_buffer.extend_from_slice(&buffer[..bytes_read]),
+        }
+    }
+}
+
+impl ChunkedServer {
+    #[allow(dead_code)]
+    pub async fn new() -> ChunkedServer {
+        ChunkedServer::new_with_body(DEFAULT_CHUNKED_BODY).await
+    }
+
+    /// Starts a server that responds with the given (already chunk-encoded) body
+    #[allow(dead_code)]
+    pub async fn new_with_body(encoded_body: &'static str) -> ChunkedServer {
+        let mut rng = rand::thread_rng();
+        let bind_addr_string = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+        let mut listener = TcpListener::bind(&bind_addr_string).await.unwrap();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        if let Ok((stream, _)) = accepted {
+                            tokio::spawn(serve_connection(
+                                stream,
+                                server_task_state.clone(),
+                                encoded_body,
+                            ));
+                        }
+                    }
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+
+        ChunkedServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address: bind_addr_string,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for ChunkedServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the server to stop accepting connections
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("ChunkedServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
@@ -9,6 +9,8 @@ use tokio::sync::oneshot;
 #[derive(Debug)]
 struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    pub connections_received: atomic::AtomicUsize,
+    pub address: String,
 }
 
 async fn echo(
@@ -29,7 +31,11 @@ async fn echo(
     req_text += "\n";
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
-    Ok(Response::new(Body::from(req_as_bytes)))
+    // Tell the client which upstream answered, so that tests can check where requests were routed
+    Ok(Response::builder()
+        .header("x-upstream-address", server_state.address.as_str())
+        .body(Body::from(req_as_bytes))
+        .unwrap())
 }
 
 pub struct EchoServer {
@@ -53,11 +59,16 @@ impl EchoServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            connections_received: atomic::AtomicUsize::new(0),
+            address: bind_addr_string.clone(),
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
             let service = make_service_fn(|_| {
                 let server_task_state = server_task_state.clone();
+                server_task_state
+                    .connections_received
+                    .fetch_add(1, atomic::Ordering::SeqCst);
                 async move {
                     Ok::<_, hyper::Error>(service_fn(move |req| {
                         let server_task_state = server_task_state.clone();
@@ -83,6 +94,14 @@ impl EchoServer {
             address: bind_addr_string,
         }
     }
+
+    /// Returns the number of TCP connections that have been opened to this server so far
+    #[allow(dead_code)]
+    pub fn connections_received(&self) -> usize {
+        self.state
+            .connections_received
+            .load(atomic::Ordering::SeqCst)
+    }
 }
 
 #[async_trait]
@@ -1,14 +1,24 @@
 mod balancebeam;
+mod chunked_server;
 mod echo_server;
 mod error_server;
 mod server;
+mod test_cert;
 
 use std::sync;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
 
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
+pub use chunked_server::ChunkedServer;
 pub use echo_server::EchoServer;
 pub use error_server::ErrorServer;
 pub use server::Server;
+#[allow(unused_imports)]
+pub use test_cert::TestCert;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
@@ -20,3 +30,25 @@ pub fn init_logging() {
             .init();
     });
 }
+
+/// Send raw bytes to balancebeam over a new connection, and read the reply until it contains
+/// `expected_end` (or until balancebeam hangs up or stops sending data)
+#[allow(dead_code)]
+pub async fn send_raw(address: &str, request: &str, expected_end: &str) -> String {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .expect("Could not send request to balancebeam");
+    let mut response = Vec::new();
+    while !String::from_utf8_lossy(&response).contains(expected_end) {
+        let mut buffer = [0_u8; 512];
+        match timeout(Duration::from_secs(5), stream.read(&mut buffer)).await {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+            Ok(Ok(bytes_read)) => response.extend_from_slice(&buffer[..bytes_read]),
+        }
+    }
+    String::from_utf8_lossy(&response).to_string()
+}
@@ -0,0 +1,58 @@
+use rand::Rng;
+use std::path::PathBuf;
+use tokio_rustls::rustls::internal::pemfile;
+
+/// A self-signed certificate generated for a test, saved as PEM files in the system's temp
+/// directory (which are deleted when dropped)
+#[allow(dead_code)]
+pub struct TestCert {
+    pub der: Vec<u8>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+#[allow(dead_code)]
+impl TestCert {
+    pub fn new(hostname: &str) -> TestCert {
+        let prefix = format!("balancebeam-test-{}", rand::thread_rng().gen::<u64>());
+        let mut cert = TestCert {
+            der: Vec::new(),
+            cert_path: std::env::temp_dir().join(format!("{}-cert.pem", prefix)),
+            key_path: std::env::temp_dir().join(format!("{}-key.pem", prefix)),
+        };
+        cert.regenerate(hostname);
+        cert
+    }
+
+    /// Replaces the files with a freshly generated certificate and key
+    pub fn regenerate(&mut self, hostname: &str) {
+        let cert = rcgen::generate_simple_self_signed(vec![hostname.to_string()])
+            .expect("Could not generate certificate");
+        // Every serialization signs the certificate afresh, so take the DER from the PEM we save
+        let pem = cert.serialize_pem().unwrap();
+        self.der = pemfile::certs(&mut pem.as_bytes()).unwrap()[0].0.clone();
+        std::fs::write(&self.cert_path, pem).expect("Could not write certificate");
+        std::fs::write(&self.key_path, cert.serialize_private_key_pem())
+            .expect("Could not write key");
+    }
+
+    pub fn cert_path(&self) -> &str {
+        self.cert_path.to_str().unwrap()
+    }
+
+    pub fn key_path(&self) -> &str {
+        self.key_path.to_str().unwrap()
+    }
+
+    /// Returns the --tls-sni-cert argument serving this certificate for a hostname
+    pub fn sni_arg(&self, hostname: &str) -> String {
+        format!("{}={},{}", hostname, self.cert_path(), self.key_path())
+    }
+}
+
+impl Drop for TestCert {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.cert_path);
+        let _ = std::fs::remove_file(&self.key_path);
+    }
+}